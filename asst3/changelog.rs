@@ -0,0 +1,103 @@
+/*
+ * changelog.rs
+ *
+ * A bounded log of the changes applied to rows, numbered in the order
+ * they happened, from which consumers catch up on what they missed.
+ * The log is kept in memory only, with the rows. Each run of the server
+ * numbers its changes after the time it started, in microseconds, so a
+ * cursor from before a restart falls before the new log and is TRUNCATED.
+ *
+ * University of Toronto
+ * 2019
+ */
+
+use packet::{Change, Response, Value};
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/* most changes returned by one CHANGES request */
+pub const MAX_PAGE: usize = 1000;
+
+pub struct ChangeLog {
+    entries: VecDeque<Change>,  /* oldest first */
+    capacity: usize,
+    start: i64,                 /* sequence the log numbers changes after */
+    latest: i64,                /* sequence of the newest change */
+}
+
+impl ChangeLog {
+    pub fn new(capacity: usize) -> ChangeLog {
+        let start = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|time| time.as_micros() as i64).unwrap_or(0);
+        ChangeLog {
+            entries: VecDeque::new(),
+            capacity: capacity,
+            start: start,
+            latest: start,
+        }
+    }
+
+    pub fn latest(& self) -> i64 {
+        self.latest
+    }
+
+    /* empties the log, numbering the next change after `sequence` */
+    pub fn restart(&mut self, sequence: i64) {
+        self.entries.clear();
+        self.start = sequence;
+        self.latest = sequence;
+    }
+
+    /* numbers a change and keeps it, discarding the oldest when full */
+    pub fn record(&mut self, kind: i32, table_id: i32, object_id: i64,
+        version: i64, values: Vec<Value>) -> & Change
+    {
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.latest += 1;
+        self.entries.push_back(Change {
+            sequence: self.latest,
+            kind: kind,
+            table_id: table_id,
+            object_id: object_id,
+            version: version,
+            values: values,
+        });
+        self.entries.back().unwrap()
+    }
+
+    /*
+     * Up to `limit` changes after sequence `since`, to one table or to
+     * every table when table_id is 0, and the sequence to continue from.
+     * `since` 0 reads from the start of the log. TRUNCATED if the log no
+     * longer holds every change after `since`, because they were discarded
+     * or `since` is from before a restart.
+     */
+    pub fn since(& self, table_id: i32, since: i64, limit: i32)
+        -> Result<(i64, Vec<& Change>), i32>
+    {
+        let since = if since == 0 { self.start } else { since };
+        let discarded = self.latest - self.entries.len() as i64;
+        if since < discarded || since > self.latest {
+            return Err(Response::TRUNCATED);
+        }
+        let limit = match limit {
+            limit if limit > 0 => (limit as usize).min(MAX_PAGE),
+            _ => MAX_PAGE,
+        };
+
+        let mut cursor = since;
+        let mut page = vec![];
+        for change in self.entries.iter().skip((since - discarded) as usize) {
+            if page.len() == limit {
+                break;
+            }
+            cursor = change.sequence;
+            if table_id == 0 || change.table_id == table_id {
+                page.push(change);
+            }
+        }
+        Ok((cursor, page))
+    }
+}
@@ -0,0 +1,279 @@
+/*
+ * config.rs
+ *
+ * Reads EasyDB server settings from a TOML file and the command line
+ *
+ * University of Toronto
+ * 2019
+ */
+
+use std::fs;
+use std::fmt;
+use std::time::Duration;
+use packet;
+
+/*
+ * Effective server settings. Defaults are overridden by the config file,
+ * which is in turn overridden by command line flags.
+ */
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    pub metrics_port: u16,          /* HTTP metrics port, 0 disables */
+    pub schema: String,
+    pub max_clients: i64,
+    pub data_dir: String,
+    pub log_level: String,
+    pub log_format: String,         /* text or json */
+    pub log_file: String,           /* empty for stderr */
+    pub log_max_size: u64,          /* bytes before rotating, 0 never */
+    pub log_max_files: u32,         /* rotated files to keep */
+    pub idle_timeout: u64,          /* seconds, 0 disables the timeout */
+    pub read_timeout: u64,          /* seconds to finish reading a request */
+    pub write_timeout: u64,         /* seconds to finish sending a response */
+    pub max_packet_size: usize,     /* bytes */
+    pub event_queue: usize,         /* change events held per subscriber */
+    pub change_log_size: usize,     /* changes kept for CHANGES requests */
+    pub replica_of: String,         /* leader host:port, empty on a leader */
+    pub cluster: String,            /* peer host:port of every node, by id */
+    pub node_id: usize,             /* this node in the cluster, from 1 */
+    pub admin_token: String,        /* empty disables admin commands */
+}
+
+const LOG_LEVELS: [&str; 5] = ["error", "warn", "info", "debug", "trace"];
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            host: String::from("localhost"),
+            port: 0,
+            metrics_port: 0,
+            schema: String::from("default.txt"),
+            max_clients: 4,
+            data_dir: String::from("."),
+            log_level: String::from("info"),
+            log_format: String::from("text"),
+            log_file: String::new(),
+            log_max_size: 10 * 1024 * 1024,
+            log_max_files: 5,
+            idle_timeout: 300,
+            read_timeout: 5,
+            write_timeout: 5,
+            max_packet_size: packet::MAX_PACKET_SIZE,
+            event_queue: 1024,
+            change_log_size: 10000,
+            replica_of: String::new(),
+            cluster: String::new(),
+            node_id: 0,
+            admin_token: String::new(),
+        }
+    }
+
+    /* host:port string to bind the listener to */
+    pub fn address(& self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    /* peer addresses of the cluster nodes, empty if not clustered */
+    pub fn cluster_nodes(& self) -> Vec<String> {
+        self.cluster_nodes_of(&self.cluster)
+    }
+
+    fn cluster_nodes_of(& self, cluster: &str) -> Vec<String> {
+        cluster.split(',').map(|node| node.trim().to_string())
+            .filter(|node| node.len() > 0).collect()
+    }
+
+    /* converts a timeout setting, where 0 means no timeout */
+    pub fn timeout(seconds: u64) -> Option<Duration> {
+        match seconds {
+            0 => None,
+            _ => Some(Duration::from_secs(seconds)),
+        }
+    }
+
+    /*
+     * Sets a single setting by its config file key. Command line flags are
+     * mapped onto the same keys so both sources share the validation.
+     */
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "host" => self.host = value.to_string(),
+            "port" => self.port = parse_number(key, value)?,
+            "metrics_port" => self.metrics_port = parse_number(key, value)?,
+            "schema" => self.schema = value.to_string(),
+            "max_clients" => {
+                self.max_clients = parse_number(key, value)?;
+                if self.max_clients < 1 {
+                    return Err(format!("{} must be at least 1", key));
+                }
+            },
+            "data_dir" => self.data_dir = value.to_string(),
+            "log_level" => {
+                if !LOG_LEVELS.contains(&value) {
+                    return Err(format!("{} must be one of {}", key,
+                                       LOG_LEVELS.join(", ")));
+                }
+                self.log_level = value.to_string();
+            },
+            "log_format" => {
+                if value != "text" && value != "json" {
+                    return Err(format!("{} must be text or json", key));
+                }
+                self.log_format = value.to_string();
+            },
+            "log_file" => self.log_file = value.to_string(),
+            "log_max_size" => self.log_max_size = parse_number(key, value)?,
+            "log_max_files" => self.log_max_files = parse_number(key, value)?,
+            "idle_timeout" => self.idle_timeout = parse_number(key, value)?,
+            "read_timeout" => self.read_timeout = parse_number(key, value)?,
+            "write_timeout" => self.write_timeout = parse_number(key, value)?,
+            "max_packet_size" => {
+                self.max_packet_size = parse_number(key, value)?;
+                if self.max_packet_size < 64 {
+                    return Err(format!("{} must be at least 64", key));
+                }
+            },
+            "event_queue" => {
+                self.event_queue = parse_number(key, value)?;
+                if self.event_queue < 1 {
+                    return Err(format!("{} must be at least 1", key));
+                }
+            },
+            "change_log_size" => {
+                self.change_log_size = parse_number(key, value)?;
+                if self.change_log_size < 1 {
+                    return Err(format!("{} must be at least 1", key));
+                }
+            },
+            "replica_of" => {
+                if value.len() > 0 && !value.contains(':') {
+                    return Err(format!("{} must be host:port", key));
+                }
+                self.replica_of = value.to_string();
+            },
+            "cluster" => {
+                let nodes = self.cluster_nodes_of(value);
+                if nodes.iter().any(|n| !n.contains(':')) {
+                    return Err(format!("{} must be a list of host:port", key));
+                }
+                self.cluster = value.to_string();
+            },
+            "node_id" => self.node_id = parse_number(key, value)?,
+            "admin_token" => self.admin_token = value.to_string(),
+            _ => return Err(format!("unknown setting '{}'", key)),
+        }
+        Ok(())
+    }
+
+    /*
+     * Applies a TOML config file. Only the subset needed for flat
+     * settings is supported: `key = value` pairs, `#` comments, quoted
+     * strings, integers and booleans. Section headers are accepted and
+     * ignored so the file can be grouped under `[server]`.
+     */
+    pub fn load_file(&mut self, filename: &str) -> Result<(), String> {
+        let contents = match fs::read_to_string(filename) {
+            Ok(contents) => contents,
+            Err(e) => return Err(format!("could not read {}: {}", filename, e)),
+        };
+
+        for (i, line) in contents.lines().enumerate() {
+            let line = strip_comment(line).trim();
+            if line.len() == 0 ||
+                (line.starts_with("[") && line.ends_with("]")) {
+                continue;
+            }
+
+            let (key, value) = match line.find('=') {
+                Some(pos) => (line[..pos].trim(), line[pos + 1..].trim()),
+                None => return Err(format!("{}:{}: expecting 'key = value'",
+                                           filename, i + 1)),
+            };
+
+            let value = match parse_toml_value(value) {
+                Some(value) => value,
+                None => return Err(format!("{}:{}: invalid value for '{}'",
+                                           filename, i + 1, key)),
+            };
+
+            if let Err(e) = self.set(key, &value) {
+                return Err(format!("{}:{}: {}", filename, i + 1, e));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(key: &str, value: &str)
+    -> Result<T, String>
+{
+    match value.parse::<T>() {
+        Ok(n) => Ok(n),
+        Err(_) => Err(format!("{} must be a non-negative number", key)),
+    }
+}
+
+/* removes a trailing `#` comment that is not inside a quoted string */
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, ch) in line.char_indices() {
+        match ch {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => (),
+        }
+    }
+    line
+}
+
+/* returns the raw text of a TOML string, integer or boolean */
+fn parse_toml_value(value: &str) -> Option<String> {
+    if value.len() >= 2 && value.starts_with("\"") && value.ends_with("\"") {
+        let inner = &value[1..value.len() - 1];
+        if inner.contains('"') {
+            return None;
+        }
+        return Some(inner.to_string());
+    }
+
+    if value == "true" || value == "false" {
+        return Some(value.to_string());
+    }
+
+    /* TOML allows underscores as digit separators */
+    let digits: String = value.chars().filter(|c| *c != '_').collect();
+    if digits.len() > 0 && digits.chars().all(|c| c.is_ascii_digit()) {
+        return Some(digits);
+    }
+    None
+}
+
+/* Printed at startup in verbose mode, in the config file format */
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "host = \"{}\"\n", self.host)?;
+        write!(f, "port = {}\n", self.port)?;
+        write!(f, "metrics_port = {}\n", self.metrics_port)?;
+        write!(f, "schema = \"{}\"\n", self.schema)?;
+        write!(f, "max_clients = {}\n", self.max_clients)?;
+        write!(f, "data_dir = \"{}\"\n", self.data_dir)?;
+        write!(f, "log_level = \"{}\"\n", self.log_level)?;
+        write!(f, "log_format = \"{}\"\n", self.log_format)?;
+        write!(f, "log_file = \"{}\"\n", self.log_file)?;
+        write!(f, "log_max_size = {}\n", self.log_max_size)?;
+        write!(f, "log_max_files = {}\n", self.log_max_files)?;
+        write!(f, "idle_timeout = {}\n", self.idle_timeout)?;
+        write!(f, "read_timeout = {}\n", self.read_timeout)?;
+        write!(f, "write_timeout = {}\n", self.write_timeout)?;
+        write!(f, "max_packet_size = {}\n", self.max_packet_size)?;
+        write!(f, "event_queue = {}\n", self.event_queue)?;
+        write!(f, "change_log_size = {}\n", self.change_log_size)?;
+        write!(f, "replica_of = \"{}\"\n", self.replica_of)?;
+        write!(f, "cluster = \"{}\"\n", self.cluster)?;
+        write!(f, "node_id = {}\n", self.node_id)?;
+        /* never print the token itself */
+        write!(f, "admin_token = \"{}\"", 
+               if self.admin_token.len() > 0 { "<set>" } else { "" })
+    }
+}
@@ -7,9 +7,23 @@
  * 2019
  */
 
-use packet::{Command, Request, Response, Value};
-use schema::Table;
+use changelog::ChangeLog;
+use decimal;
+use expr::Check;
+use filter;
+use filter::Filter;
+use index::Index;
+use json;
+use pattern;
+use replica::Replica;
+use packet::{Change, Command, Migration, ReplicaStatus, Request, Response, 
+             Value};
+use schema;
+use schema::{Column, Table};
+use std::collections::HashMap;
 use std::fmt;
+use std::mem;
+use subscription::{Queue, Subscriptions};
  
 /* OP codes for the query command */
 pub const OP_AL: i32 = 1;
@@ -19,9 +33,23 @@ pub const OP_LT: i32 = 4;
 pub const OP_GT: i32 = 5;
 pub const OP_LE: i32 = 6;
 pub const OP_GE: i32 = 7;
+pub const OP_CONTAINS: i32 = 8;     /* list holds the value, or substring */
+pub const OP_STARTS_WITH: i32 = 9;
+pub const OP_ENDS_WITH: i32 = 10;
+pub const OP_LIKE: i32 = 11;        /* SQL LIKE with % and _ */
+pub const OP_EQ_NOCASE: i32 = 12;   /* equal ignoring case */
+pub const OP_REGEX: i32 = 13;
+pub const OP_IS_NULL: i32 = 14;     /* the operand is ignored */
+pub const OP_IS_NOT_NULL: i32 = 15;
+pub const OP_IN: i32 = 16;          /* the operand is a list of values */
+pub const OP_BETWEEN: i32 = 17;     /* the operand is a [low, high] list */
+
+/* reserved column id that scans use for the object id of each row */
+pub const ID_COLUMN: i32 = 0;
 
 /* You can implement your Database structure here
  * Q: How you will store your tables into the database? */
+#[derive(Debug)]
 pub struct Row {
     pub table_id: i32,
     pub object_id: i64,
@@ -48,22 +76,133 @@ impl fmt::Display for Row {
 
 pub struct Database { 
     pub tables: Vec<Table>,
-    pub row_objects: Vec<Row>
+    pub row_objects: Vec<Row>,
+    /* ids of dropped tables, never reused */
+    dropped_tables: Vec<i32>,
+    /* indexes of unique columns, by (table id, column id) */
+    indexes: HashMap<(i32, i32), Index>,
+    subscriptions: Subscriptions,
+    change_log: ChangeLog,
+    /* set on followers, which take their rows from a leader */
+    pub replica: Option<Replica>,
+    /* set on leaders once a follower has taken a snapshot */
+    pub followed: bool,
 }
 
 impl Database {
-    pub fn new(table_schema: Vec<Table>) -> Database {
-        Database {
+    pub fn new(table_schema: Vec<Table>, change_log_size: usize) -> Database {
+        let mut db = Database {
             tables: table_schema,
             row_objects: vec![],
+            dropped_tables: vec![],
+            indexes: HashMap::new(),
+            subscriptions: Subscriptions::new(),
+            change_log: ChangeLog::new(change_log_size),
+            replica: None,
+            followed: false,
+        };
+        db.build_indexes();
+        db
+    }
+    
+    /* (re)creates the index of every unique column from the stored rows */
+    fn build_indexes(&mut self) {
+        self.indexes.clear();
+        for table in &self.tables {
+            for (j, column) in table.t_cols.iter().enumerate() {
+                if !column.c_unique {
+                    continue;
+                }
+                let mut index = Index::new();
+                for row in &self.row_objects {
+                    if row.table_id == table.t_id {
+                        index.insert(&row.values[j], row.object_id);
+                    }
+                }
+                self.indexes.insert((table.t_id, column.c_id), index);
+            }
+        }
+    }
+    
+    /* 
+     * Records a change to a row in the change log and sends it to the
+     * subscriptions that match the row before or after the change
+     */
+    fn changed(&mut self, kind: i32, table_id: i32, object_id: i64, 
+        version: i64, old: Option<& Vec<Value>>, new: Option<& Vec<Value>>)
+    {
+        let values = new.or(old).cloned().unwrap_or(vec![]);
+        let change = self.change_log.record(kind, table_id, object_id, 
+                                            version, values);
+        self.subscriptions.publish(change, old, new);
+    }
+    
+    /* adds or removes a row's values in the indexes of its table */
+    fn index_row(&mut self, table_index: usize, object_id: i64, 
+        values: & Vec<Value>, add: bool) 
+    {
+        let table = &self.tables[table_index];
+        for (j, column) in table.t_cols.iter().enumerate() {
+            if let Some(index) = self.indexes.get_mut(&(table.t_id, column.c_id)) {
+                if add {
+                    index.insert(&values[j], object_id);
+                }
+                else {
+                    index.remove(&values[j], object_id);
+                }
+            }
         }
     }
+    
+    /* sequence of the last change made, or applied from a leader */
+    pub fn applied(& self) -> i64 {
+        self.change_log.latest()
+    }
+    
+    /* (table id, number of rows) for every table */
+    pub fn row_counts(& self) -> Vec<(i32, i64)> {
+        self.tables.iter().map(|table| {
+            let rows = self.row_objects.iter()
+                .filter(|row| row.table_id == table.t_id).count();
+            (table.t_id, rows as i64)
+        }).collect()
+    }
+    
+    /* rough number of bytes used to store the rows */
+    pub fn memory_estimate(& self) -> usize {
+        let mut total = self.row_objects.capacity() * mem::size_of::<Row>();
+        for row in &self.row_objects {
+            total += row.values.capacity() * mem::size_of::<Value>();
+            for value in &row.values {
+                total += heap_size(value);
+            }
+        }
+        total
+    }
+}
+
+/* bytes a value holds outside of the Value itself */
+fn heap_size(value: & Value) -> usize {
+    match value {
+        Value::Text(s) | Value::Json(s) => s.capacity(),
+        Value::Bytes(b) => b.capacity(),
+        Value::List(values) => values.capacity() * mem::size_of::<Value>()
+            + values.iter().map(heap_size).sum::<usize>(),
+        _ => 0,
+    }
 }
 
 /* Receive the request packet from client and send a response back */
 pub fn handle_request(request: Request, db: & mut Database) 
     -> Response  
 {           
+    /* followers only change rows as their leader tells them */
+    if request.command.is_write() {
+        if let Some(response) = not_leader(db) {
+            return response;
+        }
+    }
+
     /* Handle a valid request */
     let result = match request.command {
         Command::Insert(values) => 
@@ -74,8 +213,17 @@ pub fn handle_request(request: Request, db: & mut Database)
         Command::Get(id) => handle_get(db, request.table_id, id),
         Command::Query(column_id, operator, value) => 
             handle_query(db, request.table_id, column_id, operator, value),
+        Command::Schema => Ok(Response::Schema(&db.tables)),
+        Command::Sum(column_id) => handle_sum(db, request.table_id, column_id),
+        Command::Changes(since, limit) => 
+            handle_changes(db, request.table_id, since, limit),
+        Command::Snapshot => {
+            db.followed = true;
+            Ok(Response::Snapshot(db.change_log.latest(), &db.row_objects))
+        },
+        Command::Replication => Ok(handle_replication(db)),
         /* should never get here */
-        Command::Exit => Err(Response::UNIMPLEMENTED),
+        _ => Err(Response::UNIMPLEMENTED),
     };
     
     /* Send back a response */
@@ -88,82 +236,175 @@ pub fn handle_request(request: Request, db: & mut Database)
 /*
  * TODO: Implment these EasyDB functions
  */
- 
-fn handle_insert(db: & mut Database, table_id: i32, values: Vec<Value>) 
-    -> Result<Response, i32> 
-{
-    //Check if table_id exists in Database
-    let mut table_id_exist: bool = false;
-    let mut table_object_index: usize = 0;
-
-    for i in 0..db.tables.len() {
-        if table_id == db.tables[i].t_id {
-            table_id_exist = true;
-            table_object_index = i;
-        }
-    }
 
-    if !table_id_exist {
-        return Err(Response::BAD_TABLE);
-    }
+/* Checks a row of values against the columns of the table at table_index */
+fn check_values(db: & Database, table_index: usize, values: & Vec<Value>)
+    -> Result<(), i32>
+{
+    let table = &db.tables[table_index];
     
     //Check number of values matches number of columns
-    if values.len() != db.tables[table_object_index].t_cols.len() {
+    if values.len() != table.t_cols.len() {
         return Err(Response::BAD_ROW);
     }
 
-    //Check for column type mismatches and bad foreign key
     for i in 0..values.len() {
-        let value_type: i32;
-        let mut foreign_value: i64 = 0;
-
-        //Find value's type
-        match &values[i] {
-            Value::Null => value_type = Value::NULL,
-            Value::Integer(val) => value_type = Value::INTEGER,
-            Value::Float(val) => value_type = Value::FLOAT,
-            Value::Text(val) => value_type = Value::STRING,
-            Value::Foreign(val) => {
-                value_type = Value::FOREIGN;
-                foreign_value = *val;
-            },
-        }
+        check_value(db, &table.t_cols[i], &values[i])?;
+    }
+    Ok(())
+}
 
-        if value_type == Value::INTEGER && db.tables[table_object_index].t_cols[i].c_type != Value::INTEGER {
+/* 
+ * Checks a value's type, that a not null column has a value, and that a
+ * foreign key refers to an existing row 
+ */
+fn check_value(db: & Database, column: & Column, value: & Value)
+    -> Result<(), i32>
+{
+    if *value == Value::Null {
+        if column.c_not_null {
+            return Err(Response::NOT_NULL);
+        }
+        return Ok(());
+    }
+    
+    if value.type_code() != column.value_type() {
+        return Err(Response::BAD_VALUE);
+    }
+    
+    //Decimals are stored at the column's scale, within its precision
+    if let Value::Decimal(val, scale) = value {
+        if *scale != column.c_scale || !decimal::fits(*val, column.c_precision) {
             return Err(Response::BAD_VALUE);
         }
-        else if value_type == Value::FLOAT && db.tables[table_object_index].t_cols[i].c_type != Value::FLOAT {
+    }
+    
+    //Lists hold values of their element type, without nulls
+    if let Value::List(values) = value {
+        if values.iter().any(|v| v.type_code() != column.c_element) {
             return Err(Response::BAD_VALUE);
         }
-        else if value_type == Value::STRING && db.tables[table_object_index].t_cols[i].c_type != Value::STRING {
+    }
+    
+    //Documents must be valid JSON
+    if let Value::Json(text) = value {
+        if json::parse(text).is_err() {
             return Err(Response::BAD_VALUE);
         }
-        else if value_type == Value::FOREIGN {
-            if db.tables[table_object_index].t_cols[i].c_type != Value::FOREIGN {
-                return Err(Response::BAD_VALUE);
+    }
+    
+    //Enum values must be one of those declared
+    if let Value::Text(text) = value {
+        if column.c_type == Value::ENUM && column.variant_index(text).is_none() {
+            return Err(Response::BAD_VALUE);
+        }
+    }
+    
+    //Check if foreign key reference exists, 0 being a null reference
+    if let Value::Foreign(foreign_value) = value {
+        let foreign_key_exist = *foreign_value == 0 || 
+            db.row_objects.iter().any(|row| row.table_id == column.c_ref
+                                      && row.object_id == *foreign_value);
+        if !foreign_key_exist {
+            return Err(Response::BAD_FOREIGN);
+        }
+    }
+    Ok(())
+}
+
+/* 
+ * Checks the values of unique columns against their indexes. The row 
+ * being updated, if any, may keep its own values.
+ */
+fn check_unique(db: & Database, table_index: usize, object_id: i64, 
+    values: & Vec<Value>) -> Result<(), i32>
+{
+    let table = &db.tables[table_index];
+    for (j, column) in table.t_cols.iter().enumerate() {
+        if let Some(index) = db.indexes.get(&(table.t_id, column.c_id)) {
+            if index.get(&values[j]).iter().any(|id| *id != object_id) {
+                return Err(Response::NOT_UNIQUE);
             }
-            else {
-                //Check if foreign key reference exists
-                //let foreign_table_id = db.tables[table_object_index].t_cols[i].c_ref;
-                let mut foreign_key_exist = false;
-
-                for j in 0..db.row_objects.len() {
-                    if db.tables[table_object_index].t_cols[i].c_ref == db.row_objects[j].table_id && foreign_value == db.row_objects[j].object_id {
-                        foreign_key_exist = true;
-                    }
-                }
+        }
+    }
+    Ok(())
+}
 
-                if foreign_value == 0 {
-                    foreign_key_exist = true;
-                }
+/* 
+ * Evaluates the check constraints of a table over a row and returns the
+ * first one that fails. A check that evaluates to null passes, while one
+ * that cannot be evaluated, e.g. on division by zero, fails.
+ */
+fn failed_check(table: & Table, values: & Vec<Value>) 
+    -> Option<Response<'static>> 
+{
+    table.t_checks.iter().find(|check| fails(check, &table.t_cols, values))
+        .map(|check| Response::Violation(check.name.clone()))
+}
 
-                if !foreign_key_exist {
-                    return Err(Response::BAD_FOREIGN);
-                }
-            }
+fn fails(check: & Check, columns: & Vec<Column>, values: & Vec<Value>) 
+    -> bool 
+{
+    match check.expr.eval(values, columns) {
+        Ok(Value::Boolean(false)) | Err(_) => true,
+        Ok(_) => false,
+    }
+}
+
+/* fills in the defaults of columns an insert leaves null */
+fn apply_defaults(table: & Table, values: & mut Vec<Value>) {
+    for (value, column) in values.iter_mut().zip(&table.t_cols) {
+        if *value == Value::Null {
+            *value = column.c_default.clone();
+        }
+    }
+}
+
+/* 
+ * Brings decimals to the scale of their columns. Values that would lose
+ * digits are left as they are, for check_value to reject.
+ */
+fn fit_decimals(table: & Table, values: & mut Vec<Value>) {
+    for (value, column) in values.iter_mut().zip(&table.t_cols) {
+        fit_decimal(column, value);
+    }
+}
+
+fn fit_decimal(column: & Column, value: & mut Value) {
+    if let Value::Decimal(val, scale) = *value {
+        if let Some(val) = decimal::rescale(val, scale, column.c_scale) {
+            *value = Value::Decimal(val, column.c_scale);
+        }
+    }
+}
+ 
+fn handle_insert(db: & mut Database, table_id: i32, mut values: Vec<Value>) 
+    -> Result<Response, i32> 
+{
+    //Check if table_id exists in Database
+    let mut table_id_exist: bool = false;
+    let mut table_object_index: usize = 0;
+
+    for i in 0..db.tables.len() {
+        if table_id == db.tables[i].t_id {
+            table_id_exist = true;
+            table_object_index = i;
         }
     }
 
+    if !table_id_exist {
+        return Err(Response::BAD_TABLE);
+    }
+    
+    //Check number of values, column types, constraints and foreign keys
+    apply_defaults(&db.tables[table_object_index], &mut values);
+    fit_decimals(&db.tables[table_object_index], &mut values);
+    check_values(db, table_object_index, &values)?;
+    let table = &db.tables[table_object_index];
+    if let Some(violation) = failed_check(table, &values) {
+        return Ok(violation);
+    }
+    check_unique(db, table_object_index, 0, &values)?;
 
     //All checks passed
     //Insert the row
@@ -180,6 +421,9 @@ fn handle_insert(db: & mut Database, table_id: i32, values: Vec<Value>)
     let version: i64 = 1;
     let response: Response = Response::Insert(insert_row_id, version);
 
+    db.index_row(table_object_index, insert_row_id, &values, true);
+    db.changed(Change::INSERT, table_id, insert_row_id, version, None, 
+               Some(&values));
     let new_row: Row = Row::new(table_id, insert_row_id, version, values);
     db.row_objects.push(new_row);
    
@@ -187,7 +431,7 @@ fn handle_insert(db: & mut Database, table_id: i32, values: Vec<Value>)
 }
 
 fn handle_update(db: & mut Database, table_id: i32, object_id: i64, 
-    version: i64, values: Vec<Value>) -> Result<Response, i32> 
+    version: i64, mut values: Vec<Value>) -> Result<Response, i32> 
 {
     //Check if table_id exists in Database
     let mut table_id_exist: bool = false;
@@ -219,64 +463,14 @@ fn handle_update(db: & mut Database, table_id: i32, object_id: i64,
         return Err(Response::NOT_FOUND);
     }
 
-    //Check number of values matches number of columns
-    if values.len() != db.tables[table_object_index].t_cols.len() {
-        return Err(Response::BAD_ROW);
-    }
-
-    //Check for column type mismatches and bad foreign key
-    for i in 0..values.len() {
-        let value_type: i32;
-        let mut foreign_value: i64 = 0;
-
-        //Find value's type
-        match &values[i] {
-            Value::Null => value_type = Value::NULL,
-            Value::Integer(val) => value_type = Value::INTEGER,
-            Value::Float(val) => value_type = Value::FLOAT,
-            Value::Text(val) => value_type = Value::STRING,
-            Value::Foreign(val) => {
-                value_type = Value::FOREIGN;
-                foreign_value = *val;
-            },
-        }
-
-        if value_type == Value::INTEGER && db.tables[table_object_index].t_cols[i].c_type != Value::INTEGER {
-            return Err(Response::BAD_VALUE);
-        }
-        else if value_type == Value::FLOAT && db.tables[table_object_index].t_cols[i].c_type != Value::FLOAT {
-            return Err(Response::BAD_VALUE);
-        }
-        else if value_type == Value::STRING && db.tables[table_object_index].t_cols[i].c_type != Value::STRING {
-            return Err(Response::BAD_VALUE);
-        }
-        else if value_type == Value::FOREIGN {
-            if db.tables[table_object_index].t_cols[i].c_type != Value::FOREIGN {
-                return Err(Response::BAD_VALUE);
-            }
-            else {
-                //Check if foreign key reference exists
-                //let foreign_table_id = db.tables[table_object_index].t_cols[i].c_ref;
-                let mut foreign_key_exist = false;
-
-                for j in 0..db.row_objects.len() {
-                    if db.tables[table_object_index].t_cols[i].c_ref == db.row_objects[j].table_id && foreign_value == db.row_objects[j].object_id {
-                        foreign_key_exist = true;
-                    }
-                }
-
-                if foreign_value == 0 {
-                    foreign_key_exist = true;
-                }
-
-                if !foreign_key_exist {
-                    return Err(Response::BAD_FOREIGN);
-                }
-
-            }
-        }
-
+    //Check number of values, column types, constraints and foreign keys
+    fit_decimals(&db.tables[table_object_index], &mut values);
+    check_values(db, table_object_index, &values)?;
+    let table = &db.tables[table_object_index];
+    if let Some(violation) = failed_check(table, &values) {
+        return Ok(violation);
     }
+    check_unique(db, table_object_index, object_id, &values)?;
 
     //Check if version number matches or if version = 0
     let mut version_match: bool = false;
@@ -297,6 +491,14 @@ fn handle_update(db: & mut Database, table_id: i32, object_id: i64,
     let new_version: i64 = db.row_objects[row_object_index].version + 1;
     let response: Response = Response::Update(new_version);
 
+    let old_values = mem::replace(&mut db.row_objects[row_object_index].values, 
+                                  vec![]);
+    db.index_row(table_object_index, object_id, &old_values, false);
+    db.index_row(table_object_index, object_id, &values, true);
+
+    db.changed(Change::UPDATE, table_id, object_id, new_version, 
+               Some(&old_values), Some(&values));
+
     db.row_objects[row_object_index].version = new_version;
     db.row_objects[row_object_index].values = values;
 
@@ -342,39 +544,34 @@ fn handle_drop(db: & mut Database, table_id: i32, object_id: i64)
         return Err(Response::NOT_FOUND);
     }
     
+    ref_object.push(row_object_index);
+    
     //only when schema has foreign, find foreigners
     if schema_has_foreign {
-        let first_ref_object = find_referenced_row(db, table_id, object_id);
+        //visit every row that references a dropped row, transitively.
+        //references can form cycles, so each row is only visited once
+        let mut i = 0;
         
-        if first_ref_object.len() != 0 {
-            for i in 0..first_ref_object.len() {
-                //push the first foreigners
-                ref_object.push(first_ref_object[i]);
-                
-                //find if there is any secondary foreigners
-                let second_ref_object = find_referenced_row(db, db.row_objects[first_ref_object[i]].table_id, db.row_objects[first_ref_object[i]].object_id);
-                if second_ref_object.len() != 0 {
-                    for j in 0..second_ref_object.len() {
-                        ref_object.push(second_ref_object[j]);
-                    }
+        while i < ref_object.len() {
+            let row = &db.row_objects[ref_object[i]];
+            for index in find_referenced_row(db, row.table_id, row.object_id) {
+                if !ref_object.contains(&index) {
+                    ref_object.push(index);
                 }
             }
+            i += 1;
         }
     }
     
-    //start dropping
-    db.row_objects.remove(row_object_index);
-    
-    if ref_object.len() != 0 {
-        ref_object.sort();
-        ref_object.dedup();
-        
-        let mut removal_count: usize = 1;
-        
-        for i in 0..ref_object.len() {
-            db.row_objects.remove(ref_object[i] - removal_count);
-            removal_count += 1;
-        }
+    //start dropping, from the back so indices stay valid
+    ref_object.sort();
+    for index in ref_object.iter().rev() {
+        let row = db.row_objects.remove(*index);
+        let table_index = db.tables.iter()
+            .position(|t| t.t_id == row.table_id).unwrap();
+        db.index_row(table_index, row.object_id, &row.values, false);
+        db.changed(Change::DROP, row.table_id, row.object_id, row.version, 
+                   Some(&row.values), None);
     }
     
     Ok(Response::Drop)
@@ -422,191 +619,187 @@ fn handle_query(db: & Database, table_id: i32, column_id: i32,
     operator: i32, other: Value) 
     -> Result<Response, i32>
 {
-    let mut matched_results = Vec::new();
-
     //Check if table_id exists in Database
-    let mut table_id_exist: bool = false;
-
-    for i in 0..db.tables.len() {
-        if table_id == db.tables[i].t_id {
-            table_id_exist = true;
-        }
-    }
-
-    if !table_id_exist {
-        return Err(Response::BAD_TABLE);
-    }
-    
-    //column infomation
-    let mut col_id_exist: bool = false;
-    let mut col_index: usize = 0;
-    let mut col_type: i32 = 0;
-    
-    //column_id must be zero for OP_AL
-    if operator == OP_AL && column_id != 0 {
-        return Err(Response::BAD_QUERY);
-    }
+    let table = match db.tables.iter().find(|t| t.t_id == table_id) {
+        Some(table) => table,
+        None => return Err(Response::BAD_TABLE),
+    };
     
-    for i in 0..db.tables.len() {
-        for j in 0..db.tables[i].t_cols.len() {
-            
-            if table_id == db.tables[i].t_id && column_id == db.tables[i].t_cols[j].c_id {
-                col_id_exist = true;
-                col_index = j;
-                col_type = db.tables[i].t_cols[j].c_type;
-                
-                //only EQ and NE are supported for foreign and id
-                if col_type == Value::FOREIGN || db.tables[i].t_cols[j].c_name == "id" {
-                    if operator != OP_EQ && operator != OP_NE && operator != OP_AL{
-                        return Err(Response::BAD_QUERY);
-                    }
-                }
-            }
-        }
+    let filter = filter::compile(table, column_id, operator, other.clone())?;
+    if let Some(matched_results) = 
+        indexed_scan(db, table, column_id, operator, &other, &filter) {
+        return Ok(Response::Query(matched_results));
     }
     
-    //Invalid column_id
-    if !col_id_exist {
-        if operator != OP_AL {
-            return Err(Response::BAD_QUERY); 
-        }
+    let matched_results = db.row_objects.iter()
+        .filter(|row| row.table_id == table_id)
+        .filter(|row| filter.matches(row.object_id, &row.values))
+        .map(|row| row.object_id).collect();
+    Ok(Response::Query(matched_results))
+}
+
+/*
+ * Answers a scan without testing every row of the table, or returns None.
+ * Id ranges use the storage order, since the rows of a table are stored
+ * in increasing id order. Equality, sets, ranges and prefixes of a column
+ * use its index, except for ranges of enums, whose index is not in 
+ * declaration order.
+ */
+fn indexed_scan(db: & Database, table: & Table, column_id: i32, operator: i32,
+    other: & Value, filter: & Filter) -> Option<Vec<i64>>
+{
+    if column_id == ID_COLUMN {
+        let (low, high) = id_bounds(operator, other)?;
+        return Some(db.row_objects.iter()
+            .filter(|row| row.table_id == table.t_id)
+            .skip_while(|row| (row.object_id as i128) < low)
+            .take_while(|row| (row.object_id as i128) <= high)
+            .filter(|row| filter.matches(row.object_id, &row.values))
+            .map(|row| row.object_id).collect());
     }
     
-    //case OP_AL: regard less column_id and other
-    if operator == OP_AL {
-        for i in 0..db.row_objects.len() {
-            if table_id == db.row_objects[i].table_id {
-                matched_results.push(db.row_objects[i].object_id);
-            }
-        }
+    let column = table.t_cols.iter().find(|c| c.c_id == column_id)?;
+    let index = db.indexes.get(&(table.t_id, column_id))?;
+    if column.c_type == Value::JSON {
+        return None;
     }
-    
-    //Parse other type and value
-    let other_type: i32;
-    let mut other_val_int: i64 = 0;
-    let mut other_val_float: f64 = 0.0;
-    let mut other_val_text: String = String::from(' ');
-    let mut other_val_foreign: i64 = 0;
-    
-    match &other {
-        Value::Null => other_type = Value::NULL,
-        Value::Integer(val) => {
-            other_type = Value::INTEGER;
-            other_val_int = *val;
-        },
-        Value::Float(val) => {
-            other_type = Value::FLOAT;
-            other_val_float = *val;
-        },
-        Value::Text(val) => {
-            other_type = Value::STRING;
-            other_val_text = val.to_string();
+    let mut ids = match (operator, other) {
+        (OP_EQ, value) if *value != Value::Null => index.get(value),
+        (OP_IN, Value::List(values)) => values.iter()
+            .flat_map(|value| index.get(value)).collect(),
+        (OP_BETWEEN, Value::List(bounds)) if column.c_type != Value::ENUM 
+            && !bounds.contains(&Value::Null) => 
+            index.range(&bounds[0], &bounds[1]),
+        (OP_STARTS_WITH, Value::Text(pattern)) | (OP_LIKE, Value::Text(pattern))
+            if column.c_type == Value::STRING => {
+            let prefix = match operator {
+                OP_LIKE => pattern::like_prefix(pattern),
+                _ => pattern.clone(),
+            };
+            index.prefix(&prefix).into_iter()
+                .filter(|(text, _)| filter.test(&Value::Text(text.clone())))
+                .map(|(_, id)| id).collect()
         },
-        Value::Foreign(val) => {
-            other_type = Value::FOREIGN;
-            other_val_foreign = *val;
+        _ => return None,
+    };
+    ids.sort();
+    ids.dedup();
+    Some(ids)
+}
+
+/* inclusive bounds on the ids an id scan can match, as i128 so n ± 1 fits */
+fn id_bounds(operator: i32, other: & Value) -> Option<(i128, i128)> {
+    let (min, max) = (i64::MIN as i128, i64::MAX as i128);
+    match (operator, other) {
+        (OP_BETWEEN, Value::List(bounds)) => match (&bounds[0], &bounds[1]) {
+            (Value::Integer(low), Value::Integer(high)) => 
+                Some((*low as i128, *high as i128)),
+            _ => None,
         },
+        (OP_EQ, Value::Integer(n)) => Some((*n as i128, *n as i128)),
+        (OP_LT, Value::Integer(n)) => Some((min, *n as i128 - 1)),
+        (OP_GT, Value::Integer(n)) => Some((*n as i128 + 1, max)),
+        (OP_LE, Value::Integer(n)) => Some((min, *n as i128)),
+        (OP_GE, Value::Integer(n)) => Some((*n as i128, max)),
+        _ => None,
     }
-    
-    //Invalid value type
-    if col_type != other_type {
-        return Err(Response::BAD_QUERY); 
+}
+
+/* 
+ * Pages through the change log after sequence `since`. Table id 0 reads
+ * the changes to every table.
+ */
+fn handle_changes(db: & Database, table_id: i32, since: i64, limit: i32)
+    -> Result<Response<'_>, i32>
+{
+    if table_id != 0 && !db.tables.iter().any(|t| t.t_id == table_id) {
+        return Err(Response::BAD_TABLE);
     }
+    let (cursor, changes) = db.change_log.since(table_id, since, limit)?;
+    Ok(Response::Changes(cursor, db.change_log.latest(), changes))
+}
 
-    let mut iter: Value;
+/* 
+ * How far this server is behind its leader. A leader is never behind, and
+ * reports itself as its own leader with an empty address.
+ */
+fn handle_replication(db: & Database) -> Response<'static> {
+    let applied = db.change_log.latest();
+    Response::Replication(match db.replica {
+        Some(ref replica) => ReplicaStatus {
+            leader: replica.leader.clone(),
+            applied: applied,
+            leader_sequence: replica.leader_sequence,
+            lag: replica.lag(applied),
+            since_contact: replica.since_contact(),
+        },
+        None => ReplicaStatus {
+            leader: String::new(),
+            applied: applied,
+            leader_sequence: applied,
+            lag: 0,
+            since_contact: 0,
+        },
+    })
+}
 
-    for i in 0..db.row_objects.len() {
-        if table_id == db.row_objects[i].table_id {
-            
-            if operator != OP_AL {
-                let mut iter_val_int: i64 = 0;
-                let mut iter_val_float: f64 = 0.0;
-                let mut iter_val_text: String = String::from(' ');
-                let mut iter_val_foreign: i64 = 0;
-                
-                match &db.row_objects[i].values[col_index] {
-                    Value::Null => (),
-                    Value::Integer(val) => iter_val_int = *val,
-                    Value::Float(val) => iter_val_float = *val,
-                    Value::Text(val) => iter_val_text = val.to_string(),
-                    Value::Foreign(val) => iter_val_foreign = *val,
-                }
-                
-                //case FOREIGN
-                if other_type == Value::FOREIGN {
-                    if iter_val_foreign == other_val_foreign && operator == OP_EQ {
-                        matched_results.push(db.row_objects[i].object_id);
-                    }
-                    else if iter_val_foreign != other_val_foreign && operator == OP_NE {
-                        matched_results.push(db.row_objects[i].object_id);
-                    }
-                }
-                
-                //case INTEGER 
-                else if other_type == Value::INTEGER {
-                    if iter_val_int == other_val_int {
-                        if operator == OP_EQ || operator == OP_LE || operator == OP_GE {
-                            matched_results.push(db.row_objects[i].object_id);
-                        }
-                    }
-                    else if iter_val_int < other_val_int {
-                        if operator == OP_LT || operator == OP_LE || operator == OP_NE {
-                            matched_results.push(db.row_objects[i].object_id);
-                        }
-                    }
-                    else if iter_val_int > other_val_int {
-                        if operator == OP_GT || operator == OP_GE || operator == OP_NE {
-                            matched_results.push(db.row_objects[i].object_id);
-                        }
-                    }
+/* 
+ * Totals a numeric column over the table, skipping nulls. Integers and
+ * decimals are summed exactly, and overflow is reported as BAD_VALUE.
+ */
+fn handle_sum(db: & Database, table_id: i32, column_id: i32)
+    -> Result<Response<'_>, i32>
+{
+    let table = match db.tables.iter().find(|t| t.t_id == table_id) {
+        Some(table) => table,
+        None => return Err(Response::BAD_TABLE),
+    };
+    let col_index = match table.t_cols.iter().position(|c| c.c_id == column_id) {
+        Some(index) => index,
+        None => return Err(Response::BAD_QUERY),
+    };
+    let column = &table.t_cols[col_index];
+    let values = db.row_objects.iter()
+        .filter(|row| row.table_id == table_id)
+        .map(|row| &row.values[col_index]);
+    
+    let total = match column.c_type {
+        Value::INTEGER => {
+            let mut total: i64 = 0;
+            for value in values {
+                if let Value::Integer(val) = value {
+                    total = total.checked_add(*val).ok_or(Response::BAD_VALUE)?;
                 }
-                
-                //case FLOAT
-                else if other_type == Value::FLOAT{
-                    if iter_val_float == other_val_float {
-                        if operator == OP_EQ || operator == OP_LE || operator == OP_GE {
-                            matched_results.push(db.row_objects[i].object_id);
-                        }
-                    }
-                    else if iter_val_float < other_val_float {
-                        if operator == OP_LT || operator == OP_LE || operator == OP_NE {
-                            matched_results.push(db.row_objects[i].object_id);
-                        }
-                    }
-                    else if iter_val_float > other_val_float {
-                        if operator == OP_GT || operator == OP_GE || operator == OP_NE {
-                            matched_results.push(db.row_objects[i].object_id);
-                        }
-                    }
+            }
+            Value::Integer(total)
+        },
+        Value::FLOAT => {
+            let mut total: f64 = 0.0;
+            for value in values {
+                if let Value::Float(val) = value {
+                    total += *val;
                 }
-                
-                //case STRING
-                else if other_type == Value::STRING{
-                    if iter_val_text == other_val_text {
-                        if operator == OP_EQ || operator == OP_LE || operator == OP_GE {
-                            matched_results.push(db.row_objects[i].object_id);
-                        }
-                    }
-                    else if iter_val_text < other_val_text {
-                        if operator == OP_LT || operator == OP_LE || operator == OP_NE {
-                            matched_results.push(db.row_objects[i].object_id);
-                        }
-                    }
-                    else if iter_val_text > other_val_text {
-                        if operator == OP_GT || operator == OP_GE || operator == OP_NE {
-                            matched_results.push(db.row_objects[i].object_id);
-                        }
-                    }
+            }
+            Value::Float(total)
+        },
+        Value::DECIMAL => {
+            /* every value is at the column's scale */
+            let mut total: i128 = 0;
+            for value in values {
+                if let Value::Decimal(val, _) = value {
+                    total += *val as i128;
                 }
             }
-        }
-    }
-
-    let response: Response = Response::Query(matched_results);
-    Ok(response)
+            if total < i64::MIN as i128 || total > i64::MAX as i128 {
+                return Err(Response::BAD_VALUE);
+            }
+            Value::Decimal(total as i64, column.c_scale)
+        },
+        _ => return Err(Response::BAD_QUERY),
+    };
+    Ok(Response::Sum(total))
 }
 
-
 //find all rows which reference to the given row
 fn find_referenced_row(db: & Database, table_id: i32, object_id: i64) 
     -> Vec<usize>
@@ -655,3 +848,343 @@ fn find_referenced_row(db: & Database, table_id: i32, object_id: i64)
     return results;
 }
 
+
+
+/* 
+ * Applies an online schema change. Rows of the table are rewritten and 
+ * their versions bumped when columns are added or dropped. Column ids 
+ * never change, and those of dropped columns are not reused, so clients
+ * and subscriptions never find an id naming a different column. Every
+ * row rewritten or dropped is recorded as a change, so cursors into the
+ * change log see it.
+ */
+pub fn migrate(db: & mut Database, table_id: i32, migration: Migration)
+    -> Result<(), i32>
+{
+    if let Migration::AddTable(name, columns) = migration {
+        return add_table(db, name, columns);
+    }
+    
+    let table_index = match db.tables.iter().position(|t| t.t_id == table_id) {
+        Some(index) => index,
+        None => return Err(Response::BAD_TABLE),
+    };
+    
+    let (kind, result) = match migration {
+        Migration::DropTable => (Change::DROP, drop_table(db, table_index)),
+        Migration::AddColumn(name, column_type, default) => (Change::UPDATE,
+            add_column(db, table_index, name, column_type, default)),
+        Migration::DropColumn(column_id) => 
+            (Change::UPDATE, drop_column(db, table_index, column_id)),
+        Migration::RenameColumn(column_id, name) => 
+            (Change::UPDATE, rename_column(db, table_index, column_id, name)),
+        Migration::AddTable(..) => 
+            (Change::UPDATE, Err(Response::UNIMPLEMENTED)),
+    };
+    
+    /* columns may have moved within the rows */
+    db.build_indexes();
+    db.subscriptions.schema_changed(&db.tables);
+    
+    /* 
+     * recorded once the filters of subscriptions follow the new schema, 
+     * which the rows now have
+     */
+    for row in result? {
+        let (old, new) = match kind {
+            Change::DROP => (Some(&row.values), None),
+            _ => (None, Some(&row.values)),
+        };
+        db.changed(kind, row.table_id, row.object_id, row.version, old, new);
+    }
+    Ok(())
+}
+
+/* Subscribes a connection to the changes of a table, filtered like a scan */
+pub fn subscribe(db: & mut Database, table_id: i32, conn_id: u64, 
+    column_id: i32, operator: i32, operand: Value, queue: & Queue)
+    -> Response<'static>
+{
+    let table = match db.tables.iter().find(|t| t.t_id == table_id) {
+        Some(table) => table,
+        None => return Response::Error(Response::BAD_TABLE),
+    };
+    match db.subscriptions.subscribe(table, conn_id, column_id, operator, 
+                                     operand, queue) {
+        Ok(id) => Response::Subscribed(id),
+        Err(code) => Response::Error(code),
+    }
+}
+
+pub fn unsubscribe(db: & mut Database, conn_id: u64, id: i64) 
+    -> Response<'static>
+{
+    match db.subscriptions.unsubscribe(conn_id, id) {
+        true => Response::Done,
+        false => Response::Error(Response::NOT_FOUND),
+    }
+}
+
+/* Drops the subscriptions of a connection that has closed */
+pub fn disconnected(db: & mut Database, conn_id: u64) {
+    db.subscriptions.disconnected(conn_id);
+}
+
+/* 
+ * On followers, which refuse to change rows themselves, the answer that
+ * sends clients to the leader
+ */
+pub fn not_leader(db: & Database) -> Option<Response<'static>> {
+    db.replica.as_ref()
+        .map(|replica| Response::NotLeader(replica.leader.clone()))
+}
+
+/* 
+ * Replaces every row with a snapshot of the leader's, which includes the
+ * changes up to `sequence`. Subscriptions are cancelled, as the changes
+ * that took their rows to the snapshot are unknown.
+ */
+pub fn restore(db: & mut Database, sequence: i64, rows: Vec<Row>) 
+    -> Result<(), i32>
+{
+    for row in &rows {
+        replicated_table(db, row.table_id, &row.values)?;
+    }
+    db.row_objects = rows;
+    db.build_indexes();
+    db.change_log.restart(sequence);
+    db.subscriptions.cancel_all();
+    Ok(())
+}
+
+/* 
+ * Applies the next change from the leader's change log. The leader checked
+ * it, and sends the rows its cascades drop as changes of their own, so the
+ * row is changed as given. The change keeps the leader's sequence number,
+ * and one that does not follow the last is TRUNCATED.
+ */
+pub fn apply(db: & mut Database, change: Change) -> Result<(), i32> {
+    if change.sequence != db.change_log.latest() + 1 {
+        return Err(Response::TRUNCATED);
+    }
+    let table_index = replicated_table(db, change.table_id, &change.values)?;
+    let position = db.row_objects.iter().position(|row| 
+        row.table_id == change.table_id && row.object_id == change.object_id);
+    
+    match (change.kind, position) {
+        (Change::INSERT, None) => {
+            db.index_row(table_index, change.object_id, &change.values, true);
+            db.changed(Change::INSERT, change.table_id, change.object_id, 
+                       change.version, None, Some(&change.values));
+            db.row_objects.push(Row::new(change.table_id, change.object_id, 
+                                         change.version, change.values));
+        },
+        (Change::UPDATE, Some(index)) => {
+            let old_values = mem::replace(&mut db.row_objects[index].values, 
+                                          vec![]);
+            db.index_row(table_index, change.object_id, &old_values, false);
+            db.index_row(table_index, change.object_id, &change.values, true);
+            db.changed(Change::UPDATE, change.table_id, change.object_id, 
+                       change.version, Some(&old_values), Some(&change.values));
+            db.row_objects[index].version = change.version;
+            db.row_objects[index].values = change.values;
+        },
+        (Change::DROP, Some(index)) => {
+            let row = db.row_objects.remove(index);
+            db.index_row(table_index, row.object_id, &row.values, false);
+            db.changed(Change::DROP, row.table_id, row.object_id, row.version, 
+                       Some(&row.values), None);
+        },
+        _ => return Err(Response::NOT_FOUND),
+    }
+    Ok(())
+}
+
+/* 
+ * Finds the table of a row from the leader. Followers load their own
+ * schema, so a row that does not fit it is BAD_SCHEMA.
+ */
+fn replicated_table(db: & Database, table_id: i32, values: & Vec<Value>) 
+    -> Result<usize, i32>
+{
+    match db.tables.iter().position(|t| t.t_id == table_id) {
+        Some(index) if db.tables[index].t_cols.len() == values.len() => 
+            Ok(index),
+        _ => Err(Response::BAD_SCHEMA),
+    }
+}
+
+/* Checks that a new column name is valid and unused in the table */
+fn check_column_name(table: & Table, name: & String) -> Result<(), i32> {
+    if schema::validate_name(name).is_err() || name.len() == 0 ||
+        table.t_cols.iter().any(|c| c.c_name == *name) {
+        return Err(Response::BAD_SCHEMA);
+    }
+    Ok(())
+}
+
+fn add_table(db: & mut Database, name: String, columns: Vec<(String, String)>)
+    -> Result<(), i32>
+{
+    if schema::validate_name(&name).is_err() || name.len() == 0 ||
+        columns.len() == 0 || db.tables.iter().any(|t| t.t_name == name) {
+        return Err(Response::BAD_SCHEMA);
+    }
+    
+    /* after every id a table has used, including dropped ones */
+    let table_id = db.tables.iter().map(|t| t.t_id)
+        .chain(db.dropped_tables.iter().cloned()).max().unwrap_or(0) + 1;
+    let mut table = Table::new(name, table_id, vec![]);
+    
+    /* a column may refer to the table, and its checks to earlier columns */
+    for (column_name, declaration) in columns {
+        let (column, checks) = declared_column(&db.tables, &table, 
+                                               column_name, &declaration)?;
+        table.t_cols.push(column);
+        table.t_checks.extend(checks);
+    }
+    
+    db.tables.push(table);
+    Ok(())
+}
+
+/* 
+ * Parses a column a migration adds to `table`, declared as in a schema
+ * file, along with the checks that come with it
+ */
+fn declared_column(tables: & Vec<Table>, table: & Table, name: String, 
+    declaration: & String) -> Result<(Column, Vec<Check>), i32>
+{
+    check_column_name(table, &name)?;
+    let column_id = table.next_column_id();
+    match schema::parse_added_column(table, tables, &name, declaration, 
+                                     column_id) {
+        Ok(parsed) => Ok(parsed),
+        Err(e) => {
+            warn!("invalid column declaration"; table = &table.t_name, 
+                  column = &name, error = e);
+            Err(Response::BAD_SCHEMA)
+        },
+    }
+}
+
+/* The migrations below return the rows they changed, as they are now */
+
+fn drop_table(db: & mut Database, table_index: usize) 
+    -> Result<Vec<Row>, i32> 
+{
+    let table_id = db.tables[table_index].t_id;
+    
+    //Cannot drop a table that other tables still refer to
+    for table in &db.tables {
+        if table.t_id != table_id && 
+            table.t_cols.iter().any(|c| c.c_type == Value::FOREIGN 
+                                    && c.c_ref == table_id) {
+            return Err(Response::BAD_FOREIGN);
+        }
+    }
+    
+    let (dropped, kept) = mem::replace(&mut db.row_objects, vec![])
+        .into_iter().partition(|row| row.table_id == table_id);
+    db.row_objects = kept;
+    db.tables.remove(table_index);
+    db.dropped_tables.push(table_id);
+    Ok(dropped)
+}
+
+/* 
+ * Adds a column to every row of the table, set to `default`, or to the 
+ * declared default if that is null. The rows must then pass the new 
+ * constraints, or the migration is refused.
+ */
+fn add_column(db: & mut Database, table_index: usize, name: String,
+    declaration: String, default: Value) -> Result<Vec<Row>, i32>
+{
+    let (column, checks) = declared_column(&db.tables, 
+                                           &db.tables[table_index], 
+                                           name, &declaration)?;
+    let mut value = match default {
+        Value::Null => column.c_default.clone(),
+        value => value,
+    };
+    fit_decimal(&column, &mut value);
+    check_value(db, &column, &value)?;
+    
+    let table_id = db.tables[table_index].t_id;
+    let rows: Vec<&Row> = db.row_objects.iter()
+        .filter(|r| r.table_id == table_id).collect();
+    if column.c_unique && value != Value::Null && rows.len() > 1 {
+        return Err(Response::NOT_UNIQUE);
+    }
+    let mut columns = db.tables[table_index].t_cols.clone();
+    columns.push(column.clone());
+    for row in rows {
+        let mut values = row.values.clone();
+        values.push(value.clone());
+        if checks.iter().any(|check| fails(check, &columns, &values)) {
+            return Err(Response::BAD_SCHEMA);
+        }
+    }
+    
+    db.tables[table_index].t_cols.push(column);
+    db.tables[table_index].t_checks.extend(checks);
+    let mut changed = vec![];
+    for row in db.row_objects.iter_mut().filter(|r| r.table_id == table_id) {
+        row.values.push(value.clone());
+        row.version += 1;
+        changed.push(Row::new(table_id, row.object_id, row.version, 
+                              row.values.clone()));
+    }
+    Ok(changed)
+}
+
+fn drop_column(db: & mut Database, table_index: usize, column_id: i32)
+    -> Result<Vec<Row>, i32>
+{
+    let table = &mut db.tables[table_index];
+    let col_index = match table.t_cols.iter().position(|c| c.c_id == column_id) {
+        Some(index) => index,
+        None => return Err(Response::BAD_SCHEMA),
+    };
+    
+    /* a table keeps at least one column */
+    if table.t_cols.len() == 1 {
+        return Err(Response::BAD_SCHEMA);
+    }
+    
+    /* a column used by a check cannot be dropped */
+    if table.t_checks.iter().any(|check| check.expr.uses(col_index)) {
+        return Err(Response::BAD_SCHEMA);
+    }
+    
+    /* the columns after it move up in the rows, but keep their ids */
+    table.t_cols.remove(col_index);
+    table.t_dropped.push(column_id);
+    for check in table.t_checks.iter_mut() {
+        check.expr.column_removed(col_index);
+    }
+    
+    let table_id = table.t_id;
+    let mut changed = vec![];
+    for row in db.row_objects.iter_mut().filter(|r| r.table_id == table_id) {
+        row.values.remove(col_index);
+        row.version += 1;
+        changed.push(Row::new(table_id, row.object_id, row.version, 
+                              row.values.clone()));
+    }
+    Ok(changed)
+}
+
+fn rename_column(db: & mut Database, table_index: usize, column_id: i32,
+    name: String) -> Result<Vec<Row>, i32>
+{
+    let table = &mut db.tables[table_index];
+    let col_index = match table.t_cols.iter().position(|c| c.c_id == column_id) {
+        Some(index) => index,
+        None => return Err(Response::BAD_SCHEMA),
+    };
+    
+    check_column_name(table, &name)?;
+    table.t_cols[col_index].c_name = name;
+    Ok(vec![])
+}
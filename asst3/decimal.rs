@@ -0,0 +1,86 @@
+/*
+ * decimal.rs
+ *
+ * Exact fixed-point arithmetic for decimal(p,s) columns. A decimal is an
+ * unscaled integer and a scale, so 12.50 is (1250, 2).
+ *
+ * University of Toronto
+ * 2019
+ */
+
+use std::cmp::Ordering;
+
+/* largest precision whose values always fit in an i64 */
+pub const MAX_PRECISION: u32 = 18;
+
+fn power(scale: u32) -> i128 {
+    10i128.pow(scale)
+}
+
+/* parses `-12.50` into (-1250, 2) */
+pub fn parse(text: & str) -> Option<(i64, u32)> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let (whole, fraction) = match digits.find('.') {
+        Some(pos) => (&digits[..pos], &digits[pos + 1..]),
+        None => (digits, ""),
+    };
+    if whole.len() == 0 || !whole.chars().all(|c| c.is_ascii_digit())
+        || !fraction.chars().all(|c| c.is_ascii_digit())
+        || fraction.len() as u32 > MAX_PRECISION {
+        return None;
+    }
+
+    let unscaled: i64 = format!("{}{}", whole, fraction).parse().ok()?;
+    Some((if negative { -unscaled } else { unscaled }, fraction.len() as u32))
+}
+
+pub fn format(unscaled: i64, scale: u32) -> String {
+    let digits = format!("{:0width$}", unscaled.unsigned_abs(),
+                         width = scale as usize + 1);
+    let sign = if unscaled < 0 { "-" } else { "" };
+    if scale == 0 {
+        return format!("{}{}", sign, digits);
+    }
+    let point = digits.len() - scale as usize;
+    format!("{}{}.{}", sign, &digits[..point], &digits[point..])
+}
+
+/*
+ * converts to another scale, failing if digits would be lost or the
+ * result does not fit
+ */
+pub fn rescale(unscaled: i64, from: u32, to: u32) -> Option<i64> {
+    if to >= from {
+        return unscaled.checked_mul(power(to - from) as i64);
+    }
+    let divisor = power(from - to) as i64;
+    if unscaled % divisor != 0 {
+        return None;
+    }
+    Some(unscaled / divisor)
+}
+
+/* true if the value has at most `precision` digits */
+pub fn fits(unscaled: i64, precision: u32) -> bool {
+    (unscaled.unsigned_abs() as i128) < power(precision)
+}
+
+/* compares by value, whatever the scales */
+pub fn compare(a: i64, a_scale: u32, b: i64, b_scale: u32) -> Ordering {
+    let (a, b, _) = align(a, a_scale, b, b_scale);
+    a.cmp(&b)
+}
+
+/* brings two decimals to the larger of their scales, as i128 */
+pub fn align(a: i64, a_scale: u32, b: i64, b_scale: u32) -> (i128, i128, u32) {
+    let scale = a_scale.max(b_scale);
+    (a as i128 * power(scale - a_scale), b as i128 * power(scale - b_scale),
+     scale)
+}
+
+pub fn to_f64(unscaled: i64, scale: u32) -> f64 {
+    unscaled as f64 / power(scale) as f64
+}
@@ -0,0 +1,366 @@
+/*
+ * expr.rs
+ *
+ * Expressions over the columns of a row, used by CHECK constraints
+ *
+ * University of Toronto
+ * 2019
+ */
+
+use std::cmp::Ordering;
+use decimal;
+use packet::Value;
+use schema::{type_name, Column};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    And,
+    Or,
+    Not,
+    Neg,
+}
+
+impl Op {
+    fn as_str(& self) -> &'static str {
+        match self {
+            Op::Add => "+",
+            Op::Sub => "-",
+            Op::Mul => "*",
+            Op::Div => "/",
+            Op::Rem => "%",
+            Op::Eq => "==",
+            Op::Ne => "!=",
+            Op::Lt => "<",
+            Op::Gt => ">",
+            Op::Le => "<=",
+            Op::Ge => ">=",
+            Op::And => "and",
+            Op::Or => "or",
+            Op::Not => "not",
+            Op::Neg => "-",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum Expr {
+    Literal(Value),
+    Column(usize),      /* index of the column in the row */
+    Unary(Op, Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+/* A named CHECK constraint of a table */
+pub struct Check {
+    pub name: String,
+    pub expr: Expr,
+}
+
+/* 
+ * numeric types promote to float, and integers to decimals and 
+ * timestamps 
+ */
+fn promote(a: i32, b: i32) -> Option<i32> {
+    match (a, b) {
+        _ if a == b => Some(a),
+        (Value::NULL, t) | (t, Value::NULL) => Some(t),
+        (Value::INTEGER, Value::FLOAT) | (Value::FLOAT, Value::INTEGER) =>
+            Some(Value::FLOAT),
+        (Value::DECIMAL, Value::FLOAT) | (Value::FLOAT, Value::DECIMAL) =>
+            Some(Value::FLOAT),
+        (Value::INTEGER, Value::DECIMAL) | (Value::DECIMAL, Value::INTEGER) =>
+            Some(Value::DECIMAL),
+        (Value::INTEGER, Value::TIMESTAMP) | (Value::TIMESTAMP, Value::INTEGER) =>
+            Some(Value::TIMESTAMP),
+        _ => None,
+    }
+}
+
+fn convert(value: Value, to: i32) -> Value {
+    match (value, to) {
+        (Value::Integer(v), Value::FLOAT) => Value::Float(v as f64),
+        (Value::Integer(v), Value::TIMESTAMP) => Value::Timestamp(v),
+        (Value::Integer(v), Value::DECIMAL) => Value::Decimal(v, 0),
+        (Value::Decimal(v, scale), Value::FLOAT) => 
+            Value::Float(decimal::to_f64(v, scale)),
+        (value, _) => value,
+    }
+}
+
+impl Expr {
+    /*
+     * Type checks the expression against the columns of its table and
+     * returns its type code
+     */
+    pub fn type_of(& self, columns: & Vec<Column>) -> Result<i32, String> {
+        match self {
+            Expr::Literal(value) => Ok(value.type_code()),
+            Expr::Column(index) => Ok(columns[*index].value_type()),
+            Expr::Unary(op, operand) => {
+                let t = operand.type_of(columns)?;
+                match op {
+                    Op::Not if t == Value::BOOLEAN || t == Value::NULL =>
+                        Ok(Value::BOOLEAN),
+                    Op::Neg if t == Value::INTEGER || t == Value::FLOAT
+                        || t == Value::DECIMAL || t == Value::NULL => Ok(t),
+                    _ => Err(format!("cannot apply '{}' to {}", op.as_str(),
+                                     type_name(t))),
+                }
+            },
+            Expr::Binary(op, left, right) => {
+                let (l, r) = (left.type_of(columns)?, right.type_of(columns)?);
+                let mismatch = || format!("cannot apply '{}' to {} and {}",
+                                          op.as_str(), type_name(l),
+                                          type_name(r));
+                match op {
+                    Op::And | Op::Or => {
+                        match promote(l, r) {
+                            Some(Value::BOOLEAN) | Some(Value::NULL) =>
+                                Ok(Value::BOOLEAN),
+                            _ => Err(mismatch()),
+                        }
+                    },
+                    Op::Eq | Op::Ne | Op::Lt | Op::Gt | Op::Le | Op::Ge => {
+                        check_variant(left, right, columns)?;
+                        check_variant(right, left, columns)?;
+                        match promote(l, r) {
+                            Some(_) => Ok(Value::BOOLEAN),
+                            None => Err(mismatch()),
+                        }
+                    },
+                    _ => {
+                        match promote(l, r) {
+                            Some(t) if t == Value::INTEGER || t == Value::FLOAT
+                                || t == Value::NULL => Ok(t),
+                            /* decimals only support exact operations */
+                            Some(Value::DECIMAL) if *op == Op::Add 
+                                || *op == Op::Sub || *op == Op::Mul => 
+                                Ok(Value::DECIMAL),
+                            _ => Err(mismatch()),
+                        }
+                    },
+                }
+            },
+        }
+    }
+
+    /*
+     * Evaluates the expression over a row of a table with `columns`. Null
+     * operands give a null result, except where `and`/`or` are decided by
+     * the other operand. Division by zero and overflow are errors.
+     */
+    pub fn eval(& self, row: & Vec<Value>, columns: & Vec<Column>) 
+        -> Result<Value, ()> 
+    {
+        match self {
+            Expr::Literal(value) => Ok(value.clone()),
+            Expr::Column(index) => Ok(row[*index].clone()),
+            Expr::Unary(op, operand) => {
+                match (op, operand.eval(row, columns)?) {
+                    (_, Value::Null) => Ok(Value::Null),
+                    (Op::Not, Value::Boolean(v)) => Ok(Value::Boolean(!v)),
+                    (Op::Neg, Value::Integer(v)) =>
+                        v.checked_neg().map(Value::Integer).ok_or(()),
+                    (Op::Neg, Value::Float(v)) => Ok(Value::Float(-v)),
+                    (Op::Neg, Value::Decimal(v, scale)) => 
+                        v.checked_neg().map(|v| Value::Decimal(v, scale))
+                            .ok_or(()),
+                    _ => Err(()),
+                }
+            },
+            Expr::Binary(Op::And, left, right) => {
+                match (left.eval(row, columns)?, right.eval(row, columns)?) {
+                    (Value::Boolean(false), _) | (_, Value::Boolean(false)) =>
+                        Ok(Value::Boolean(false)),
+                    (Value::Boolean(true), Value::Boolean(true)) =>
+                        Ok(Value::Boolean(true)),
+                    _ => Ok(Value::Null),
+                }
+            },
+            Expr::Binary(Op::Or, left, right) => {
+                match (left.eval(row, columns)?, right.eval(row, columns)?) {
+                    (Value::Boolean(true), _) | (_, Value::Boolean(true)) =>
+                        Ok(Value::Boolean(true)),
+                    (Value::Boolean(false), Value::Boolean(false)) =>
+                        Ok(Value::Boolean(false)),
+                    _ => Ok(Value::Null),
+                }
+            },
+            Expr::Binary(op, left, right) => {
+                let (l, r) = (left.eval(row, columns)?, right.eval(row, columns)?);
+                if l == Value::Null || r == Value::Null {
+                    return Ok(Value::Null);
+                }
+                let (l, r) = ordinals(left, right, l, r, columns);
+                let t = promote(l.type_code(), r.type_code()).ok_or(())?;
+                let (l, r) = (convert(l, t), convert(r, t));
+                match op {
+                    Op::Eq | Op::Ne | Op::Lt | Op::Gt | Op::Le | Op::Ge =>
+                        Ok(Value::Boolean(compare(*op, &l, &r))),
+                    _ => arithmetic(*op, l, r),
+                }
+            },
+        }
+    }
+
+    /* true if the expression refers to the column at `index` */
+    pub fn uses(& self, index: usize) -> bool {
+        match self {
+            Expr::Literal(_) => false,
+            Expr::Column(i) => *i == index,
+            Expr::Unary(_, operand) => operand.uses(index),
+            Expr::Binary(_, left, right) =>
+                left.uses(index) || right.uses(index),
+        }
+    }
+
+    /* renumbers column references after the column at `index` is dropped */
+    pub fn column_removed(&mut self, index: usize) {
+        match self {
+            Expr::Literal(_) => (),
+            Expr::Column(i) => if *i > index { *i -= 1; },
+            Expr::Unary(_, operand) => operand.column_removed(index),
+            Expr::Binary(_, left, right) => {
+                left.column_removed(index);
+                right.column_removed(index);
+            },
+        }
+    }
+
+    /* source text of the expression, with column names */
+    pub fn format(& self, columns: & Vec<Column>) -> String {
+        match self {
+            Expr::Literal(Value::Text(s)) => format!("{:?}", s),
+            Expr::Literal(Value::Float(v)) => format!("{:?}", v),
+            Expr::Literal(Value::Null) => String::from("null"),
+            Expr::Literal(value) => value.to_string(),
+            Expr::Column(index) => columns[*index].c_name.clone(),
+            Expr::Unary(Op::Not, operand) =>
+                format!("not {}", operand.format_operand(columns)),
+            Expr::Unary(op, operand) =>
+                format!("{}{}", op.as_str(), operand.format_operand(columns)),
+            Expr::Binary(op, left, right) =>
+                format!("{} {} {}", left.format_operand(columns), op.as_str(),
+                        right.format_operand(columns)),
+        }
+    }
+
+    fn format_operand(& self, columns: & Vec<Column>) -> String {
+        match self {
+            Expr::Binary(..) => format!("({})", self.format(columns)),
+            _ => self.format(columns),
+        }
+    }
+}
+
+/* the column an expression reads, if it is an enum column */
+fn enum_column<'a>(expr: & Expr, columns: &'a Vec<Column>) -> Option<&'a Column> {
+    match expr {
+        Expr::Column(index) if columns[*index].c_type == Value::ENUM => 
+            Some(&columns[*index]),
+        _ => None,
+    }
+}
+
+/* a string compared with an enum column must be one of its values */
+fn check_variant(column: & Expr, other: & Expr, columns: & Vec<Column>) 
+    -> Result<(), String> 
+{
+    match (enum_column(column, columns), other) {
+        (Some(column), Expr::Literal(Value::Text(text))) 
+            if column.variant_index(text).is_none() => 
+            Err(format!("'{}' is not a value of {}", text, column.c_name)),
+        _ => Ok(()),
+    }
+}
+
+/* 
+ * Enum values compare by their position in the declaration, as in scans.
+ * When one side is an enum column, strings on both sides are replaced by
+ * their positions in its enum.
+ */
+fn ordinals(left: & Expr, right: & Expr, l: Value, r: Value, 
+    columns: & Vec<Column>) -> (Value, Value) 
+{
+    let (l_column, r_column) = (enum_column(left, columns), 
+                                enum_column(right, columns));
+    match (l_column.or(r_column), r_column.or(l_column)) {
+        (Some(l_column), Some(r_column)) => 
+            (ordinal(l_column, l), ordinal(r_column, r)),
+        _ => (l, r),
+    }
+}
+
+fn ordinal(column: & Column, value: Value) -> Value {
+    match value {
+        Value::Text(ref text) => match column.variant_index(text) {
+            Some(index) => Value::Integer(index as i64),
+            None => value,
+        },
+        value => value,
+    }
+}
+
+fn compare(op: Op, l: & Value, r: & Value) -> bool {
+    let ordering = l.compare(r);
+    match op {
+        Op::Eq => ordering == Some(Ordering::Equal),
+        Op::Ne => ordering != Some(Ordering::Equal),
+        Op::Lt => ordering == Some(Ordering::Less),
+        Op::Gt => ordering == Some(Ordering::Greater),
+        Op::Le => ordering == Some(Ordering::Less) 
+            || ordering == Some(Ordering::Equal),
+        _ => ordering == Some(Ordering::Greater) 
+            || ordering == Some(Ordering::Equal),
+    }
+}
+
+fn arithmetic(op: Op, l: Value, r: Value) -> Result<Value, ()> {
+    match (l, r) {
+        (Value::Integer(a), Value::Integer(b)) => {
+            let result = match op {
+                Op::Add => a.checked_add(b),
+                Op::Sub => a.checked_sub(b),
+                Op::Mul => a.checked_mul(b),
+                Op::Div => a.checked_div(b),
+                _ => a.checked_rem(b),
+            };
+            result.map(Value::Integer).ok_or(())
+        },
+        (Value::Float(a), Value::Float(b)) => {
+            let result = match op {
+                Op::Add => a + b,
+                Op::Sub => a - b,
+                Op::Mul => a * b,
+                Op::Div => a / b,
+                _ => a % b,
+            };
+            if result.is_finite() { Ok(Value::Float(result)) } else { Err(()) }
+        },
+        (Value::Decimal(a, a_scale), Value::Decimal(b, b_scale)) => {
+            let (a128, b128, scale) = decimal::align(a, a_scale, b, b_scale);
+            let (result, scale) = match op {
+                Op::Add => (a128 + b128, scale),
+                Op::Sub => (a128 - b128, scale),
+                _ => (a as i128 * b as i128, a_scale + b_scale),
+            };
+            if result < i64::MIN as i128 || result > i64::MAX as i128 
+                || scale > decimal::MAX_PRECISION {
+                return Err(());
+            }
+            Ok(Value::Decimal(result as i64, scale))
+        },
+        _ => Err(()),
+    }
+}
@@ -0,0 +1,302 @@
+/*
+ * filter.rs
+ *
+ * Scan predicates, checked once against the schema and then tested
+ * against rows by scans and change subscriptions
+ *
+ * University of Toronto
+ * 2019
+ */
+
+use database::{OP_AL, OP_EQ, OP_NE, OP_LT, OP_GT, OP_LE, OP_GE, OP_CONTAINS,
+               OP_STARTS_WITH, OP_ENDS_WITH, OP_LIKE, OP_EQ_NOCASE, OP_REGEX,
+               OP_IS_NULL, OP_IS_NOT_NULL, OP_IN, OP_BETWEEN, ID_COLUMN};
+use json;
+use packet::{Response, Value};
+use pattern;
+use schema::{Column, Table};
+use std::cmp::Ordering;
+
+type Test = Box<dyn Fn(& Value) -> bool + Send>;
+
+/* A scan predicate on one column of a table, or on the object id */
+pub struct Filter {
+    column: Option<usize>,      /* index of the column, None for the id */
+    test: Test,
+}
+
+impl Filter {
+    /* whether a row with this id and these values matches */
+    pub fn matches(& self, object_id: i64, values: & Vec<Value>) -> bool {
+        match self.column {
+            Some(index) => (self.test)(&values[index]),
+            None => (self.test)(&Value::Integer(object_id)),
+        }
+    }
+
+    /* whether a field of the filtered column matches */
+    pub fn test(& self, value: & Value) -> bool {
+        (self.test)(value)
+    }
+}
+
+/*
+ * Checks a scan of `table` against its schema and builds the predicate.
+ * Invalid columns, operators and operands are BAD_QUERY.
+ */
+pub fn compile(table: & Table, column_id: i32, operator: i32, other: Value)
+    -> Result<Filter, i32>
+{
+    //case OP_AL: column_id must be zero, regardless of other
+    if operator == OP_AL {
+        if column_id != ID_COLUMN {
+            return Err(Response::BAD_QUERY);
+        }
+        return Ok(Filter { column: None, test: Box::new(|_| true) });
+    }
+
+    if operator < OP_EQ || operator > OP_BETWEEN {
+        return Err(Response::BAD_QUERY);
+    }
+
+    //the reserved column holds the object id, compared as an integer
+    if column_id == ID_COLUMN {
+        if operator >= OP_CONTAINS && operator <= OP_REGEX {
+            return Err(Response::BAD_QUERY);
+        }
+        let column = Column::new(String::new(), ID_COLUMN, Value::INTEGER, 0);
+        return Ok(Filter {
+            column: None,
+            test: column_test(column, operator, other)?,
+        });
+    }
+
+    //Invalid column_id
+    let col_index = match table.t_cols.iter().position(|c| c.c_id == column_id) {
+        Some(index) => index,
+        None => return Err(Response::BAD_QUERY),
+    };
+    let column = table.t_cols[col_index].clone();
+    Ok(Filter {
+        column: Some(col_index),
+        test: column_test(column, operator, other)?,
+    })
+}
+
+fn column_test(column: Column, operator: i32, other: Value)
+    -> Result<Test, i32>
+{
+    //case OP_IS_NULL and OP_IS_NOT_NULL: regardless of other
+    if operator == OP_IS_NULL || operator == OP_IS_NOT_NULL {
+        let is_null = operator == OP_IS_NULL;
+        return Ok(Box::new(move |value| (*value == Value::Null) == is_null));
+    }
+
+    //json columns are queried by a [path, value] operand
+    if column.c_type == Value::JSON {
+        return json_test(operator, other);
+    }
+
+    //case OP_IN and OP_BETWEEN: a list operand of column values
+    if operator == OP_IN || operator == OP_BETWEEN {
+        return set_test(column, operator, other);
+    }
+
+    //nothing compares with null, so a null operand matches no rows
+    if other == Value::Null {
+        return Ok(Box::new(|_| false));
+    }
+
+    //string columns also take the pattern operators
+    if column.c_type == Value::STRING && operator >= OP_CONTAINS {
+        return text_test(operator, other);
+    }
+    if operator > OP_CONTAINS {
+        return Err(Response::BAD_QUERY);
+    }
+
+    //case OP_CONTAINS: lists holding an element equal to other
+    if operator == OP_CONTAINS {
+        if column.c_type != Value::LIST || other.type_code() != column.c_element {
+            return Err(Response::BAD_QUERY);
+        }
+        return Ok(Box::new(move |value| match value {
+            Value::List(values) => values.iter()
+                .any(|v| v.compare(&other) == Some(Ordering::Equal)),
+            _ => false,
+        }));
+    }
+
+    //only EQ and NE are supported for foreign, boolean, list and id
+    if column.c_type == Value::FOREIGN || column.c_type == Value::BOOLEAN
+        || column.c_type == Value::LIST || column.c_name == "id" {
+        if operator != OP_EQ && operator != OP_NE {
+            return Err(Response::BAD_QUERY);
+        }
+    }
+
+    //Invalid value type
+    if other.type_code() != column.value_type() {
+        return Err(Response::BAD_QUERY);
+    }
+
+    //null fields compare with nothing, so they never match
+    let other = ordinal(&column, other)?;
+    Ok(Box::new(move |value| match compare(&column, value, &other) {
+        Some(ordering) => operator_matches(operator, ordering),
+        None => false,
+    }))
+}
+
+/* enum values compare by their position in the declaration */
+fn ordinal(column: & Column, value: Value) -> Result<Value, i32> {
+    match value {
+        Value::Text(ref text) if column.c_type == Value::ENUM => {
+            match column.variant_index(text) {
+                Some(index) => Ok(Value::Integer(index as i64)),
+                None => Err(Response::BAD_QUERY),
+            }
+        },
+        value => Ok(value),
+    }
+}
+
+/* compares a field with an operand converted by ordinal() */
+fn compare(column: & Column, value: & Value, other: & Value)
+    -> Option<Ordering>
+{
+    match value {
+        Value::Null => None,
+        Value::Text(text) if column.c_type == Value::ENUM => {
+            let position = column.variant_index(text).unwrap_or(0) as i64;
+            Value::Integer(position).compare(other)
+        },
+        value => value.compare(other),
+    }
+}
+
+/* whether a field that compares to the operand as `ordering` matches */
+pub fn operator_matches(operator: i32, ordering: Ordering) -> bool {
+    match ordering {
+        Ordering::Equal =>
+            operator == OP_EQ || operator == OP_LE || operator == OP_GE,
+        Ordering::Less =>
+            operator == OP_LT || operator == OP_LE || operator == OP_NE,
+        Ordering::Greater =>
+            operator == OP_GT || operator == OP_GE || operator == OP_NE,
+    }
+}
+
+/*
+ * Tests a json column. The operand is a list of the path into each
+ * document and the value to compare with what is found there. Documents
+ * without the path, or holding a different type at it, do not match.
+ * OP_CONTAINS matches when the path leads to an array holding the value.
+ */
+fn json_test(operator: i32, other: Value) -> Result<Test, i32> {
+    if operator > OP_CONTAINS {
+        return Err(Response::BAD_QUERY);
+    }
+    let (path, other) = match other {
+        Value::List(mut operand) if operand.len() == 2 => {
+            let other = operand.pop().unwrap();
+            match operand.pop().unwrap() {
+                Value::Text(path) => (path, other),
+                _ => return Err(Response::BAD_QUERY),
+            }
+        },
+        _ => return Err(Response::BAD_QUERY),
+    };
+
+    Ok(Box::new(move |value| {
+        let document = match value {
+            Value::Json(text) => json::parse(text),
+            _ => return false,
+        };
+        let found = match document {
+            Ok(ref document) => document.get(&path),
+            Err(_) => None,
+        };
+        match (found, operator) {
+            (Some(json::Json::Array(items)), OP_CONTAINS) => items.iter()
+                .any(|item| item.compare(&other) == Some(Ordering::Equal)),
+            (Some(_), OP_CONTAINS) | (None, _) => false,
+            (Some(found), _) => match found.compare(&other) {
+                Some(ordering) => operator_matches(operator, ordering),
+                None => false,
+            },
+        }
+    }))
+}
+
+/* Tests a string column with a pattern operator. Null fields never match. */
+fn text_test(operator: i32, other: Value) -> Result<Test, i32> {
+    let pattern = match other {
+        Value::Text(pattern) => pattern,
+        _ => return Err(Response::BAD_QUERY),
+    };
+    if (operator == OP_LIKE || operator == OP_REGEX) &&
+        pattern.chars().count() > pattern::MAX_PATTERN {
+        return Err(Response::BAD_QUERY);
+    }
+    let like = pattern::Like::new(&pattern);
+    let regex = match operator {
+        OP_REGEX => match pattern::Regex::new(&pattern) {
+            Ok(regex) => Some(regex),
+            Err(_) => return Err(Response::BAD_QUERY),
+        },
+        _ => None,
+    };
+    let lowered = pattern.to_lowercase();
+
+    Ok(Box::new(move |value| {
+        let text = match value {
+            Value::Text(text) => text,
+            _ => return false,
+        };
+        match operator {
+            OP_CONTAINS => text.contains(pattern.as_str()),
+            OP_STARTS_WITH => text.starts_with(pattern.as_str()),
+            OP_ENDS_WITH => text.ends_with(pattern.as_str()),
+            OP_LIKE => like.is_match(text),
+            OP_EQ_NOCASE => text.to_lowercase() == lowered,
+            _ => regex.as_ref().map_or(false, |regex| regex.is_match(text)),
+        }
+    }))
+}
+
+/*
+ * Tests for fields equal to one of a list of values, or between two
+ * bounds inclusive. Null members and bounds match nothing.
+ */
+fn set_test(column: Column, operator: i32, other: Value) -> Result<Test, i32> {
+    let values = match other {
+        Value::List(values) => values,
+        _ => return Err(Response::BAD_QUERY),
+    };
+    if column.c_type == Value::LIST
+        || (operator == OP_BETWEEN && values.len() != 2) {
+        return Err(Response::BAD_QUERY);
+    }
+    if values.iter().any(|v| *v != Value::Null
+                         && v.type_code() != column.value_type()) {
+        return Err(Response::BAD_QUERY);
+    }
+    if operator == OP_BETWEEN && values.contains(&Value::Null) {
+        return Ok(Box::new(|_| false));
+    }
+
+    let mut keys = Vec::new();
+    for value in values.into_iter().filter(|v| *v != Value::Null) {
+        keys.push(ordinal(&column, value)?);
+    }
+
+    Ok(Box::new(move |value| match operator {
+        OP_IN => keys.iter().any(|key|
+            compare(&column, value, key) == Some(Ordering::Equal)),
+        _ => compare(&column, value, &keys[0])
+                .map_or(false, |ordering| operator_matches(OP_GE, ordering))
+            && compare(&column, value, &keys[1])
+                .map_or(false, |ordering| operator_matches(OP_LE, ordering)),
+    }))
+}
@@ -0,0 +1,128 @@
+/*
+ * index.rs
+ *
+ * Ordered secondary indexes over column values
+ *
+ * University of Toronto
+ * 2019
+ */
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use packet::Value;
+
+/*
+ * A column value with a total order, so it can be used as a map key.
+ * Values of different types are ordered by their type code, and floats
+ * by their IEEE total order.
+ */
+#[derive(Clone, Debug)]
+pub struct Key(pub Value);
+
+impl Key {
+    /* 
+     * The total order tells -0.0 from 0.0, which scans and checks compare
+     * as equal, so both are kept as 0.0
+     */
+    pub fn new(value: &Value) -> Key {
+        match value {
+            Value::Float(v) if *v == 0.0 => Key(Value::Float(0.0)),
+            value => Key(value.clone()),
+        }
+    }
+}
+
+impl Ord for Key {
+    fn cmp(&self, other: &Key) -> Ordering {
+        match (&self.0, &other.0) {
+            (Value::Float(a), Value::Float(b)) => a.total_cmp(b),
+            (a, b) if a.type_code() != b.type_code() =>
+                a.type_code().cmp(&b.type_code()),
+            (a, b) => a.compare(b).unwrap_or(Ordering::Equal),
+        }
+    }
+}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Key) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Key) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Key {}
+
+/* Maps the values of one column to the ids of the rows holding them */
+pub struct Index {
+    entries: BTreeMap<Key, BTreeSet<i64>>,
+}
+
+impl Index {
+    pub fn new() -> Index {
+        Index {
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /* nulls are not indexed */
+    pub fn insert(&mut self, value: &Value, object_id: i64) {
+        if *value == Value::Null {
+            return;
+        }
+        self.entries.entry(Key::new(value)).or_insert(BTreeSet::new())
+            .insert(object_id);
+    }
+
+    pub fn remove(&mut self, value: &Value, object_id: i64) {
+        let key = Key::new(value);
+        let empty = match self.entries.get_mut(&key) {
+            Some(ids) => {
+                ids.remove(&object_id);
+                ids.len() == 0
+            },
+            None => false,
+        };
+        if empty {
+            self.entries.remove(&key);
+        }
+    }
+
+    /* ids of the rows holding exactly `value` */
+    pub fn get(&self, value: &Value) -> Vec<i64> {
+        match self.entries.get(&Key::new(value)) {
+            Some(ids) => ids.iter().cloned().collect(),
+            None => vec![],
+        }
+    }
+
+    /* texts starting with `prefix` and the ids holding them, in key order */
+    pub fn prefix(&self, prefix: &str) -> Vec<(String, i64)> {
+        let start = Key::new(&Value::Text(String::from(prefix)));
+        let mut results = vec![];
+        for (key, ids) in self.entries.range(start..) {
+            match &key.0 {
+                Value::Text(text) if text.starts_with(prefix) =>
+                    results.extend(ids.iter().map(|id| (text.clone(), *id))),
+                _ => break,
+            }
+        }
+        results
+    }
+
+    /* ids of the rows holding values from `low` to `high` inclusive */
+    pub fn range(&self, low: &Value, high: &Value) -> Vec<i64> {
+        let (low, high) = (Key::new(low), Key::new(high));
+        if low > high {
+            return vec![];
+        }
+        let mut ids: Vec<i64> = self.entries.range(low..=high)
+            .flat_map(|(_, ids)| ids.iter().cloned()).collect();
+        ids.sort();
+        ids
+    }
+}
@@ -0,0 +1,282 @@
+/*
+ * json.rs
+ *
+ * A small JSON parser for json columns, and path lookups into documents
+ *
+ * University of Toronto
+ * 2019
+ */
+
+use std::cmp::Ordering;
+use packet::Value;
+
+/* deepest nesting of arrays and objects accepted */
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),    /* members in document order */
+}
+
+impl Json {
+    /*
+     * Follows a dotted path such as `profile.address.city` from this
+     * value. Segments index arrays when they are numbers. The empty path
+     * is the document itself.
+     */
+    pub fn get(& self, path: & str) -> Option<& Json> {
+        let mut node = self;
+        if path.len() == 0 {
+            return Some(node);
+        }
+        for key in path.split('.') {
+            node = match node {
+                /* with duplicate names the last one wins */
+                Json::Object(members) => members.iter().rev()
+                    .find(|(name, _)| name == key).map(|(_, v)| v)?,
+                Json::Array(items) => items.get(key.parse::<usize>().ok()?)?,
+                _ => return None,
+            };
+        }
+        Some(node)
+    }
+
+    /*
+     * Compares to a scan operand. Only like types compare: null, booleans,
+     * numbers with integers and floats, and strings with text.
+     */
+    pub fn compare(& self, value: & Value) -> Option<Ordering> {
+        match (self, value) {
+            (Json::Null, Value::Null) => Some(Ordering::Equal),
+            (Json::Bool(a), Value::Boolean(b)) => Some(a.cmp(b)),
+            (Json::Number(a), Value::Integer(b)) => a.partial_cmp(&(*b as f64)),
+            (Json::Number(a), Value::Float(b)) => a.partial_cmp(b),
+            (Json::String(a), Value::Text(b)) => Some(a.as_str().cmp(b)),
+            _ => None,
+        }
+    }
+}
+
+/* Parses a complete JSON document, or says what is wrong with it */
+pub fn parse(text: & str) -> Result<Json, String> {
+    let mut parser = Parser { chars: text.chars().collect(), pos: 0 };
+    let value = parser.value(0)?;
+    parser.skip_whitespace();
+    if parser.pos < parser.chars.len() {
+        return Err(parser.error("unexpected data after the document"));
+    }
+    Ok(value)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn error(& self, message: & str) -> String {
+        format!("{} at offset {}", message, self.pos)
+    }
+
+    fn peek(& self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(' ') | Some('\t') | Some('\n') | Some('\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, ch: char) -> Result<(), String> {
+        if self.peek() != Some(ch) {
+            return Err(self.error(&format!("expecting '{}'", ch)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, word: & str, value: Json) -> Result<Json, String> {
+        for ch in word.chars() {
+            self.expect(ch)?;
+        }
+        Ok(value)
+    }
+
+    /* parses a value inside `depth` arrays and objects */
+    fn value(&mut self, depth: usize) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('[') | Some('{') if depth == MAX_DEPTH =>
+                Err(self.error("document nested too deeply")),
+            Some('n') => self.literal("null", Json::Null),
+            Some('t') => self.literal("true", Json::Bool(true)),
+            Some('f') => self.literal("false", Json::Bool(false)),
+            Some('"') => Ok(Json::String(self.string()?)),
+            Some('[') => self.array(depth),
+            Some('{') => self.object(depth),
+            Some(ch) if ch == '-' || ch.is_ascii_digit() => self.number(),
+            Some(_) => Err(self.error("expecting a value")),
+            None => Err(self.error("unexpected end of document")),
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> Result<Json, String> {
+        self.expect('[')?;
+        let mut items = vec![];
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.pos += 1;
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value(depth + 1)?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(']') => {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                },
+                _ => return Err(self.error("expecting ',' or ']'")),
+            }
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> Result<Json, String> {
+        self.expect('{')?;
+        let mut members = vec![];
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(Json::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            let name = self.string()?;
+            self.skip_whitespace();
+            self.expect(':')?;
+            members.push((name, self.value(depth + 1)?));
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some('}') => {
+                    self.pos += 1;
+                    return Ok(Json::Object(members));
+                },
+                _ => return Err(self.error("expecting ',' or '}'")),
+            }
+        }
+    }
+
+    /* -?(0|[1-9][0-9]*)(.[0-9]+)?([eE][+-]?[0-9]+)? */
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.pos;
+        if self.peek() == Some('-') {
+            self.pos += 1;
+        }
+        match self.peek() {
+            Some('0') => self.pos += 1,
+            Some(ch) if ch.is_ascii_digit() => self.digits(),
+            _ => return Err(self.error("expecting a digit")),
+        }
+        if self.peek() == Some('.') {
+            self.pos += 1;
+            if !self.peek().map_or(false, |c| c.is_ascii_digit()) {
+                return Err(self.error("expecting a digit"));
+            }
+            self.digits();
+        }
+        if let Some('e') | Some('E') = self.peek() {
+            self.pos += 1;
+            if let Some('+') | Some('-') = self.peek() {
+                self.pos += 1;
+            }
+            if !self.peek().map_or(false, |c| c.is_ascii_digit()) {
+                return Err(self.error("expecting a digit"));
+            }
+            self.digits();
+        }
+
+        let text: String = self.chars[start..self.pos].iter().collect();
+        match text.parse::<f64>() {
+            Ok(n) if n.is_finite() => Ok(Json::Number(n)),
+            _ => Err(self.error("number out of range")),
+        }
+    }
+
+    fn digits(&mut self) {
+        while self.peek().map_or(false, |c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut out = String::new();
+        loop {
+            let ch = match self.peek() {
+                Some(ch) => ch,
+                None => return Err(self.error("unterminated string")),
+            };
+            self.pos += 1;
+            match ch {
+                '"' => return Ok(out),
+                '\\' => out.push(self.escape()?),
+                c if (c as u32) < 0x20 =>
+                    return Err(self.error("control character in string")),
+                c => out.push(c),
+            }
+        }
+    }
+
+    fn escape(&mut self) -> Result<char, String> {
+        let ch = self.peek().ok_or(self.error("unterminated string"))?;
+        self.pos += 1;
+        Ok(match ch {
+            '"' => '"',
+            '\\' => '\\',
+            '/' => '/',
+            'b' => '\u{8}',
+            'f' => '\u{c}',
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            'u' => {
+                let high = self.hex()?;
+                /* characters outside the BMP come as a surrogate pair */
+                let code = if high >= 0xD800 && high < 0xDC00 {
+                    self.expect('\\')?;
+                    self.expect('u')?;
+                    let low = self.hex()?;
+                    if low < 0xDC00 || low >= 0xE000 {
+                        return Err(self.error("invalid surrogate pair"));
+                    }
+                    0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+                }
+                else {
+                    high
+                };
+                std::char::from_u32(code)
+                    .ok_or(self.error("invalid unicode escape"))?
+            },
+            _ => return Err(self.error("invalid escape")),
+        })
+    }
+
+    fn hex(&mut self) -> Result<u32, String> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = self.peek().and_then(|c| c.to_digit(16))
+                .ok_or(self.error("expecting 4 hex digits"))?;
+            code = code * 16 + digit;
+            self.pos += 1;
+        }
+        Ok(code)
+    }
+}
@@ -0,0 +1,352 @@
+/*
+ * logger.rs
+ *
+ * Leveled, structured logging for the EasyDB server
+ *
+ * University of Toronto
+ * 2019
+ */
+
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use config::Config;
+
+/*
+ * Logs a record with optional `key = value` fields, e.g.
+ * log!(Level::Info, "connected"; conn = 1, peer = addr.to_string());
+ */
+macro_rules! log {
+    ($level:expr, $msg:expr) => {
+        log!($level, $msg;)
+    };
+    ($level:expr, $msg:expr; $($key:ident = $value:expr),*) => {
+        if $crate::logger::enabled($level) {
+            $crate::logger::write($level, &$msg,
+                &[$((stringify!($key), $crate::logger::Field::from($value))),*]);
+        }
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => { log!($crate::logger::Level::Error, $($arg)*) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => { log!($crate::logger::Level::Warn, $($arg)*) };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { log!($crate::logger::Level::Info, $($arg)*) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => { log!($crate::logger::Level::Debug, $($arg)*) };
+}
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn from_str(name: &str) -> Level {
+        match name {
+            "error" => Level::Error,
+            "warn" => Level::Warn,
+            "debug" => Level::Debug,
+            "trace" => Level::Trace,
+            _ => Level::Info,
+        }
+    }
+
+    fn as_str(& self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+}
+
+/* value of a structured field, kept typed so JSON output can use numbers */
+pub enum Field {
+    Int(i64),
+    Float(f64),
+    Text(String),
+}
+
+impl From<i32> for Field {
+    fn from(v: i32) -> Field { Field::Int(v as i64) }
+}
+
+impl From<i64> for Field {
+    fn from(v: i64) -> Field { Field::Int(v) }
+}
+
+impl From<u64> for Field {
+    fn from(v: u64) -> Field { Field::Int(v as i64) }
+}
+
+impl From<usize> for Field {
+    fn from(v: usize) -> Field { Field::Int(v as i64) }
+}
+
+impl From<f64> for Field {
+    fn from(v: f64) -> Field { Field::Float(v) }
+}
+
+impl<'a> From<&'a str> for Field {
+    fn from(v: &str) -> Field { Field::Text(v.to_string()) }
+}
+
+impl From<String> for Field {
+    fn from(v: String) -> Field { Field::Text(v) }
+}
+
+impl<'a> From<&'a String> for Field {
+    fn from(v: &String) -> Field { Field::Text(v.clone()) }
+}
+
+/* durations are logged in microseconds */
+impl From<Duration> for Field {
+    fn from(v: Duration) -> Field { Field::Int(v.as_micros() as i64) }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Field::Int(v) => write!(f, "{}", v),
+            Field::Float(v) => write!(f, "{}", v),
+            Field::Text(v) => {
+                /* quote text that would otherwise be ambiguous */
+                if v.len() == 0 || v.contains(|c: char| c.is_whitespace()
+                                              || c == '"' || c == '=') {
+                    write!(f, "{:?}", v)
+                }
+                else {
+                    write!(f, "{}", v)
+                }
+            },
+        }
+    }
+}
+
+enum Output {
+    Stderr,
+    File(RotatingFile),
+}
+
+/*
+ * A log file that is renamed to FILE.1 once it grows past max_size,
+ * shifting older files up to FILE.<max_files>
+ */
+struct RotatingFile {
+    path: String,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: u32,
+}
+
+impl RotatingFile {
+    fn open(path: &str, max_size: u64, max_files: u32)
+        -> io::Result<RotatingFile>
+    {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_string(),
+            file: file,
+            size: size,
+            max_size: max_size,
+            max_files: max_files,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for i in (1..self.max_files).rev() {
+            let from = format!("{}.{}", self.path, i);
+            if fs::metadata(&from).is_ok() {
+                fs::rename(&from, format!("{}.{}", self.path, i + 1))?;
+            }
+        }
+
+        if self.max_files > 0 {
+            fs::rename(&self.path, format!("{}.1", self.path))?;
+        }
+
+        self.file = OpenOptions::new().create(true).write(true)
+            .truncate(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.max_size > 0 && self.size + line.len() as u64 > self.max_size
+            && self.size > 0 {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+struct Logger {
+    level: Level,
+    json: bool,
+    output: Mutex<Output>,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/*
+ * Sets up the logger from the config. Records logged before this are
+ * written as text to stderr at the info level.
+ */
+pub fn init(config: &Config) -> io::Result<()> {
+    let output = match config.log_file.as_str() {
+        "" => Output::Stderr,
+        path => Output::File(RotatingFile::open(path, config.log_max_size,
+                                                config.log_max_files)?),
+    };
+
+    let logger = Logger {
+        level: Level::from_str(&config.log_level),
+        json: config.log_format == "json",
+        output: Mutex::new(output),
+    };
+
+    if LOGGER.set(logger).is_err() {
+        return Err(io::Error::new(io::ErrorKind::Other,
+                                  "logger already initialized"));
+    }
+    Ok(())
+}
+
+pub fn enabled(level: Level) -> bool {
+    match LOGGER.get() {
+        Some(logger) => level <= logger.level,
+        None => level <= Level::Info,
+    }
+}
+
+pub fn write(level: Level, message: &str, fields: &[(&str, Field)]) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0));
+    let time = format!("{}Z", format_time(now.as_micros() as i64));
+
+    let json = LOGGER.get().map_or(false, |logger| logger.json);
+    let line = if json {
+        format_json(&time, level, message, fields)
+    }
+    else {
+        format_text(&time, level, message, fields)
+    };
+
+    let logger = match LOGGER.get() {
+        Some(logger) => logger,
+        None => {
+            let _ = io::stderr().write_all(line.as_bytes());
+            return;
+        },
+    };
+
+    let mut output = logger.output.lock().unwrap();
+    let result = match &mut *output {
+        Output::Stderr => io::stderr().write_all(line.as_bytes()),
+        Output::File(file) => file.write_line(&line),
+    };
+
+    /* nowhere better to report a broken log file */
+    if let Err(e) = result {
+        let _ = write!(io::stderr(), "could not write log: {}\n{}", e, line);
+    }
+}
+
+/* TIME LEVEL message key=value ... */
+fn format_text(time: &str, level: Level, message: &str,
+    fields: &[(&str, Field)]) -> String
+{
+    let mut line = format!("{} {:5} {}", time, level.as_str().to_uppercase(),
+                           message);
+    for (key, value) in fields {
+        line.push_str(&format!(" {}={}", key, value));
+    }
+    line.push('\n');
+    line
+}
+
+/* one JSON object per line */
+fn format_json(time: &str, level: Level, message: &str,
+    fields: &[(&str, Field)]) -> String
+{
+    let mut line = format!("{{\"time\":\"{}\",\"level\":\"{}\",\"msg\":{}",
+                           time, level.as_str(), json_string(message));
+    for (key, value) in fields {
+        let value = match value {
+            Field::Int(v) => v.to_string(),
+            Field::Float(v) if v.is_finite() => v.to_string(),
+            Field::Float(v) => json_string(&v.to_string()),
+            Field::Text(v) => json_string(v),
+        };
+        line.push_str(&format!(",{}:{}", json_string(key), value));
+    }
+    line.push_str("}\n");
+    line
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for ch in s.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/* 
+ * formats microseconds since the epoch as an ISO 8601 time, without a
+ * timezone 
+ */
+pub fn format_time(micros: i64) -> String {
+    let secs = micros.div_euclid(1_000_000);
+    let frac = micros.rem_euclid(1_000_000);
+    let days = secs.div_euclid(86400);
+    let rem = secs.rem_euclid(86400);
+
+    /* civil date from days since 1970-01-01 (Howard Hinnant's algorithm) */
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}", year, month, day,
+            rem / 3600, rem % 3600 / 60, rem % 60, frac)
+}
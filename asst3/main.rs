@@ -7,74 +7,212 @@
  * 2019
  */
 
+#[macro_use]
+mod logger;
 mod schema;
 mod packet;
 mod server;
 mod database;
+mod index;
+mod expr;
+mod decimal;
+mod json;
+mod pattern;
+mod filter;
+mod subscription;
+mod changelog;
+mod replica;
+mod raftlog;
+mod raft;
+mod config;
+mod metrics;
 
 use std::env;
+use config::Config;
 
 fn usage(prog: &String) {
-    println!("usage: {} [-g] PORT [FILE=default.txt] [HOST=localhost]", prog);
-    println!("\t-g: debug mode (more verbose)");
+    println!("usage: {} [-g] [OPTIONS] [PORT [FILE=default.txt] [HOST=localhost]]",
+             prog);
+    println!("\t-g: debug mode (same as --log-level debug)");
     println!("\tFILE: EasyDB schema file");
     println!("\tHOST: host name");
+    println!("options:");
+    println!("\t--config FILE: TOML config file, overridden by other options");
+    println!("\t--host HOST: address to bind to");
+    println!("\t--port PORT: port to listen on");
+    println!("\t--metrics-port PORT: serve Prometheus metrics over HTTP");
+    println!("\t--schema FILE: EasyDB schema file");
+    println!("\t--max-clients N: simultaneous clients (default 4)");
+    println!("\t--data-dir DIR: directory for server data (default .)");
+    println!("\t--log-level LEVEL: error, warn, info, debug or trace");
+    println!("\t--log-format FORMAT: text or json (default text)");
+    println!("\t--log-file FILE: rotating log file instead of stderr");
+    println!("\t--idle-timeout SECS: disconnect idle clients (default 300), \
+              0 disables");
+    println!("\t--read-timeout SECS: time to receive a request (default 5)");
+    println!("\t--write-timeout SECS: time to send a response (default 5)");
+    println!("\t--max-packet-size BYTES: largest accepted request packet");
+    println!("\t--event-queue N: change events held for a slow subscriber \
+              before its subscriptions are cancelled (default 1024)");
+    println!("\t--change-log-size N: changes kept for CHANGES requests \
+              (default 10000)");
+    println!("\t--replica-of HOST:PORT: follow a leader, copying its rows \
+              and answering writes with NOT_LEADER. The leader must have \
+              the same --admin-token");
+    println!("\t--cluster HOST:PORT,...: run as a node of a cluster, given \
+              the address every node listens to its peers on");
+    println!("\t--node-id N: position of this node in --cluster, from 1");
+    println!("\t--admin-token TOKEN: enables admin commands for clients \
+              that authenticate with TOKEN");
+}
+
+/* maps a command line flag onto its config file key */
+fn flag_key(flag: &str) -> Option<&'static str> {
+    Some(match flag {
+        "--host" => "host",
+        "--port" => "port",
+        "--metrics-port" => "metrics_port",
+        "--schema" => "schema",
+        "--max-clients" => "max_clients",
+        "--data-dir" => "data_dir",
+        "--log-level" => "log_level",
+        "--log-format" => "log_format",
+        "--log-file" => "log_file",
+        "--idle-timeout" => "idle_timeout",
+        "--read-timeout" => "read_timeout",
+        "--write-timeout" => "write_timeout",
+        "--max-packet-size" => "max_packet_size",
+        "--event-queue" => "event_queue",
+        "--change-log-size" => "change_log_size",
+        "--replica-of" => "replica_of",
+        "--cluster" => "cluster",
+        "--node-id" => "node_id",
+        "--admin-token" => "admin_token",
+        _ => return None,
+    })
+}
+
+/* 
+ * Builds the effective config: defaults, then the config file, then the
+ * flags and positional arguments in the order they were given 
+ */
+fn parse_args(args: &[String]) -> Result<Config, String> {
+    let mut config_file = None;
+    let mut overrides: Vec<(&'static str, String)> = vec![];
+    let mut positional = vec![];
+    
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        /* accept both `--flag value` and `--flag=value` */
+        let (flag, inline) = match arg.find('=') {
+            Some(pos) if arg.starts_with("--") => 
+                (&arg[..pos], Some(arg[pos + 1..].to_string())),
+            _ => (&arg[..], None),
+        };
+        
+        if flag == "-g" {
+            overrides.push(("log_level", String::from("debug")));
+            continue;
+        }
+        
+        if !flag.starts_with("--") {
+            positional.push(arg.clone());
+            continue;
+        }
+        
+        let value = match inline.or_else(|| it.next().cloned()) {
+            Some(value) => value,
+            None => return Err(format!("missing value for {}", flag)),
+        };
+        
+        match flag {
+            "--config" => config_file = Some(value),
+            _ => match flag_key(flag) {
+                Some(key) => overrides.push((key, value)),
+                None => return Err(format!("unknown option {}", flag)),
+            },
+        }
+    }
+    
+    if positional.len() > 3 {
+        return Err(String::from("too many arguments"));
+    }
+    
+    /* PORT [FILE] [HOST] as before named flags existed */
+    let keys = ["port", "schema", "host"];
+    for (key, value) in keys.iter().zip(positional.into_iter()) {
+        overrides.push((key, value));
+    }
+    
+    let mut config = Config::new();
+    if let Some(filename) = config_file {
+        config.load_file(&filename)?;
+    }
+    
+    for (key, value) in overrides {
+        config.set(key, &value)?;
+    }
+    
+    if config.port == 0 {
+        return Err(String::from("no port given"));
+    }
+    
+    let nodes = config.cluster_nodes().len();
+    if nodes > 0 && (config.node_id < 1 || config.node_id > nodes) {
+        return Err(format!("--node-id must be from 1 to {}", nodes));
+    }
+    if nodes > 0 && config.replica_of.len() > 0 {
+        return Err(String::from("--cluster and --replica-of cannot be combined"));
+    }
+    if config.replica_of.len() > 0 && config.admin_token.len() == 0 {
+        return Err(String::from("--replica-of needs the --admin-token of \
+                                 the leader"));
+    }
+    Ok(config)
 }
 
 fn main() {
     // Read in the arguments from command line
     let args: Vec<String> = env::args().collect();
-    let mut hostname = String::from("localhost");
-    let mut filename = String::from("default.txt");
-    let mut verbose = false;
     
     if args.len() < 2 {
         return usage(&args[0]);
     }
     
-    /* offset args by 1 depending on whether -g option is used */
-    let args = match &args[1][..] {
-        "-g" => { verbose = true; &args[1..] },
-        _ => &args[..],
+    let config = match parse_args(&args[1..]) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            return usage(&args[0]);
+        },
     };
     
-    if args.len() < 2 || args.len() > 4 {
-        return usage(&args[0]);
-    }
-       
-    if args.len() >= 3 {
-        filename.clear();
-        filename.push_str(&args[2]);
+    if let Err(e) = logger::init(&config) {
+        eprintln!("Could not open log file {}: {}", config.log_file, e);
+        return;
     }
     
-    if args.len() == 4 {
-        hostname.clear();
-        hostname.push_str(&args[3]);
+    for line in config.to_string().lines() {
+        debug!(format!("config {}", line));
     }
     
-    hostname.push_str(":");
-    hostname.push_str(&args[1]); 
-    
-    let tokens = match schema::tokenize(&filename) {
-        Ok(tokens) => tokens,
-        Err(e) => {
-            eprintln!("Could not read from {}: {}", filename, e);
-            return;
-        },
-    };
-
-    let table_schema = match schema::parse(tokens) {
+    let table_schema = match schema::load(&config.schema) {
         Ok(table_schema) => table_schema,
         Err(e) => {
-            eprintln!("Error processing {}: {}", filename, e);
+            error!("could not load schema"; file = &config.schema);
+            eprintln!("{}", e);
             return;
         },
     };
    
-    if verbose {
-        println!("{:?}", table_schema);
+    for table in &table_schema {
+        let columns: Vec<String> = table.t_cols.iter()
+            .map(|c| c.to_string()).collect();
+        let checks: Vec<&str> = table.t_checks.iter()
+            .map(|c| c.name.as_str()).collect();
+        debug!("table"; id = table.t_id, name = &table.t_name, 
+               columns = columns.join(", "), checks = checks.join(", "));
     }
     
-    server::run_server(table_schema, hostname, verbose);
+    server::run_server(table_schema, config);
 }
-
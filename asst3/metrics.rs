@@ -0,0 +1,244 @@
+/*
+ * metrics.rs
+ *
+ * Server metrics, exposed over HTTP in the Prometheus text format
+ *
+ * University of Toronto
+ * 2019
+ */
+
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{Read, Write};
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+use config::Config;
+use database::Database;
+use packet::Response;
+
+/* upper bounds of the histogram buckets, in seconds */
+const BUCKETS: [f64; 12] = [0.00001, 0.00005, 0.0001, 0.0005, 0.001, 0.005,
+                            0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/* how often the metrics listener checks for a pending shutdown */
+const ACCEPT_POLL: Duration = Duration::from_millis(50);
+
+pub struct Histogram {
+    counts: [AtomicU64; 12],    /* per bucket, not cumulative */
+    sum_us: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Histogram {
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Histogram {
+            counts: [ZERO; 12],
+            sum_us: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(& self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|b| seconds <= *b) {
+            self.counts[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_us.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(& self, out: &mut String, name: &str) {
+        let mut cumulative = 0;
+        for (i, bound) in BUCKETS.iter().enumerate() {
+            cumulative += self.counts[i].load(Ordering::Relaxed);
+            let _ = write!(out, "{}_bucket{{le=\"{}\"}} {}\n", name, bound,
+                           cumulative);
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_us.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = write!(out, "{}_bucket{{le=\"+Inf\"}} {}\n", name, count);
+        let _ = write!(out, "{}_sum {}\n", name, sum);
+        let _ = write!(out, "{}_count {}\n", name, count);
+    }
+}
+
+pub struct Metrics {
+    requests: Mutex<BTreeMap<(&'static str, i32), u64>>,
+    pub latency: Histogram,
+    pub lock_wait: Histogram,
+    active_connections: AtomicI64,
+    connections: AtomicU64,
+    rejected: AtomicU64,
+}
+
+pub static METRICS: Metrics = Metrics {
+    requests: Mutex::new(BTreeMap::new()),
+    latency: Histogram::new(),
+    lock_wait: Histogram::new(),
+    active_connections: AtomicI64::new(0),
+    connections: AtomicU64::new(0),
+    rejected: AtomicU64::new(0),
+};
+
+impl Metrics {
+    /* counts a handled request by command name and result code */
+    pub fn request(& self, command: &'static str, code: i32,
+        latency: Duration)
+    {
+        *self.requests.lock().unwrap().entry((command, code)).or_insert(0) += 1;
+        self.latency.observe(latency);
+    }
+
+    pub fn connected(& self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn disconnected(& self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /* a client turned away with SERVER_BUSY */
+    pub fn rejected(& self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(& self, db: &Mutex<Database>) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP easydb_requests_total Requests handled, by command and result code.\n");
+        out.push_str("# TYPE easydb_requests_total counter\n");
+        for ((command, code), count) in self.requests.lock().unwrap().iter() {
+            let _ = write!(out,
+                "easydb_requests_total{{command=\"{}\",code=\"{}\"}} {}\n",
+                command, Response::code_name(*code), count);
+        }
+
+        out.push_str("# HELP easydb_request_duration_seconds Time to handle a request.\n");
+        out.push_str("# TYPE easydb_request_duration_seconds histogram\n");
+        self.latency.render(&mut out, "easydb_request_duration_seconds");
+
+        out.push_str("# HELP easydb_lock_wait_seconds Time spent waiting for the database lock.\n");
+        out.push_str("# TYPE easydb_lock_wait_seconds histogram\n");
+        self.lock_wait.render(&mut out, "easydb_lock_wait_seconds");
+
+        out.push_str("# HELP easydb_active_connections Clients currently connected.\n");
+        out.push_str("# TYPE easydb_active_connections gauge\n");
+        let _ = write!(out, "easydb_active_connections {}\n",
+                       self.active_connections.load(Ordering::Relaxed));
+
+        out.push_str("# HELP easydb_connections_total Clients accepted.\n");
+        out.push_str("# TYPE easydb_connections_total counter\n");
+        let _ = write!(out, "easydb_connections_total {}\n",
+                       self.connections.load(Ordering::Relaxed));
+
+        out.push_str("# HELP easydb_rejected_connections_total Clients rejected with SERVER_BUSY.\n");
+        out.push_str("# TYPE easydb_rejected_connections_total counter\n");
+        let _ = write!(out, "easydb_rejected_connections_total {}\n",
+                       self.rejected.load(Ordering::Relaxed));
+
+        out.push_str("# HELP easydb_rows Rows stored, by table.\n");
+        out.push_str("# TYPE easydb_rows gauge\n");
+        let db = db.lock().unwrap();
+        for (table, (_, rows)) in db.tables.iter().zip(db.row_counts()) {
+            let _ = write!(out, "easydb_rows{{table=\"{}\"}} {}\n",
+                           table.t_name, rows);
+        }
+
+        if let Some(ref replica) = db.replica {
+            out.push_str("# HELP easydb_replication_lag Changes made on the leader and not yet applied.\n");
+            out.push_str("# TYPE easydb_replication_lag gauge\n");
+            let _ = write!(out, "easydb_replication_lag {}\n",
+                           replica.lag(db.applied()));
+
+            out.push_str("# HELP easydb_replication_contact_seconds Time since the leader last answered, -1 if never.\n");
+            out.push_str("# TYPE easydb_replication_contact_seconds gauge\n");
+            let contact = replica.since_contact();
+            let seconds = match contact {
+                -1 => -1.0,
+                ms => ms as f64 / 1e3,
+            };
+            let _ = write!(out, "easydb_replication_contact_seconds {}\n",
+                           seconds);
+        }
+        out
+    }
+}
+
+/*
+ * Starts the HTTP listener for metrics scrapes on its own thread, each
+ * scrape answered on a thread of its own. The listener is non-blocking so
+ * it can poll `stopped` and close once the server shuts down.
+ */
+pub fn serve(config: &Config, db: Arc<Mutex<Database>>, stopped: fn() -> bool) {
+    if config.metrics_port == 0 {
+        return;
+    }
+
+    let address = format!("{}:{}", config.host, config.metrics_port);
+    let listener = match TcpListener::bind(&address)
+        .and_then(|listener| listener.set_nonblocking(true).map(|_| listener)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("could not start metrics listener"; address = &address,
+                   error = e.to_string());
+            return;
+        },
+    };
+
+    info!("serving metrics"; address = &address);
+    thread::spawn(move || {
+        while !stopped() {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(ACCEPT_POLL);
+                    continue;
+                },
+                Err(e) => {
+                    debug!("metrics scrape failed"; error = e.to_string());
+                    continue;
+                },
+            };
+
+            let db = db.clone();
+            thread::spawn(move || {
+                let result = stream.set_nonblocking(false)
+                    .and_then(|_| handle_scrape(stream, &db));
+                if let Err(e) = result {
+                    debug!("metrics scrape failed"; error = e.to_string());
+                }
+            });
+        }
+    });
+}
+
+/* answers a single HTTP request; only GET /metrics is supported */
+fn handle_scrape(mut stream: TcpStream, db: &Mutex<Database>)
+    -> io::Result<()>
+{
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut buffer = [0 as u8; 1024];
+    let len = stream.read(&mut buffer)?;
+    let request = String::from_utf8_lossy(&buffer[..len]);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+
+    let (status, body) = if request.starts_with("GET ") &&
+        (path == "/metrics" || path == "/") {
+        ("200 OK", METRICS.render(db))
+    }
+    else {
+        ("404 Not Found", String::from("not found\n"))
+    };
+
+    write!(stream, "HTTP/1.0 {}\r\n\
+                    Content-Type: text/plain; version=0.0.4\r\n\
+                    Content-Length: {}\r\n\
+                    Connection: close\r\n\r\n{}", status, body.len(), body)?;
+    stream.flush()
+}
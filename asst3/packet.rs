@@ -10,15 +10,26 @@
 use std::mem;
 use std::io;
 use std::fmt;
+use std::cmp::Ordering;
+use schema::Table;
+use database::Row;
+use logger;
+use decimal;
 
 /* The foreign key is a reference to a row id in a separate table */
-#[derive(Debug, PartialEq, PartialOrd)]
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub enum Value {
     Null,
     Integer(i64),
     Float(f64),
     Text(String),
     Foreign(i64),
+    Boolean(bool),
+    Timestamp(i64),             /* microseconds since the epoch */
+    Bytes(Vec<u8>),
+    Decimal(i64, u32),          /* unscaled value, scale */
+    List(Vec<Value>),           /* scalar values of a single type */
+    Json(String),               /* document text, exactly as written */
 }
 
 impl Value {
@@ -27,6 +38,39 @@ impl Value {
     pub const FLOAT: i32 = 2; 
     pub const STRING: i32 = 3;
     pub const FOREIGN: i32 = 4; 
+    pub const BOOLEAN: i32 = 5;
+    pub const TIMESTAMP: i32 = 6;
+    pub const BYTES: i32 = 7;
+    pub const ENUM: i32 = 8;    /* column type only, values are STRING */
+    pub const DECIMAL: i32 = 9;
+    pub const LIST: i32 = 10;
+    pub const JSON: i32 = 11;
+    
+    /* the type code sent on the wire and stored in Column::c_type */
+    pub fn type_code(& self) -> i32 {
+        match self {
+            Value::Null => Value::NULL,
+            Value::Integer(_) => Value::INTEGER,
+            Value::Float(_) => Value::FLOAT,
+            Value::Text(_) => Value::STRING,
+            Value::Foreign(_) => Value::FOREIGN,
+            Value::Boolean(_) => Value::BOOLEAN,
+            Value::Timestamp(_) => Value::TIMESTAMP,
+            Value::Bytes(_) => Value::BYTES,
+            Value::Decimal(..) => Value::DECIMAL,
+            Value::List(_) => Value::LIST,
+            Value::Json(_) => Value::JSON,
+        }
+    }
+    
+    /* like partial_cmp, except decimals compare by value across scales */
+    pub fn compare(& self, other: & Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Decimal(a, a_scale), Value::Decimal(b, b_scale)) =>
+                Some(decimal::compare(*a, *a_scale, *b, *b_scale)),
+            _ => self.partial_cmp(other),
+        }
+    }
 }
 
 impl fmt::Display for Value {
@@ -37,11 +81,33 @@ impl fmt::Display for Value {
             Value::Float(val) => write!(f, "{}", val),
             Value::Text(val) => write!(f, "{}", val),
             Value::Foreign(val) => write!(f, "foreign({})", val),
+            Value::Boolean(val) => write!(f, "{}", val),
+            Value::Timestamp(val) => write!(f, "{}", logger::format_time(*val)),
+            Value::Bytes(val) => {
+                write!(f, "0x")?;
+                for byte in val {
+                    write!(f, "{:02x}", byte)?;
+                }
+                Ok(())
+            },
+            Value::Decimal(val, scale) => 
+                write!(f, "{}", decimal::format(*val, *scale)),
+            Value::List(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            },
+            Value::Json(val) => write!(f, "{}", val),
         }
     }
 }
 
-/* Specifies the 5 available commands in EasyDB */
+/* Specifies the available commands in EasyDB */
 #[derive(Debug)]
 pub enum Command {
     Insert(Vec<Value>),            /* values */
@@ -49,7 +115,98 @@ pub enum Command {
     Drop(i64),                     /* id */
     Get(i64),                      /* id */
     Query(i32, i32, Value),        /* column_id, operator, value */
+    Schema,                        /* describe the loaded schema */
     Exit,                          /* disconnect from server */
+    Auth(String),                  /* admin token */
+    Stats,                         /* server statistics (admin) */
+    Connections,                   /* list connected clients (admin) */
+    Kill(i64),                     /* connection id (admin) */
+    Migrate(Migration),            /* schema change (admin) */
+    Sum(i32),                      /* column_id */
+    Subscribe(i32, i32, Value),    /* column_id, operator, value */
+    Unsubscribe(i64),              /* subscription id */
+    Changes(i64, i32),             /* after sequence, limit */
+    Snapshot,                      /* every row, for a follower */
+    Replication,                   /* replication status */
+}
+
+/* An online schema change, applied to the table in the request */
+#[derive(Debug)]
+pub enum Migration {
+    AddTable(String, Vec<(String, String)>),    /* name, (column, declaration) */
+    DropTable,
+    AddColumn(String, String, Value),       /* name, declaration, default */
+    DropColumn(i32),                            /* column id */
+    RenameColumn(i32, String),                  /* column id, new name */
+}
+
+impl Migration {
+    pub const ADD_TABLE: i32 = 1;
+    pub const DROP_TABLE: i32 = 2;
+    pub const ADD_COLUMN: i32 = 3;
+    pub const DROP_COLUMN: i32 = 4;
+    pub const RENAME_COLUMN: i32 = 5;
+}
+
+impl Command {
+    /* short name of the command, for logging */
+    pub fn name(& self) -> &'static str {
+        match self {
+            Command::Insert(..) => "insert",
+            Command::Update(..) => "update",
+            Command::Drop(..) => "drop",
+            Command::Get(..) => "get",
+            Command::Query(..) => "scan",
+            Command::Schema => "schema",
+            Command::Exit => "exit",
+            Command::Auth(..) => "auth",
+            Command::Stats => "stats",
+            Command::Connections => "connections",
+            Command::Kill(..) => "kill",
+            Command::Migrate(..) => "migrate",
+            Command::Sum(..) => "sum",
+            Command::Subscribe(..) => "subscribe",
+            Command::Unsubscribe(..) => "unsubscribe",
+            Command::Changes(..) => "changes",
+            Command::Snapshot => "snapshot",
+            Command::Replication => "replication",
+        }
+    }
+
+    /* commands that change rows, which only a leader accepts */
+    pub fn is_write(& self) -> bool {
+        match self {
+            Command::Insert(..) | Command::Update(..) | Command::Drop(..) => true,
+            _ => false,
+        }
+    }
+
+    /* commands that read rows, which a cluster only answers on its leader */
+    pub fn is_read(& self) -> bool {
+        match self {
+            Command::Get(..) | Command::Query(..) | Command::Sum(..) |
+            Command::Subscribe(..) | Command::Changes(..) | 
+            Command::Snapshot => true,
+            _ => false,
+        }
+    }
+
+    /* subscriptions belong to the connection, so the server handles them */
+    pub fn is_subscription(& self) -> bool {
+        match self {
+            Command::Subscribe(..) | Command::Unsubscribe(..) => true,
+            _ => false,
+        }
+    }
+
+    /* admin commands are answered by the server, not the database */
+    pub fn is_admin(& self) -> bool {
+        match self {
+            Command::Auth(..) | Command::Stats | Command::Connections |
+            Command::Kill(..) | Command::Migrate(..) => true,
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -65,6 +222,65 @@ impl Request {
     pub const GET: i32 = 4;   
     pub const SCAN: i32 = 5;
     pub const EXIT: i32 = 6;
+    pub const SCHEMA: i32 = 7;
+    pub const AUTH: i32 = 8;
+    pub const STATS: i32 = 9;
+    pub const CONNECTIONS: i32 = 10;
+    pub const KILL: i32 = 11;
+    pub const MIGRATE: i32 = 12;
+    pub const SUM: i32 = 13;
+    pub const SUBSCRIBE: i32 = 14;
+    pub const UNSUBSCRIBE: i32 = 15;
+    pub const CHANGES: i32 = 16;
+    pub const SNAPSHOT: i32 = 17;
+    pub const REPLICATION: i32 = 18;
+}
+
+/* server statistics returned by the STATS admin command */
+#[derive(Debug)]
+pub struct Stats {
+    pub uptime: i64,            /* seconds */
+    pub requests: i64,          /* requests served since startup */
+    pub connections: i32,       /* clients currently connected */
+    pub memory: i64,            /* estimated bytes used by rows */
+    pub rows: Vec<(i32, i64)>,  /* table id, row count */
+}
+
+/* a connected client, as listed by the CONNECTIONS admin command */
+#[derive(Debug)]
+pub struct ClientInfo {
+    pub id: i64,
+    pub peer: String,
+    pub since: i64,             /* seconds since the epoch */
+    pub requests: i64,
+}
+
+/* replication state returned by the REPLICATION command */
+#[derive(Debug)]
+pub struct ReplicaStatus {
+    pub leader: String,         /* host:port of the leader, empty on a leader */
+    pub applied: i64,           /* sequence of the last change applied */
+    pub leader_sequence: i64,   /* latest sequence the leader reported */
+    pub lag: i64,               /* changes not applied yet */
+    pub since_contact: i64,     /* ms since the leader answered, -1 never */
+}
+
+/* a change to a row, as kept in the change log and sent to subscribers */
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub sequence: i64,          /* position in the change log */
+    pub kind: i32,              /* one of the kinds below */
+    pub table_id: i32,
+    pub object_id: i64,
+    pub version: i64,           /* new version, or the last one for drops */
+    pub values: Vec<Value>,     /* new values, or the last ones for drops */
+}
+
+impl Change {
+    pub const INSERT: i32 = 1;
+    pub const UPDATE: i32 = 2;
+    pub const DROP: i32 = 3;
+    pub const CANCELLED: i32 = 4;   /* the subscription ended, no row */
 }
 
 /* we have to specify lifetime here because we are borrowing Vec<Value>
@@ -72,15 +288,38 @@ impl Request {
 #[derive(Debug)]
 pub enum Response<'a> {
     Error(i32),                 /* error code (except for OK) */
+    Violation(String),          /* name of the failed check constraint */
+    NotLeader(String),          /* address of the leader, empty if unknown */
     Connected,
     Insert(i64, i64),           /* id, version */
     Update(i64),                /* version */
     Drop,
     Get(i64, &'a Vec<Value>),   /* version, values */
     Query(Vec<i64>),            /* ids */
+    Schema(&'a Vec<Table>),     /* tables */
+    Done,                       /* admin command succeeded */
+    Stats(Stats),
+    Connections(Vec<ClientInfo>),
+    Sum(Value),                 /* total of a column */
+    Subscribed(i64),            /* subscription id */
+    Change(i64, Change),        /* subscription id; pushed, not a response */
+    Changes(i64, i64, Vec<&'a Change>), /* cursor, latest sequence, changes */
+    Snapshot(i64, &'a Vec<Row>),        /* sequence, rows */
+    Replication(ReplicaStatus),
 }
 
 impl Response<'_> {
+    /* result code sent as the first field of the response */
+    pub fn code(& self) -> i32 {
+        match self {
+            Response::Error(code) => *code,
+            Response::Violation(_) => Response::CHECK_FAILED,
+            Response::NotLeader(_) => Response::NOT_LEADER,
+            Response::Change(..) => Response::CHANGE,
+            _ => Response::OK,
+        }
+    }
+
     pub const OK: i32 = 1;
     pub const NOT_FOUND: i32 = 2;       /* id not found */
     pub const BAD_TABLE: i32 = 3;       /* table not found */
@@ -92,8 +331,53 @@ impl Response<'_> {
     pub const BAD_FOREIGN: i32 = 9;     /* foreign key not found */
     pub const SERVER_BUSY: i32 = 10;    /* server is busy */
     pub const UNIMPLEMENTED: i32 = 11;  /* command not implemented */
+    pub const TIMEOUT: i32 = 12;        /* connection timed out */
+    pub const FORBIDDEN: i32 = 13;      /* admin command not authorized */
+    pub const BAD_SCHEMA: i32 = 14;     /* invalid schema change */
+    pub const NOT_NULL: i32 = 15;       /* null in a not null column */
+    pub const NOT_UNIQUE: i32 = 16;     /* duplicate in a unique column */
+    pub const CHECK_FAILED: i32 = 17;   /* row fails a check constraint */
+    
+    /* starts a change event instead of a result code */
+    pub const CHANGE: i32 = 18;
+    
+    pub const TRUNCATED: i32 = 19;      /* change log misses the changes */
+    pub const NOT_LEADER: i32 = 20;     /* write sent to a follower */
+
+    /* name of a result code, for logs and metrics */
+    pub fn code_name(code: i32) -> &'static str {
+        match code {
+            Response::OK => "OK",
+            Response::NOT_FOUND => "NOT_FOUND",
+            Response::BAD_TABLE => "BAD_TABLE",
+            Response::BAD_QUERY => "BAD_QUERY",
+            Response::TXN_ABORT => "TXN_ABORT",
+            Response::BAD_VALUE => "BAD_VALUE",
+            Response::BAD_ROW => "BAD_ROW",
+            Response::BAD_REQUEST => "BAD_REQUEST",
+            Response::BAD_FOREIGN => "BAD_FOREIGN",
+            Response::SERVER_BUSY => "SERVER_BUSY",
+            Response::UNIMPLEMENTED => "UNIMPLEMENTED",
+            Response::TIMEOUT => "TIMEOUT",
+            Response::FORBIDDEN => "FORBIDDEN",
+            Response::BAD_SCHEMA => "BAD_SCHEMA",
+            Response::NOT_NULL => "NOT_NULL",
+            Response::NOT_UNIQUE => "NOT_UNIQUE",
+            Response::CHECK_FAILED => "CHECK_FAILED",
+            Response::CHANGE => "CHANGE",
+            Response::TRUNCATED => "TRUNCATED",
+            Response::NOT_LEADER => "NOT_LEADER",
+            _ => "UNKNOWN",
+        }
+    }
 }
 
+/* size field of a decimal value */
+const DECIMAL_SIZE: usize = 12;
+
+/* default limit on the size of a request packet */
+pub const MAX_PACKET_SIZE : usize = 16384;
+
 /* trait for response packet (outgoing) */
 trait Out<T: ?Sized> {   
     fn write(&mut self, value: &T);
@@ -107,7 +391,8 @@ trait Buffer {
 trait In<T> : Buffer {
     fn read(&mut self) -> io::Result<T> {
         if self.underfull(self.size()) {
-            Err(io::Error::new(io::ErrorKind::Other, "Incomplete packet"))
+            Err(io::Error::new(io::ErrorKind::UnexpectedEof, 
+                               "Incomplete packet"))
         }
         else {      
             Ok(self.from_raw())
@@ -126,8 +411,6 @@ struct ByteArray {
 }
 
 impl ByteArray {
-    const MAX_PACKET_SIZE : usize = 16384;
-
     pub fn new() -> Self {
         ByteArray {
             buffer: vec!(),
@@ -170,7 +453,67 @@ impl ByteArray {
         Ok(var)
     }
     
+    /* write size field followed by the string padded to 4 bytes */
+    fn write_text(&mut self, value: &str) {
+        self.write(&(aligned_size(value.len(), mem::size_of::<i32>()) as i32));
+        self.write(value);
+    }
+    
+    /* write type and size fields followed by the value field */
+    fn write_value(&mut self, value: &Value) {
+        self.write(&value.type_code());
+        match value {
+            Value::Null => self.write(&(0 as i32)),
+            Value::Integer(v) | Value::Foreign(v) | Value::Timestamp(v) => { 
+                self.write(&(mem::size_of::<i64>() as i32));
+                self.write(v);
+            },
+            Value::Float(v) => { 
+                self.write(&(mem::size_of::<f64>() as i32));
+                self.write(v);
+            },
+            Value::Text(v) | Value::Json(v) => self.write_text(v),
+            Value::Boolean(v) => {
+                self.write(&(mem::size_of::<i64>() as i32));
+                self.write(&(*v as i64));
+            },
+            Value::Bytes(v) => {
+                /* exact length, unlike strings which are NUL padded */
+                self.write(&(v.len() as i32));
+                self.write(&v[..]);
+            },
+            Value::Decimal(v, scale) => {
+                self.write(&(DECIMAL_SIZE as i32));
+                self.write(v);
+                self.write(&(*scale as i32));
+            },
+            Value::List(values) => {
+                /* the size covers the count and the nested values */
+                let mut list = ByteArray::new();
+                list.write(&(values.len() as i32));
+                for value in values {
+                    list.write_value(value);
+                }
+                self.write(&(list.buffer.len() as i32));
+                self.buffer.extend_from_slice(&list.buffer);
+                self.pointer += list.buffer.len();
+            },
+        };
+    }
+    
     /* read size field followed by variant value field */
+    fn write_change(&mut self, change: &Change) {
+        self.write(&change.sequence);
+        self.write(&change.kind);
+        self.write(&change.table_id);
+        self.write(&change.object_id);
+        self.write(&change.version);
+        self.write(&(change.values.len() as i32));
+        for value in &change.values {
+            self.write_value(value);
+        }
+    }
+    
     fn read_value(&mut self) -> io::Result<Value> {
         let value_type: i32  = self.read()?;
         Ok(match value_type {
@@ -190,16 +533,185 @@ impl ByteArray {
                 self.read_size()?;
                 Value::Text(self.read()?)
             },
+            /* encoded like a string, and validated when written */
+            Value::JSON => {
+                self.read_size()?;
+                Value::Json(self.read()?)
+            },
             Value::FOREIGN => {
                 self.read_fixed()?;
                 Value::Foreign(self.read()?)
             },
+            Value::BOOLEAN => {
+                self.read_fixed()?;
+                let val: i64 = self.read()?;
+                match val {
+                    0 => Value::Boolean(false),
+                    1 => Value::Boolean(true),
+                    _ => return Err(io::Error::new(io::ErrorKind::Other,
+                                    "Read invalid boolean")),
+                }
+            },
+            Value::TIMESTAMP => {
+                self.read_fixed()?;
+                Value::Timestamp(self.read()?)
+            },
+            Value::BYTES => {
+                self.read_size()?;
+                Value::Bytes(self.read()?)
+            },
+            Value::DECIMAL => {
+                /* unscaled i64 followed by an i32 scale */
+                if self.read_size()? != DECIMAL_SIZE as i32 {
+                    return Err(io::Error::new(io::ErrorKind::Other,
+                               "Read invalid value size (decimal)"));
+                }
+                self.strlen = 0;
+                let val: i64 = self.read()?;
+                let scale: i32 = self.read()?;
+                if scale < 0 || scale as u32 > decimal::MAX_PRECISION {
+                    return Err(io::Error::new(io::ErrorKind::Other,
+                               "Read invalid decimal scale"));
+                }
+                Value::Decimal(val, scale as u32)
+            },
+            Value::LIST => {
+                /* a count followed by that many values, but no lists */
+                let size = self.read_size()? as usize;
+                self.strlen = 0;
+                let end = self.pointer + size;
+                let count: i32 = self.read()?;
+                let mut values = vec![];
+                for _ in 0..count.max(0) {
+                    let value = self.read_value()?;
+                    if let Value::List(_) = value {
+                        return Err(io::Error::new(io::ErrorKind::Other,
+                                   "Read nested list"));
+                    }
+                    values.push(value);
+                }
+                if count < 0 || self.pointer != end {
+                    return Err(io::Error::new(io::ErrorKind::Other,
+                               "Read invalid list size"));
+                }
+                Value::List(values)
+            },
             _ => {
                 return Err(io::Error::new(io::ErrorKind::Other,
                                 "Read invalid value type"));
             },
         })
     }
+
+    /* read a value that must be a string */
+    fn read_text(&mut self) -> io::Result<String> {
+        match self.read_value()? {
+            Value::Text(text) => Ok(text),
+            _ => Err(io::Error::new(io::ErrorKind::Other,
+                                    "Expecting a string value")),
+        }
+    }
+    
+    /* read a schema change: operation code followed by its arguments */
+    fn read_migration(&mut self) -> io::Result<Migration> {
+        let op: i32 = self.read()?;
+        Ok(match op {
+            Migration::ADD_TABLE => {
+                let name = self.read_text()?;
+                let numcols: i32 = self.read()?;
+                let mut columns = vec![];
+                for _ in 0..numcols {
+                    let column = self.read_text()?;
+                    columns.push((column, self.read_text()?));
+                }
+                Migration::AddTable(name, columns)
+            },
+            Migration::DROP_TABLE => Migration::DropTable,
+            Migration::ADD_COLUMN => {
+                let name = self.read_text()?;
+                let column_type = self.read_text()?;
+                Migration::AddColumn(name, column_type, self.read_value()?)
+            },
+            Migration::DROP_COLUMN => Migration::DropColumn(self.read()?),
+            Migration::RENAME_COLUMN => {
+                let column_id: i32 = self.read()?;
+                Migration::RenameColumn(column_id, self.read_text()?)
+            },
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::Other,
+                                "Invalid migration"));
+            },
+        })
+    }
+    
+    /* parse a request packet */
+    fn read_request(&mut self) -> io::Result<Request> {
+        let cmd = self.read()?;
+        use self::Command::*;
+       
+        Ok(Request{ 
+            table_id: self.read()?, 
+            command: match cmd {
+                Request::INSERT => {
+                    let mut vec = Vec::<Value>::new();
+                    let numcols: i32 = self.read()?;
+                    
+                    for _ in 0..numcols {
+                        vec.push(self.read_value()?);
+                    }
+                    
+                    Insert(vec)
+                },
+                Request::UPDATE => {
+                    let mut vec = Vec::<Value>::new();
+                    let id: i64 = self.read()?;
+                    let version: i64 = self.read()?;
+                    let numcols: i32 = self.read()?;
+                    
+                    for _ in 0..numcols {
+                        vec.push(self.read_value()?);
+                    }
+                    
+                    Update(id, version, vec)
+                },
+                Request::DROP => {
+                    Drop(self.read()?)
+                },
+                Request::GET => {
+                    Get(self.read()?)
+                },
+                Request::SCAN => {
+                    let column_id: i32 = self.read()?;
+                    let operator: i32 = self.read()?;
+                    Query(column_id, operator, self.read_value()?)
+                },
+                Request::EXIT => Exit,
+                Request::SCHEMA => Schema,
+                Request::AUTH => Auth(self.read_text()?),
+                Request::STATS => Stats,
+                Request::CONNECTIONS => Connections,
+                Request::KILL => Kill(self.read()?),
+                Request::MIGRATE => Migrate(self.read_migration()?),
+                Request::SUM => Sum(self.read()?),
+                Request::SUBSCRIBE => {
+                    let column_id: i32 = self.read()?;
+                    let operator: i32 = self.read()?;
+                    Subscribe(column_id, operator, self.read_value()?)
+                },
+                Request::UNSUBSCRIBE => Unsubscribe(self.read()?),
+                Request::CHANGES => {
+                    let since: i64 = self.read()?;
+                    Changes(since, self.read()?)
+                },
+                Request::SNAPSHOT => Snapshot,
+                Request::REPLICATION => Replication,
+                _ => {
+                    return Err(io::Error::new(io::ErrorKind::Other,
+                                "Invalid command"));
+                },
+            },
+        })
+    }
 }
 
 /* make sure we do not overflow buffer */
@@ -210,8 +722,8 @@ impl Buffer for ByteArray {
 }
 
 /* create packet from a byte array */
-impl From<& [u8; ByteArray::MAX_PACKET_SIZE]> for ByteArray {
-    fn from(buf: & [u8; ByteArray::MAX_PACKET_SIZE]) -> Self {
+impl From<& [u8]> for ByteArray {
+    fn from(buf: & [u8]) -> Self {
         ByteArray {
             buffer: buf.to_vec(),
             pointer: 0,
@@ -346,84 +858,109 @@ impl In<String> for ByteArray {
         self.pointer = end;
         self.strlen = 0;        /* consumed and reset */  
         let mut s = s.to_string();
-        loop {
-            if let Some(c) = s.pop() {
-                if c != '\0' {
-                    s.push(c);
-                    break;
-                }
-            }
+        while s.ends_with('\0') {
+            s.pop();
         }
         s
     }
 }
 
+/* reading in raw bytes, which are padded to 4 bytes like strings */
+impl In<Vec<u8>> for ByteArray {
+    fn size(& self) -> usize {
+        aligned_size(self.strlen, mem::size_of::<i32>())
+    }
+
+    fn from_raw(&mut self) -> Vec<u8> {
+        let end: usize = self.pointer + self.strlen;
+        let bytes = self.buffer[self.pointer..end].to_vec();
+        self.pointer += aligned_size(self.strlen, mem::size_of::<i32>());
+        self.strlen = 0;        /* consumed and reset */  
+        bytes
+    }
+}
+
+/* 
+ * A write request in the format clients send it, for the consensus log of
+ * a cluster. None for requests that do not change rows.
+ */
+pub fn encode_request(request: & Request) -> Option<Vec<u8>> {
+    let mut packet = ByteArray::new();
+    match request.command {
+        Command::Insert(ref values) => {
+            packet.write(&Request::INSERT);
+            packet.write(&request.table_id);
+            packet.write(&(values.len() as i32));
+            for value in values {
+                packet.write_value(value);
+            }
+        },
+        Command::Update(id, version, ref values) => {
+            packet.write(&Request::UPDATE);
+            packet.write(&request.table_id);
+            packet.write(&id);
+            packet.write(&version);
+            packet.write(&(values.len() as i32));
+            for value in values {
+                packet.write_value(value);
+            }
+        },
+        Command::Drop(id) => {
+            packet.write(&Request::DROP);
+            packet.write(&request.table_id);
+            packet.write(&id);
+        },
+        _ => return None,
+    }
+    Some(packet.buffer)
+}
+
+pub fn decode_request(bytes: & [u8]) -> io::Result<Request> {
+    ByteArray::from(bytes).read_request()
+}
+
 pub trait Network : io::Write + io::Read {
 
-    /* receive a packet from client */
-    fn receive(&mut self) -> io::Result<Request> { 
-        let mut buffer : [u8; ByteArray::MAX_PACKET_SIZE] = unsafe {
-            mem::MaybeUninit::uninit().assume_init()
-        };
+    /* 
+     * receive a packet of at most max_size bytes from client. A packet
+     * that arrives in several pieces is read until it is complete.
+     */
+    fn receive(&mut self, max_size: usize) -> io::Result<Request> { 
+        let mut buffer = vec![0 as u8; max_size];
+        let mut len = 0;
       
-        self.read(&mut buffer)?;
-        let mut packet = ByteArray::from(&buffer);
-        let cmd = packet.read()?;
-        use self::Command::*;
-       
-        Ok(Request{ 
-            table_id: packet.read()?, 
-            command: match cmd {
-                Request::INSERT => {
-                    let mut vec = Vec::<Value>::new();
-                    let numcols: i32 = packet.read()?;
-                    
-                    for _ in 0..numcols {
-                        vec.push(packet.read_value()?);
-                    }
-                    
-                    Insert(vec)
-                },
-                Request::UPDATE => {
-                    let mut vec = Vec::<Value>::new();
-                    let id: i64 = packet.read()?;
-                    let version: i64 = packet.read()?;
-                    let numcols: i32 = packet.read()?;
-                    
-                    for _ in 0..numcols {
-                        vec.push(packet.read_value()?);
-                    }
-                    
-                    Update(id, version, vec)
-                },
-                Request::DROP => {
-                    Drop(packet.read()?)
-                },
-                Request::GET => {
-                    Get(packet.read()?)
-                },
-                Request::SCAN => {
-                    let column_id: i32 = packet.read()?;
-                    let operator: i32 = packet.read()?;
-                    Query(column_id, operator, packet.read_value()?)
-                },
-                Request::EXIT => Exit,
-                _ => {
-                    return Err(io::Error::new(io::ErrorKind::Other,
-                                "Invalid command"));
-                },
-            },
-        })
+        loop {
+            let n = self.read(&mut buffer[len..])?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                          "Connection closed"));
+            }
+            len += n;
+            
+            let mut packet = ByteArray::from(&buffer[..len]);
+            match packet.read_request() {
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof 
+                    && len < max_size => continue,
+                result => return result,
+            }
+        }
     }
 
     /* send packet to client */
     fn respond(&mut self, resp: &Response) -> io::Result<usize> {
         use self::Response::*;
-        use self::Value::*;
         let mut packet = ByteArray::new();
         
         match resp {
             Error(code) => packet.write(code),
+            Violation(name) => {
+                packet.write(&Response::CHECK_FAILED);
+                packet.write_text(name);
+            },
+            NotLeader(leader) => {
+                packet.write(&Response::NOT_LEADER);
+                packet.write_text(leader);
+            },
             Insert(id, version) => {
                 packet.write(&Response::OK);
                 packet.write(id);
@@ -440,33 +977,7 @@ pub trait Network : io::Write + io::Read {
                 packet.write(version);
                 packet.write(&(values.len() as i32));
                 for value in values.into_iter() {
-                    match value {
-                        Null => {
-                            packet.write(&Value::NULL);
-                            packet.write(&(0 as i32));
-                        },
-                        Integer(v) => { 
-                            packet.write(&Value::INTEGER);
-                            packet.write(&(mem::size_of::<i64>() as i32));
-                            packet.write(v);
-                        },
-                        Float(v) => { 
-                            packet.write(&Value::FLOAT);
-                            packet.write(&(mem::size_of::<f64>() as i32));
-                            packet.write(v);
-                        },
-                        Text(v) => {
-                            packet.write(&Value::STRING);
-                            packet.write(&(aligned_size(v.len(), 
-                                         mem::size_of::<i32>()) as i32));
-                            packet.write(v);
-                        },
-                        Foreign(v) => { 
-                            packet.write(&Value::FOREIGN);
-                            packet.write(&(mem::size_of::<i64>() as i32));
-                            packet.write(v);
-                        },
-                    };
+                    packet.write_value(value);
                 }
             },
             Query(ids) => {
@@ -476,9 +987,250 @@ pub trait Network : io::Write + io::Read {
                     packet.write(id);
                 }
             },
+            Schema(tables) => {
+                packet.write(&Response::OK);
+                packet.write(&(tables.len() as i32));
+                for table in tables.iter() {
+                    packet.write(&table.t_id);
+                    packet.write_text(&table.t_name);
+                    packet.write(&(table.t_cols.len() as i32));
+                    for column in &table.t_cols {
+                        packet.write(&column.c_id);
+                        packet.write_text(&column.c_name);
+                        packet.write(&column.c_type);
+                        packet.write(&column.c_ref);
+                        /* enums are followed by their values, in order */
+                        if column.c_type == Value::ENUM {
+                            packet.write(&(column.c_variants.len() as i32));
+                            for variant in &column.c_variants {
+                                packet.write_text(variant);
+                            }
+                        }
+                        /* and decimals by their precision and scale */
+                        if column.c_type == Value::DECIMAL {
+                            packet.write(&(column.c_precision as i32));
+                            packet.write(&(column.c_scale as i32));
+                        }
+                        /* and lists by the type of their elements */
+                        if column.c_type == Value::LIST {
+                            packet.write(&column.c_element);
+                        }
+                    }
+                }
+            },
+            Done => packet.write(&Response::OK),
+            Stats(stats) => {
+                packet.write(&Response::OK);
+                packet.write(&stats.uptime);
+                packet.write(&stats.requests);
+                packet.write(&stats.connections);
+                packet.write(&stats.memory);
+                packet.write(&(stats.rows.len() as i32));
+                for (table_id, rows) in &stats.rows {
+                    packet.write(table_id);
+                    packet.write(rows);
+                }
+            },
+            Sum(value) => {
+                packet.write(&Response::OK);
+                packet.write_value(value);
+            },
+            Subscribed(id) => {
+                packet.write(&Response::OK);
+                packet.write(id);
+            },
+            Change(subscription, change) => {
+                packet.write(&Response::CHANGE);
+                packet.write(subscription);
+                packet.write_change(change);
+            },
+            Changes(cursor, latest, changes) => {
+                packet.write(&Response::OK);
+                packet.write(cursor);
+                packet.write(latest);
+                packet.write(&(changes.len() as i32));
+                for change in changes {
+                    packet.write_change(change);
+                }
+            },
+            Snapshot(sequence, rows) => {
+                packet.write(&Response::OK);
+                packet.write(sequence);
+                packet.write(&(rows.len() as i32));
+                for row in rows.iter() {
+                    packet.write(&row.table_id);
+                    packet.write(&row.object_id);
+                    packet.write(&row.version);
+                    packet.write(&(row.values.len() as i32));
+                    for value in &row.values {
+                        packet.write_value(value);
+                    }
+                }
+            },
+            Replication(status) => {
+                packet.write(&Response::OK);
+                packet.write_text(&status.leader);
+                packet.write(&status.applied);
+                packet.write(&status.leader_sequence);
+                packet.write(&status.lag);
+                packet.write(&status.since_contact);
+            },
+            Connections(clients) => {
+                packet.write(&Response::OK);
+                packet.write(&(clients.len() as i32));
+                for client in clients {
+                    packet.write(&client.id);
+                    packet.write_text(&client.peer);
+                    packet.write(&client.since);
+                    packet.write(&client.requests);
+                }
+            },
         };
         
-        self.write(&packet.buffer)
+        /* snapshots can be far larger than one write takes */
+        self.write_all(&packet.buffer)?;
+        Ok(packet.buffer.len())
+    }
+
+    /*
+     * Followers are clients of their leader. These send it a request and
+     * read the answer, or the error code it answered with.
+     */
+
+    /* the answer to connecting: OK, or SERVER_BUSY */
+    fn greeting(&mut self) -> io::Result<Result<(), i32>> {
+        let mut reader = Reader { stream: self };
+        match reader.i32()? {
+            Response::OK => Ok(Ok(())),
+            code => Ok(Err(code)),
+        }
+    }
+
+    /* authenticates with the admin token, which snapshots need */
+    fn request_auth(&mut self, token: &str) -> io::Result<Result<(), i32>> {
+        let mut packet = ByteArray::new();
+        packet.write(&Request::AUTH);
+        packet.write(&(0 as i32));
+        packet.write_value(&Value::Text(token.to_string()));
+        self.write_all(&packet.buffer)?;
+
+        let mut reader = Reader { stream: self };
+        match reader.i32()? {
+            Response::OK => Ok(Ok(())),
+            code => Ok(Err(code)),
+        }
+    }
+
+    /* every row, and the sequence of the last change they include */
+    fn request_snapshot(&mut self) -> io::Result<Result<(i64, Vec<Row>), i32>> {
+        let mut packet = ByteArray::new();
+        packet.write(&Request::SNAPSHOT);
+        packet.write(&(0 as i32));
+        self.write_all(&packet.buffer)?;
+
+        let mut reader = Reader { stream: self };
+        match reader.i32()? {
+            Response::OK => (),
+            code => return Ok(Err(code)),
+        }
+        let sequence = reader.i64()?;
+        let mut rows = vec![];
+        for _ in 0..reader.count()? {
+            let table_id = reader.i32()?;
+            let object_id = reader.i64()?;
+            let version = reader.i64()?;
+            rows.push(Row::new(table_id, object_id, version, reader.values()?));
+        }
+        Ok(Ok((sequence, rows)))
+    }
+
+    /* a page of the changes to every table: cursor, latest, changes */
+    fn request_changes(&mut self, since: i64, limit: i32) 
+        -> io::Result<Result<(i64, i64, Vec<Change>), i32>> 
+    {
+        let mut packet = ByteArray::new();
+        packet.write(&Request::CHANGES);
+        packet.write(&(0 as i32));
+        packet.write(&since);
+        packet.write(&limit);
+        self.write_all(&packet.buffer)?;
+
+        let mut reader = Reader { stream: self };
+        match reader.i32()? {
+            Response::OK => (),
+            code => return Ok(Err(code)),
+        }
+        let cursor = reader.i64()?;
+        let latest = reader.i64()?;
+        let mut changes = vec![];
+        for _ in 0..reader.count()? {
+            changes.push(Change {
+                sequence: reader.i64()?,
+                kind: reader.i32()?,
+                table_id: reader.i32()?,
+                object_id: reader.i64()?,
+                version: reader.i64()?,
+                values: reader.values()?,
+            });
+        }
+        Ok(Ok((cursor, latest, changes)))
+    }
+}
+
+/* 
+ * Reads the fields of an answer straight from the stream. Unlike requests,
+ * answers have no size limit, so they are read field by field.
+ */
+struct Reader<'a, R: io::Read + ?Sized + 'a> {
+    stream: &'a mut R,
+}
+
+impl<'a, R: io::Read + ?Sized> Reader<'a, R> {
+    fn field(&mut self, size: usize) -> io::Result<ByteArray> {
+        let mut bytes = vec![0 as u8; size];
+        self.stream.read_exact(&mut bytes)?;
+        Ok(ByteArray::from(&bytes[..]))
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        self.field(mem::size_of::<i32>())?.read()
+    }
+
+    fn i64(&mut self) -> io::Result<i64> {
+        self.field(mem::size_of::<i64>())?.read()
+    }
+
+    fn count(&mut self) -> io::Result<i32> {
+        let count = self.i32()?;
+        if count < 0 {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                       "Read invalid count"));
+        }
+        Ok(count)
+    }
+
+    /* the type and size fields, then the value padded to 4 bytes */
+    fn value(&mut self) -> io::Result<Value> {
+        let mut bytes = vec![0 as u8; 2 * mem::size_of::<i32>()];
+        self.stream.read_exact(&mut bytes)?;
+        let size: i32 = ByteArray::from(&bytes[4..]).read()?;
+        if size < 0 {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                       "Read invalid value size"));
+        }
+        let start = bytes.len();
+        let padded = aligned_size(size as usize, mem::size_of::<i32>());
+        bytes.resize(start + padded, 0);
+        self.stream.read_exact(&mut bytes[start..])?;
+        ByteArray::from(&bytes[..]).read_value()
+    }
+
+    fn values(&mut self) -> io::Result<Vec<Value>> {
+        let mut values = vec![];
+        for _ in 0..self.count()? {
+            values.push(self.value()?);
+        }
+        Ok(values)
     }
 }
 
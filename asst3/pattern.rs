@@ -0,0 +1,328 @@
+/*
+ * pattern.rs
+ *
+ * String matching for scans: SQL LIKE patterns and a small regex engine
+ *
+ * University of Toronto
+ * 2019
+ */
+
+/*
+ * Longest LIKE or regex pattern a scan accepts, in characters. Matching
+ * takes time proportional to the pattern times the text, under the
+ * database lock.
+ */
+pub const MAX_PATTERN: usize = 256;
+
+/* A part of a LIKE pattern */
+#[derive(PartialEq)]
+enum Wildcard {
+    Many,           /* % */
+    One,            /* _ */
+    Char(char),
+}
+
+/*
+ * SQL LIKE: `%` is any run of characters, `_` any one character, and `\`
+ * makes the next character literal. The whole text must match.
+ */
+pub struct Like {
+    parts: Vec<Wildcard>,
+}
+
+impl Like {
+    pub fn new(pattern: & str) -> Like {
+        let mut parts = vec![];
+        let mut chars = pattern.chars();
+        while let Some(ch) = chars.next() {
+            parts.push(match ch {
+                '%' => Wildcard::Many,
+                '_' => Wildcard::One,
+                /* a trailing backslash is literal */
+                '\\' => Wildcard::Char(chars.next().unwrap_or('\\')),
+                ch => Wildcard::Char(ch),
+            });
+        }
+        Like { parts: parts }
+    }
+
+    /*
+     * Matches left to right, remembering only the last `%`. When the rest
+     * fails to match, that `%` takes one more character and matching
+     * resumes after it, so no position is tried twice for it.
+     */
+    pub fn is_match(& self, text: & str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        let (mut part, mut t) = (0, 0);
+        let mut last_many: Option<(usize, usize)> = None;  /* part, text */
+        while t < text.len() {
+            match self.parts.get(part) {
+                Some(Wildcard::Many) => {
+                    part += 1;
+                    last_many = Some((part, t));
+                },
+                Some(Wildcard::One) => {
+                    part += 1;
+                    t += 1;
+                },
+                Some(Wildcard::Char(ch)) if *ch == text[t] => {
+                    part += 1;
+                    t += 1;
+                },
+                _ => match last_many {
+                    Some((after, from)) => {
+                        part = after;
+                        t = from + 1;
+                        last_many = Some((after, t));
+                    },
+                    None => return false,
+                },
+            }
+        }
+        self.parts[part..].iter().all(|p| *p == Wildcard::Many)
+    }
+}
+
+/* the literal text a LIKE pattern starts with, before any wildcard */
+pub fn like_prefix(pattern: & str) -> String {
+    let mut prefix = String::new();
+    let mut chars = pattern.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '%' | '_' => break,
+            '\\' => match chars.next() {
+                Some(ch) => prefix.push(ch),
+                None => prefix.push('\\'),
+            },
+            ch => prefix.push(ch),
+        }
+    }
+    prefix
+}
+
+/* A single-character matcher of a regex */
+#[derive(Clone)]
+enum Atom {
+    Any,
+    Char(char),
+    Class(Vec<(char, char)>, bool),     /* ranges, negated */
+}
+
+impl Atom {
+    fn matches(& self, ch: char) -> bool {
+        match self {
+            Atom::Any => true,
+            Atom::Char(c) => *c == ch,
+            Atom::Class(ranges, negated) =>
+                ranges.iter().any(|(lo, hi)| *lo <= ch && ch <= *hi) != *negated,
+        }
+    }
+}
+
+/* how many times an atom may repeat, where `x+` is kept as `xx*` */
+#[derive(Clone, Copy)]
+enum Repeat {
+    One,
+    ZeroOrOne,      /* ? */
+    ZeroOrMore,     /* * */
+}
+
+/*
+ * A regex with literals, `.`, classes like `[a-z]` and `[^0-9]`, the
+ * escapes \d \w \s, the quantifiers `*` `+` `?`, and the anchors `^` and
+ * `$`. It matches anywhere in the text unless anchored. Groups and
+ * alternation are not supported.
+ */
+pub struct Regex {
+    items: Vec<(Atom, Repeat)>,
+    anchored_start: bool,
+    anchored_end: bool,
+}
+
+impl Regex {
+    pub fn new(pattern: & str) -> Result<Regex, String> {
+        let mut chars: Vec<char> = pattern.chars().collect();
+        let anchored_start = chars.first() == Some(&'^');
+        if anchored_start {
+            chars.remove(0);
+        }
+        /* `\$` is a literal dollar, but `\\$` a backslash then the anchor */
+        let escapes = chars.iter().rev().skip(1)
+            .take_while(|ch| **ch == '\\').count();
+        let anchored_end = chars.last() == Some(&'$') && escapes % 2 == 0;
+        if anchored_end {
+            chars.pop();
+        }
+
+        let mut items: Vec<(Atom, Repeat)> = vec![];
+        let mut i = 0;
+        while i < chars.len() {
+            let atom = match chars[i] {
+                '.' => Atom::Any,
+                '\\' => {
+                    i += 1;
+                    match chars.get(i) {
+                        Some(ch) => escape_atom(*ch),
+                        None => return Err(String::from("trailing backslash")),
+                    }
+                },
+                '[' => {
+                    let (atom, end) = parse_class(&chars, i)?;
+                    i = end;
+                    atom
+                },
+                '*' | '+' | '?' =>
+                    return Err(format!("nothing to repeat at {}", i)),
+                '(' | ')' | '|' =>
+                    return Err(String::from("groups and alternation are not supported")),
+                ch => Atom::Char(ch),
+            };
+            i += 1;
+
+            match chars.get(i) {
+                Some('*') => items.push((atom, Repeat::ZeroOrMore)),
+                Some('+') => {
+                    items.push((atom.clone(), Repeat::One));
+                    items.push((atom, Repeat::ZeroOrMore));
+                },
+                Some('?') => items.push((atom, Repeat::ZeroOrOne)),
+                _ => {
+                    items.push((atom, Repeat::One));
+                    continue;
+                },
+            }
+            i += 1;
+        }
+
+        Ok(Regex {
+            items: items,
+            anchored_start: anchored_start,
+            anchored_end: anchored_end,
+        })
+    }
+
+    /*
+     * Runs the items as an automaton over the text, one character at a
+     * time, tracking every item a match could have reached at once. State
+     * i is about to match items[i], and the last state has matched them
+     * all. Time is the text times the pattern, with no backtracking.
+     */
+    pub fn is_match(& self, text: & str) -> bool {
+        let done = self.items.len();
+        let mut states = vec![false; done + 1];
+        states[0] = true;
+        self.skip_optional(&mut states);
+
+        for ch in text.chars() {
+            if states[done] && !self.anchored_end {
+                return true;
+            }
+            let mut next = vec![false; done + 1];
+            for (i, (atom, repeat)) in self.items.iter().enumerate() {
+                if states[i] && atom.matches(ch) {
+                    match repeat {
+                        Repeat::ZeroOrMore => next[i] = true,
+                        _ => next[i + 1] = true,
+                    }
+                }
+            }
+            /* unanchored matches may start at any character */
+            next[0] = next[0] || !self.anchored_start;
+            self.skip_optional(&mut next);
+            states = next;
+        }
+        states[done]
+    }
+
+    /* adds the states reached by matching optional items zero times */
+    fn skip_optional(& self, states: &mut Vec<bool>) {
+        for (i, (_, repeat)) in self.items.iter().enumerate() {
+            match repeat {
+                Repeat::ZeroOrOne | Repeat::ZeroOrMore if states[i] =>
+                    states[i + 1] = true,
+                _ => (),
+            }
+        }
+    }
+}
+
+fn escape_atom(ch: char) -> Atom {
+    match class_escape(ch) {
+        Some((ranges, negated)) => Atom::Class(ranges, negated),
+        None => Atom::Char(escape_char(ch)),
+    }
+}
+
+/* the ranges of \d \w \s, negated for \D \W \S */
+fn class_escape(ch: char) -> Option<(Vec<(char, char)>, bool)> {
+    let ranges = match ch.to_ascii_lowercase() {
+        'd' => vec![('0', '9')],
+        'w' => vec![('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')],
+        's' => vec![(' ', ' '), ('\t', '\r')],
+        _ => return None,
+    };
+    Some((ranges, ch.is_ascii_uppercase()))
+}
+
+/* the character an escape other than a class stands for */
+fn escape_char(ch: char) -> char {
+    match ch {
+        'n' => '\n',
+        't' => '\t',
+        ch => ch,
+    }
+}
+
+/* 
+ * Parses `[...]` starting at chars[start], returning the index of `]`. 
+ * \d \w and \s add their ranges, but their negations are not supported.
+ */
+fn parse_class(chars: & [char], start: usize) -> Result<(Atom, usize), String> {
+    let mut i = start + 1;
+    let negated = chars.get(i) == Some(&'^');
+    if negated {
+        i += 1;
+    }
+
+    let mut ranges = vec![];
+    let mut first = true;
+    loop {
+        let ch = match chars.get(i) {
+            Some(']') if !first => return Ok((Atom::Class(ranges, negated), i)),
+            Some('\\') => {
+                i += 1;
+                let ch = *chars.get(i)
+                    .ok_or(String::from("unterminated class"))?;
+                match class_escape(ch) {
+                    Some((_, true)) => 
+                        return Err(format!("\\{} is not supported in a class", 
+                                           ch)),
+                    Some((escaped, false)) => {
+                        ranges.extend(escaped);
+                        first = false;
+                        i += 1;
+                        continue;
+                    },
+                    None => escape_char(ch),
+                }
+            },
+            Some(ch) => *ch,
+            None => return Err(String::from("unterminated class")),
+        };
+        first = false;
+
+        if chars.get(i + 1) == Some(&'-') &&
+            chars.get(i + 2).map_or(false, |c| *c != ']') {
+            let hi = chars[i + 2];
+            if hi < ch {
+                return Err(format!("invalid range {}-{}", ch, hi));
+            }
+            ranges.push((ch, hi));
+            i += 3;
+        }
+        else {
+            ranges.push((ch, ch));
+            i += 1;
+        }
+    }
+}
@@ -0,0 +1,714 @@
+/*
+ * raft.rs
+ *
+ * Cluster mode: the nodes elect a leader, which appends every write to a
+ * consensus log (Raft) and answers it once a majority of nodes hold it.
+ * Reads are linearizable: only the leader answers them, once a majority
+ * of nodes confirm it still leads (a read index), so a read sees every
+ * write acknowledged before it, whichever node acknowledged it.
+ *
+ * University of Toronto
+ * 2019
+ */
+
+use config::Config;
+use database;
+use database::Database;
+use packet;
+use packet::{Request, Response};
+use raftlog::{Entry, RaftLog};
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::convert::TryInto;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/* how often a leader sends its followers heartbeats */
+const HEARTBEAT: Duration = Duration::from_millis(50);
+
+/*
+ * a node that hears from no leader for this long, and a random part of it
+ * again, starts an election
+ */
+const ELECTION_TIMEOUT: Duration = Duration::from_millis(300);
+
+/* how often the election timer is checked */
+const TICK: Duration = Duration::from_millis(10);
+
+/* time a peer gets to accept a connection or answer a message */
+const PEER_TIMEOUT: Duration = Duration::from_millis(500);
+
+/* a write not committed by then is answered with TIMEOUT */
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/* most entries sent in one append */
+const MAX_ENTRIES: usize = 256;
+
+/* largest message taken from a peer */
+const MAX_MESSAGE: usize = 64 * 1024 * 1024;
+
+/* message types */
+const VOTE: i32 = 1;
+const APPEND: i32 = 2;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/* Everything about a node that changes, under a single lock */
+struct State {
+    role: Role,
+    log: RaftLog,               /* with the current term and vote */
+    commit: i64,                /* last entry known to be committed */
+    applied: i64,               /* last entry applied to the database */
+    leader: usize,              /* node id, 0 if unknown */
+    leader_address: String,     /* where the leader's clients connect */
+    deadline: Instant,          /* when to start an election */
+    votes: Vec<usize>,          /* nodes that voted for this candidate */
+    /* by node id - 1 */
+    asked: Vec<i64>,            /* last term the node was asked to vote in */
+    next_index: Vec<i64>,       /* next entry the leader sends the node */
+    match_index: Vec<i64>,      /* last entry known to be on the node */
+    sent: Vec<Instant>,         /* when the leader last sent to the node */
+    acked: Vec<Instant>,        /* when the last message it answered was sent */
+    /* nodes last sent to before this are sent a heartbeat at once */
+    heartbeat_due: Instant,
+    /* writes of this node's clients, by index: their term, then answer */
+    waiting: HashMap<i64, i64>,
+    results: HashMap<i64, Response<'static>>,
+}
+
+pub struct Raft {
+    id: usize,                  /* from 1 */
+    nodes: Vec<String>,         /* peer address of every node, by id - 1 */
+    address: String,            /* where this node's clients connect */
+    db: Arc<Mutex<Database>>,
+    state: Mutex<State>,
+    /* signalled when the role, the log or the applied entries change */
+    changed: Condvar,
+}
+
+/*
+ * Loads the node's log from the data directory and joins the cluster: the
+ * node listens to its peers, replicates to them and holds elections on
+ * threads of its own, until `stopped`
+ */
+pub fn start(config: & Config, db: Arc<Mutex<Database>>,
+    stopped: fn() -> bool) -> io::Result<Arc<Raft>>
+{
+    let nodes = config.cluster_nodes();
+    let id = config.node_id;
+    let log = RaftLog::open(&config.data_dir, id)?;
+    let listener = TcpListener::bind(&nodes[id - 1])?;
+    info!("joining cluster"; node = id, address = &nodes[id - 1],
+          nodes = nodes.len(), term = log.term(), entries = log.last_index());
+
+    let count = nodes.len();
+    let raft = Arc::new(Raft {
+        id: id,
+        nodes: nodes,
+        address: config.address(),
+        db: db,
+        state: Mutex::new(State {
+            role: Role::Follower,
+            log: log,
+            commit: 0,
+            applied: 0,
+            leader: 0,
+            leader_address: String::new(),
+            deadline: election_deadline(),
+            votes: vec![],
+            asked: vec![0; count],
+            next_index: vec![1; count],
+            match_index: vec![0; count],
+            sent: vec![Instant::now(); count],
+            acked: vec![Instant::now(); count],
+            heartbeat_due: Instant::now(),
+            waiting: HashMap::new(),
+            results: HashMap::new(),
+        }),
+        changed: Condvar::new(),
+    });
+
+    let listening = raft.clone();
+    thread::spawn(move || listen(listening, listener));
+    let electing = raft.clone();
+    thread::spawn(move || elections(electing, stopped));
+    for peer in (1..count + 1).filter(|peer| *peer != id) {
+        let replicating = raft.clone();
+        thread::spawn(move || replicate(replicating, peer, stopped));
+    }
+    Ok(raft)
+}
+
+/* a new election timeout, random so that candidates rarely tie */
+fn election_deadline() -> Instant {
+    let random = RandomState::new().build_hasher().finish();
+    let jitter = random % ELECTION_TIMEOUT.as_millis() as u64;
+    Instant::now() + ELECTION_TIMEOUT + Duration::from_millis(jitter)
+}
+
+/* the answer to a write, which borrows nothing from the database */
+fn owned(response: Response) -> Response<'static> {
+    match response {
+        Response::Insert(id, version) => Response::Insert(id, version),
+        Response::Update(version) => Response::Update(version),
+        Response::Drop => Response::Drop,
+        Response::Violation(name) => Response::Violation(name),
+        response => Response::Error(response.code()),
+    }
+}
+
+impl Raft {
+    /*
+     * Appends a write to the log and answers it once it is committed and
+     * applied. Nodes other than the leader send the client to the leader.
+     */
+    pub fn submit(& self, request: Request) -> Response<'static> {
+        let command = match packet::encode_request(&request) {
+            Some(command) => command,
+            None => return Response::Error(Response::UNIMPLEMENTED),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        if state.role != Role::Leader {
+            return Response::NotLeader(state.leader_address.clone());
+        }
+        let term = state.log.term();
+        let entry = Entry { term: term, command: command };
+        if let Err(e) = state.log.append(&[entry]) {
+            error!("could not write to the log"; error = e.to_string());
+            return Response::Error(Response::TXN_ABORT);
+        }
+        let index = state.log.last_index();
+        state.match_index[self.id - 1] = index;
+        state.waiting.insert(index, term);
+        self.advance_commit(&mut state);
+        self.changed.notify_all();
+
+        let deadline = Instant::now() + COMMIT_TIMEOUT;
+        loop {
+            if let Some(response) = state.results.remove(&index) {
+                state.waiting.remove(&index);
+                return response;
+            }
+            /* a new leader replaced the entry, so the write never happened */
+            if state.log.term_at(index) != term || state.applied >= index {
+                state.waiting.remove(&index);
+                return Response::NotLeader(state.leader_address.clone());
+            }
+            /* it may still be committed, but the client cannot wait */
+            let now = Instant::now();
+            if now >= deadline {
+                state.waiting.remove(&index);
+                return Response::Error(Response::TIMEOUT);
+            }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    /*
+     * Waits until the database may answer a read, which is once this node
+     * leads, has applied an entry of its own term, and a majority of nodes
+     * have answered a message sent after the read arrived. No other leader
+     * can have committed a write by then that this node has not applied.
+     * Other nodes send the client to the leader, as their rows may be 
+     * behind.
+     */
+    pub fn read_index(& self) -> Result<(), Response<'static>> {
+        let mut state = self.state.lock().unwrap();
+        let term = state.log.term();
+        let arrived = Instant::now();
+        state.heartbeat_due = arrived;
+        self.changed.notify_all();
+
+        let deadline = arrived + COMMIT_TIMEOUT;
+        loop {
+            if state.role != Role::Leader || state.log.term() != term {
+                return Err(Response::NotLeader(state.leader_address.clone()));
+            }
+            let confirmed = state.acked.iter().enumerate()
+                .filter(|(node, acked)| *node == self.id - 1 || 
+                                        **acked >= arrived)
+                .count();
+            if confirmed * 2 > self.nodes.len() && 
+                state.log.term_at(state.applied) == term {
+                return Ok(());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Response::Error(Response::TIMEOUT));
+            }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    /* applies the committed entries not applied yet, in order */
+    fn apply(& self, state: &mut State) {
+        if state.applied >= state.commit {
+            return;
+        }
+        let mut db = self.db.lock().unwrap();
+        while state.applied < state.commit {
+            state.applied += 1;
+            let index = state.applied;
+            let entry = state.log.entry(index);
+            if entry.command.len() == 0 {
+                continue;
+            }
+            let response = match packet::decode_request(&entry.command) {
+                Ok(request) => {
+                    owned(database::handle_request(request, &mut db))
+                },
+                Err(e) => {
+                    error!("could not read log entry"; index = index,
+                           error = e.to_string());
+                    Response::Error(Response::BAD_REQUEST)
+                },
+            };
+            if state.waiting.get(&index) == Some(&entry.term) {
+                state.results.insert(index, response);
+            }
+        }
+        self.changed.notify_all();
+    }
+
+    /* commits the newest entry of this term that a majority of nodes hold */
+    fn advance_commit(& self, state: &mut State) {
+        let mut index = state.log.last_index();
+        let term = state.log.term();
+        while index > state.commit && state.log.term_at(index) == term {
+            let holders = state.match_index.iter()
+                .filter(|m| **m >= index).count();
+            if holders * 2 > self.nodes.len() {
+                state.commit = index;
+                self.apply(state);
+                return;
+            }
+            index -= 1;
+        }
+    }
+
+    fn start_election(& self, state: &mut State) -> io::Result<()> {
+        let term = state.log.term() + 1;
+        state.log.set_term(term, self.id)?;
+        debug!("starting election"; node = self.id, term = term);
+        state.role = Role::Candidate;
+        state.leader = 0;
+        state.leader_address.clear();
+        state.votes = vec![self.id];
+        state.deadline = election_deadline();
+        if self.nodes.len() == 1 {
+            self.become_leader(state)?;
+        }
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    /*
+     * Takes over as leader. Entries of earlier terms are only committed
+     * with one of the leader's own, so it starts its term with a no-op.
+     */
+    fn become_leader(& self, state: &mut State) -> io::Result<()> {
+        info!("elected leader"; node = self.id, term = state.log.term());
+        state.role = Role::Leader;
+        state.leader = self.id;
+        state.leader_address = self.address.clone();
+        let last = state.log.last_index();
+        for node in 0..self.nodes.len() {
+            state.next_index[node] = last + 1;
+            state.match_index[node] = 0;
+        }
+
+        let term = state.log.term();
+        state.log.append(&[Entry { term: term, command: vec![] }])?;
+        state.match_index[self.id - 1] = state.log.last_index();
+        self.advance_commit(state);
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    /* follows whoever leads `term`, which is at least the current term */
+    fn step_down(& self, state: &mut State, term: i64) -> io::Result<()> {
+        if term > state.log.term() {
+            state.log.set_term(term, 0)?;
+            state.leader = 0;
+            state.leader_address.clear();
+        }
+        if state.role == Role::Leader {
+            info!("stepping down"; node = self.id, term = term);
+        }
+        state.role = Role::Follower;
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    /* answers a candidate asking for this node's vote */
+    fn vote(& self, message: &mut Message) -> io::Result<Message> {
+        let term = message.get_i64()?;
+        let candidate = message.get_i64()? as usize;
+        let last_index = message.get_i64()?;
+        let last_term = message.get_i64()?;
+
+        let mut state = self.state.lock().unwrap();
+        if term > state.log.term() {
+            self.step_down(&mut state, term)?;
+        }
+        /* only a candidate with every committed entry may win */
+        let our_last = state.log.last_index();
+        let our_term = state.log.term_at(our_last);
+        let up_to_date = last_term > our_term ||
+            (last_term == our_term && last_index >= our_last);
+        let voted_for = state.log.voted_for();
+        let granted = term == state.log.term() && up_to_date &&
+            (voted_for == 0 || voted_for == candidate);
+        if granted {
+            state.log.set_term(term, candidate)?;
+            state.deadline = election_deadline();
+        }
+
+        let mut reply = Message::new();
+        reply.put_i64(state.log.term());
+        reply.put_i32(granted as i32);
+        Ok(reply)
+    }
+
+    /*
+     * Takes entries from the leader, after making sure the log matches
+     * the leader's up to them. Entries that conflict with the leader's are
+     * not committed, so they are dropped.
+     */
+    fn append(& self, message: &mut Message) -> io::Result<Message> {
+        let term = message.get_i64()?;
+        let leader = message.get_i64()? as usize;
+        let address = message.get_bytes()?;
+        let address = String::from_utf8_lossy(&address).to_string();
+        let prev_index = message.get_i64()?;
+        let prev_term = message.get_i64()?;
+        let commit = message.get_i64()?;
+        let mut entries = vec![];
+        for _ in 0..message.get_i32()? {
+            let entry_term = message.get_i64()?;
+            let command = message.get_bytes()?;
+            entries.push(Entry { term: entry_term, command: command });
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let mut reply = Message::new();
+        if term < state.log.term() {
+            reply.put_i64(state.log.term());
+            reply.put_i32(0);
+            reply.put_i64(0);
+            return Ok(reply);
+        }
+        if term > state.log.term() || state.role != Role::Follower {
+            self.step_down(&mut state, term)?;
+        }
+        state.deadline = election_deadline();
+        if state.leader != leader {
+            info!("following leader"; node = leader, term = term);
+            state.leader = leader;
+        }
+        state.leader_address = address;
+
+        /* the leader backs up to the hinted entry until the logs match */
+        let last = state.log.last_index();
+        if prev_index > last || state.log.term_at(prev_index) != prev_term {
+            reply.put_i64(term);
+            reply.put_i32(0);
+            reply.put_i64(prev_index.min(last + 1));
+            return Ok(reply);
+        }
+
+        let matched = prev_index + entries.len() as i64;
+        let mut new = vec![];
+        for (i, entry) in entries.into_iter().enumerate() {
+            let index = prev_index + 1 + i as i64;
+            if index <= state.log.last_index() {
+                if state.log.term_at(index) == entry.term {
+                    continue;
+                }
+                state.log.truncate(index)?;
+            }
+            new.push(entry);
+        }
+        if new.len() > 0 {
+            state.log.append(&new)?;
+        }
+
+        let commit = commit.min(matched);
+        if commit > state.commit {
+            state.commit = commit;
+            self.apply(&mut state);
+        }
+
+        reply.put_i64(term);
+        reply.put_i32(1);
+        reply.put_i64(matched);
+        Ok(reply)
+    }
+
+    /*
+     * Waits up to a heartbeat for `peer` to be due a message: entries or a
+     * heartbeat from a leader, or a vote request from a candidate. Returns
+     * it with its type and the term it was sent in.
+     */
+    fn next_message(& self, peer: usize) -> Option<(Message, i32, i64)> {
+        let mut state = self.state.lock().unwrap();
+        let node = peer - 1;
+        let term = state.log.term();
+        match state.role {
+            Role::Leader => {
+                let pending = state.next_index[node] <= state.log.last_index();
+                let due = state.sent[node] < state.heartbeat_due;
+                let since = state.sent[node].elapsed();
+                if pending || due || since >= HEARTBEAT {
+                    state.sent[node] = Instant::now();
+                    let message = self.append_message(&state, node);
+                    return Some((message, APPEND, term));
+                }
+                let _ = self.changed.wait_timeout(state, HEARTBEAT - since);
+            },
+            Role::Candidate if state.asked[node] < term => {
+                state.asked[node] = term;
+                let last = state.log.last_index();
+                let mut message = Message::new();
+                message.put_i32(VOTE);
+                message.put_i64(term);
+                message.put_i64(self.id as i64);
+                message.put_i64(last);
+                message.put_i64(state.log.term_at(last));
+                return Some((message, VOTE, term));
+            },
+            _ => {
+                let _ = self.changed.wait_timeout(state, HEARTBEAT);
+            },
+        }
+        None
+    }
+
+    fn append_message(& self, state: & State, node: usize) -> Message {
+        let next = state.next_index[node];
+        let mut message = Message::new();
+        message.put_i32(APPEND);
+        message.put_i64(state.log.term());
+        message.put_i64(self.id as i64);
+        message.put_bytes(self.address.as_bytes());
+        message.put_i64(next - 1);
+        message.put_i64(state.log.term_at(next - 1));
+        message.put_i64(state.commit);
+        let entries = state.log.entries_from(next, MAX_ENTRIES);
+        message.put_i32(entries.len() as i32);
+        for entry in entries {
+            message.put_i64(entry.term);
+            message.put_bytes(&entry.command);
+        }
+        message
+    }
+
+    /* takes a peer's answer to a message sent in `term` */
+    fn answered(& self, peer: usize, kind: i32, term: i64, reply: &mut Message)
+        -> io::Result<()>
+    {
+        let reply_term = reply.get_i64()?;
+        let mut state = self.state.lock().unwrap();
+        if reply_term > state.log.term() {
+            return self.step_down(&mut state, reply_term);
+        }
+        /* answers to an earlier term no longer count */
+        if term != state.log.term() {
+            return Ok(());
+        }
+
+        let node = peer - 1;
+        if kind == VOTE {
+            let granted = reply.get_i32()? == 1;
+            if granted && state.role == Role::Candidate &&
+                !state.votes.contains(&peer) {
+                state.votes.push(peer);
+                if state.votes.len() * 2 > self.nodes.len() {
+                    self.become_leader(&mut state)?;
+                }
+            }
+        }
+        else if state.role == Role::Leader {
+            /* the node still took this node for the leader when it was sent */
+            state.acked[node] = state.sent[node];
+            self.changed.notify_all();
+            let success = reply.get_i32()? == 1;
+            let index = reply.get_i64()?;
+            if success {
+                state.match_index[node] = state.match_index[node].max(index);
+                state.next_index[node] = state.match_index[node] + 1;
+                self.advance_commit(&mut state);
+            }
+            else {
+                state.next_index[node] = index.max(1);
+            }
+        }
+        Ok(())
+    }
+}
+
+/* answers other nodes, each connection on its own thread */
+fn listen(raft: Arc<Raft>, listener: TcpListener) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("could not accept peer"; error = e.to_string());
+                continue;
+            },
+        };
+        let raft = raft.clone();
+        thread::spawn(move || {
+            if let Err(e) = serve_peer(&raft, stream) {
+                debug!("peer connection closed"; error = e.to_string());
+            }
+        });
+    }
+}
+
+fn serve_peer(raft: & Raft, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    loop {
+        let mut message = Message::receive(&mut stream)?;
+        let reply = match message.get_i32()? {
+            VOTE => raft.vote(&mut message)?,
+            APPEND => raft.append(&mut message)?,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                           "unknown message type")),
+        };
+        reply.send(&mut stream)?;
+    }
+}
+
+/* starts an election whenever no leader was heard from in time */
+fn elections(raft: Arc<Raft>, stopped: fn() -> bool) {
+    while !stopped() {
+        thread::sleep(TICK);
+        let mut state = raft.state.lock().unwrap();
+        if state.role == Role::Leader || Instant::now() < state.deadline {
+            continue;
+        }
+        if let Err(e) = raft.start_election(&mut state) {
+            error!("could not start election"; error = e.to_string());
+        }
+    }
+}
+
+/* sends one peer whatever it is due, over a connection kept open */
+fn replicate(raft: Arc<Raft>, peer: usize, stopped: fn() -> bool) {
+    let mut stream: Option<TcpStream> = None;
+    while !stopped() {
+        let (message, kind, term) = match raft.next_message(peer) {
+            Some(next) => next,
+            None => continue,
+        };
+        let result = exchange(&mut stream, &raft.nodes[peer - 1], &message)
+            .and_then(|mut reply| raft.answered(peer, kind, term, &mut reply));
+        if let Err(e) = result {
+            debug!("could not reach peer"; node = peer, error = e.to_string());
+            stream = None;
+            thread::sleep(HEARTBEAT);
+        }
+    }
+}
+
+fn exchange(stream: &mut Option<TcpStream>, address: & str, message: & Message)
+    -> io::Result<Message>
+{
+    if stream.is_none() {
+        let addr = match address.to_socket_addrs()?.next() {
+            Some(addr) => addr,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound,
+                                              "unknown peer address")),
+        };
+        let connected = TcpStream::connect_timeout(&addr, PEER_TIMEOUT)?;
+        connected.set_read_timeout(Some(PEER_TIMEOUT))?;
+        connected.set_write_timeout(Some(PEER_TIMEOUT))?;
+        connected.set_nodelay(true)?;
+        *stream = Some(connected);
+    }
+    let connected = stream.as_mut().unwrap();
+    message.send(connected)?;
+    Message::receive(connected)
+}
+
+/* A message between nodes, sent as its size and then its fields */
+struct Message {
+    bytes: Vec<u8>,
+    pointer: usize,
+}
+
+impl Message {
+    fn new() -> Message {
+        Message { bytes: vec![], pointer: 0 }
+    }
+
+    fn put_i32(&mut self, value: i32) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn put_i64(&mut self, value: i64) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn put_bytes(&mut self, value: & [u8]) {
+        self.put_i32(value.len() as i32);
+        self.bytes.extend_from_slice(value);
+    }
+
+    fn take(&mut self, size: usize) -> io::Result<& [u8]> {
+        if self.pointer + size > self.bytes.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "truncated message"));
+        }
+        self.pointer += size;
+        Ok(&self.bytes[self.pointer - size..self.pointer])
+    }
+
+    fn get_i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn get_i64(&mut self) -> io::Result<i64> {
+        Ok(i64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn get_bytes(&mut self) -> io::Result<Vec<u8>> {
+        let size = self.get_i32()?;
+        if size < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "invalid size in message"));
+        }
+        Ok(self.take(size as usize)?.to_vec())
+    }
+
+    fn send(& self, stream: &mut TcpStream) -> io::Result<()> {
+        let mut packet = (self.bytes.len() as u32).to_be_bytes().to_vec();
+        packet.extend_from_slice(&self.bytes);
+        stream.write_all(&packet)
+    }
+
+    fn receive(stream: &mut TcpStream) -> io::Result<Message> {
+        let mut size = [0 as u8; 4];
+        stream.read_exact(&mut size)?;
+        let size = u32::from_be_bytes(size) as usize;
+        if size > MAX_MESSAGE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "message too large"));
+        }
+        let mut bytes = vec![0 as u8; size];
+        stream.read_exact(&mut bytes)?;
+        Ok(Message { bytes: bytes, pointer: 0 })
+    }
+}
@@ -0,0 +1,198 @@
+/*
+ * raftlog.rs
+ *
+ * The durable state of a cluster node: its consensus log, and the term
+ * and vote it must not forget across restarts
+ *
+ * University of Toronto
+ * 2019
+ */
+
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::PathBuf;
+
+/* A command in the log, with the term of the leader that received it */
+#[derive(Clone)]
+pub struct Entry {
+    pub term: i64,
+    pub command: Vec<u8>,       /* a write request, empty for a no-op */
+}
+
+/*
+ * The log is kept whole in memory and appended to a file, each entry as
+ * its term, size and command. It is never compacted, so a restarted node
+ * rebuilds its rows by applying it from the start.
+ */
+pub struct RaftLog {
+    entries: Vec<Entry>,        /* entry i is at index i + 1 */
+    offsets: Vec<u64>,          /* where each entry starts in the file */
+    file: File,
+    state_path: PathBuf,
+    term: i64,
+    voted_for: usize,           /* node id, 0 if none this term */
+}
+
+/* size of the term and size fields in front of each command */
+const HEADER: usize = mem::size_of::<i64>() + mem::size_of::<u32>();
+
+impl RaftLog {
+    /* opens the files of node `node_id` in `dir`, creating them if new */
+    pub fn open(dir: & str, node_id: usize) -> io::Result<RaftLog> {
+        let dir = PathBuf::from(dir);
+        let log_path = dir.join(format!("raft-{}.log", node_id));
+        let state_path = dir.join(format!("raft-{}.state", node_id));
+
+        let mut file = OpenOptions::new().read(true).append(true)
+            .create(true).open(&log_path)?;
+        let mut bytes = vec![];
+        file.read_to_end(&mut bytes)?;
+
+        let mut log = RaftLog {
+            entries: vec![],
+            offsets: vec![],
+            file: file,
+            state_path: state_path,
+            term: 0,
+            voted_for: 0,
+        };
+
+        let mut offset = 0;
+        while offset + HEADER <= bytes.len() {
+            let term = i64::from_be_bytes(field(&bytes[offset..]));
+            let size = u32::from_be_bytes(field(&bytes[offset + 8..])) as usize;
+            let end = offset + HEADER + size;
+            if end > bytes.len() {
+                break;
+            }
+            log.offsets.push(offset as u64);
+            log.entries.push(Entry {
+                term: term,
+                command: bytes[offset + HEADER..end].to_vec(),
+            });
+            offset = end;
+        }
+
+        /* a crash can leave the last entry half written, but never acked */
+        if offset < bytes.len() {
+            warn!("dropping partly written log entry";
+                  file = log_path.display().to_string(),
+                  bytes = bytes.len() - offset);
+            log.file.set_len(offset as u64)?;
+        }
+
+        match fs::read(&log.state_path) {
+            Ok(ref state) if state.len() == 16 => {
+                log.term = i64::from_be_bytes(field(&state[..]));
+                log.voted_for = i64::from_be_bytes(field(&state[8..])) as usize;
+            },
+            Ok(_) => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                "corrupt raft state file")),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        Ok(log)
+    }
+
+    pub fn term(& self) -> i64 {
+        self.term
+    }
+
+    pub fn voted_for(& self) -> usize {
+        self.voted_for
+    }
+
+    /* saves a new term, or a vote in the current one, before it is used */
+    pub fn set_term(&mut self, term: i64, voted_for: usize) -> io::Result<()> {
+        let mut state = Vec::with_capacity(16);
+        state.extend_from_slice(&term.to_be_bytes());
+        state.extend_from_slice(&(voted_for as i64).to_be_bytes());
+
+        /* replaced whole, so a crash leaves either the old or the new */
+        let temp = self.state_path.with_extension("state.tmp");
+        let mut file = File::create(&temp)?;
+        file.write_all(&state)?;
+        file.sync_all()?;
+        fs::rename(&temp, &self.state_path)?;
+
+        self.term = term;
+        self.voted_for = voted_for;
+        Ok(())
+    }
+
+    /* index of the last entry, 0 if the log is empty */
+    pub fn last_index(& self) -> i64 {
+        self.entries.len() as i64
+    }
+
+    /* term of the entry at `index`, 0 if there is none */
+    pub fn term_at(& self, index: i64) -> i64 {
+        if index < 1 || index > self.last_index() {
+            return 0;
+        }
+        self.entries[index as usize - 1].term
+    }
+
+    pub fn entry(& self, index: i64) -> & Entry {
+        &self.entries[index as usize - 1]
+    }
+
+    /* up to `max` entries from `index` on */
+    pub fn entries_from(& self, index: i64, max: usize) -> & [Entry] {
+        let start = (index.max(1) as usize - 1).min(self.entries.len());
+        let end = (start + max).min(self.entries.len());
+        &self.entries[start..end]
+    }
+
+    /* 
+     * Appends entries, and returns once they are on disk. A failed write
+     * leaves the log as it was, without part of an entry at its end.
+     */
+    pub fn append(&mut self, entries: & [Entry]) -> io::Result<()> {
+        let end = self.file.seek(SeekFrom::End(0))?;
+        let mut offset = end;
+        let mut offsets = vec![];
+        let mut bytes = vec![];
+        for entry in entries {
+            offsets.push(offset);
+            bytes.extend_from_slice(&entry.term.to_be_bytes());
+            let size = entry.command.len() as u32;
+            bytes.extend_from_slice(&size.to_be_bytes());
+            bytes.extend_from_slice(&entry.command);
+            offset += (HEADER + entry.command.len()) as u64;
+        }
+        let written = self.file.write_all(&bytes)
+            .and_then(|_| self.file.sync_data());
+        if let Err(e) = written {
+            let _ = self.file.set_len(end);
+            return Err(e);
+        }
+        self.offsets.extend(offsets);
+        self.entries.extend_from_slice(entries);
+        Ok(())
+    }
+
+    /* drops the entry at `index` and every one after it */
+    pub fn truncate(&mut self, index: i64) -> io::Result<()> {
+        if index < 1 || index > self.last_index() {
+            return Ok(());
+        }
+        let keep = index as usize - 1;
+        self.file.set_len(self.offsets[keep])?;
+        self.file.sync_data()?;
+        self.entries.truncate(keep);
+        self.offsets.truncate(keep);
+        Ok(())
+    }
+}
+
+/* the fixed size field at the start of `bytes` */
+fn field<T: Default + AsMut<[u8]>>(bytes: & [u8]) -> T {
+    let mut field = T::default();
+    let size = field.as_mut().len();
+    field.as_mut().copy_from_slice(&bytes[..size]);
+    field
+}
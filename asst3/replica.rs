@@ -0,0 +1,150 @@
+/*
+ * replica.rs
+ *
+ * Followers: servers that copy the rows of a leader, by loading a snapshot
+ * and then applying the changes in the leader's change log
+ *
+ * University of Toronto
+ * 2019
+ */
+
+use changelog::MAX_PAGE;
+use database;
+use database::Database;
+use packet::{Network, Response};
+use std::io;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/* how often a follower that has caught up asks for new changes */
+const POLL: Duration = Duration::from_millis(20);
+
+/* wait before reconnecting to a leader that failed */
+const RETRY: Duration = Duration::from_secs(1);
+
+/* a leader that does not answer for this long is taken to be gone */
+const LEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/* What a follower knows of its leader */
+pub struct Replica {
+    pub leader: String,         /* host:port */
+    pub leader_sequence: i64,   /* latest change the leader reported */
+    pub contact: Option<Instant>,   /* when the leader last answered */
+}
+
+impl Replica {
+    /* changes the leader has made that are not applied yet */
+    pub fn lag(& self, applied: i64) -> i64 {
+        (self.leader_sequence - applied).max(0)
+    }
+
+    /* milliseconds since the leader last answered, -1 if it never has */
+    pub fn since_contact(& self) -> i64 {
+        self.contact.map_or(-1, |contact| contact.elapsed().as_millis() as i64)
+    }
+}
+
+/* 
+ * Makes the database a follower of `leader`, and copies the leader on a
+ * thread of its own, reconnecting after failures until `stopped`. Only
+ * admins may take snapshots, so the follower authenticates with `token`.
+ */
+pub fn follow(leader: String, token: String, db: Arc<Mutex<Database>>, 
+    stopped: fn() -> bool) 
+{
+    db.lock().unwrap().replica = Some(Replica {
+        leader: leader.clone(),
+        leader_sequence: 0,
+        contact: None,
+    });
+    info!("following leader"; leader = &leader);
+
+    thread::spawn(move || {
+        while !stopped() {
+            if let Err(e) = replicate(&leader, &token, &db, stopped) {
+                warn!("replication failed, reconnecting"; leader = &leader, 
+                      error = e);
+                thread::sleep(RETRY);
+            }
+        }
+    });
+}
+
+/* copies the leader over one connection, until it fails or `stopped` */
+fn replicate(leader: &str, token: &str, db: &Mutex<Database>, 
+    stopped: fn() -> bool) -> Result<(), String>
+{
+    let mut stream = TcpStream::connect(leader).map_err(|e| e.to_string())?;
+    stream.set_read_timeout(Some(LEADER_TIMEOUT)).map_err(|e| e.to_string())?;
+    stream.set_write_timeout(Some(LEADER_TIMEOUT)).map_err(|e| e.to_string())?;
+    expect(stream.greeting())?;
+    expect(stream.request_auth(token))?;
+
+    let mut since = catch_up(&mut stream, leader, db)?;
+    while !stopped() {
+        let (cursor, latest, changes) = 
+            match stream.request_changes(since, MAX_PAGE as i32) {
+                Ok(Err(Response::TRUNCATED)) => {
+                    warn!("leader discarded changes not yet applied, \
+                           catching up again"; leader = leader, since = since);
+                    since = catch_up(&mut stream, leader, db)?;
+                    continue;
+                },
+                reply => expect(reply)?,
+            };
+        
+        let mut db = db.lock().unwrap();
+        for change in changes {
+            let sequence = change.sequence;
+            if let Err(code) = database::apply(&mut db, change) {
+                return Err(format!("could not apply change {}: {}", sequence,
+                                   Response::code_name(code)));
+            }
+        }
+        seen(&mut db, latest);
+        drop(db);
+        
+        since = cursor;
+        if cursor == latest {
+            thread::sleep(POLL);
+        }
+    }
+    Ok(())
+}
+
+/* replaces the follower's rows with a snapshot, returning its sequence */
+fn catch_up(stream: &mut TcpStream, leader: &str, db: &Mutex<Database>) 
+    -> Result<i64, String>
+{
+    let (sequence, rows) = expect(stream.request_snapshot())?;
+    let count = rows.len();
+    let mut db = db.lock().unwrap();
+    if let Err(code) = database::restore(&mut db, sequence, rows) {
+        return Err(format!("snapshot does not fit the schema: {}",
+                           Response::code_name(code)));
+    }
+    seen(&mut db, sequence);
+    info!("caught up from snapshot"; leader = leader, sequence = sequence, 
+          rows = count);
+    Ok(sequence)
+}
+
+/* notes that the leader answered, and the latest change it reported */
+fn seen(db: &mut Database, latest: i64) {
+    if let Some(ref mut replica) = db.replica {
+        replica.leader_sequence = latest;
+        replica.contact = Some(Instant::now());
+    }
+}
+
+/* an answer from the leader, with errors described for the log */
+fn expect<T>(reply: io::Result<Result<T, i32>>) -> Result<T, String> {
+    match reply {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(code)) => Err(format!("leader answered {}", 
+                                     Response::code_name(code))),
+        Err(e) => Err(e.to_string()),
+    }
+}
@@ -9,86 +9,265 @@
 
 use std::fs;
 use std::io;
+use std::mem;
 use std::fmt;
+use std::collections::HashMap;
 use packet::Value;
+use expr::{Check, Expr, Op};
+use decimal;
+use json;
 
+#[derive(Clone)]
 pub struct Column {
     pub c_name: String, /* column name */
     pub c_id: i32,      /* column id */
-    pub c_type: i32,    /* one of 4 native types */
+    pub c_type: i32,    /* one of the Value type codes */
     pub c_ref: i32,     /* table id */
+    pub c_not_null: bool,
+    pub c_unique: bool,
+    pub c_default: Value,   /* used when an insert leaves the column null */
+    pub c_variants: Vec<String>,    /* names of an enum, in order */
+    pub c_precision: u32,   /* digits of a decimal */
+    pub c_scale: u32,       /* digits of a decimal after the point */
+    pub c_element: i32,     /* type of the values in a list */
 }
 
 pub struct Table {
     pub t_name: String,
     pub t_id: i32,
     pub t_cols: Vec<Column>,
+    pub t_checks: Vec<Check>,
+    pub t_dropped: Vec<i32>,    /* ids of dropped columns, never reused */
 }
 
 impl Column {
-    fn new(cname: String, cid: i32, ctype: i32, cref: i32) -> Column {
+    pub fn new(cname: String, cid: i32, ctype: i32, cref: i32) -> Column {
         Column {
             c_name: cname,
             c_id:   cid,
             c_type: ctype,
             c_ref: cref,
+            c_not_null: false,
+            c_unique: false,
+            c_default: Value::Null,
+            c_variants: vec![],
+            c_precision: 0,
+            c_scale: 0,
+            c_element: Value::NULL,
         }
     }
     
+    /* type code of the values stored in the column */
+    pub fn value_type(& self) -> i32 {
+        match self.c_type {
+            Value::ENUM => Value::STRING,
+            c_type => c_type,
+        }
+    }
+    
+    /* position of an enum value in the declaration, which orders it */
+    pub fn variant_index(& self, value: & str) -> Option<usize> {
+        self.c_variants.iter().position(|v| v == value)
+    }
+    
     fn type_as_str(& self) -> String {
         match self.c_type {
-            Value::INTEGER => String::from("integer"),
-            Value::FLOAT => String::from("float"),
-            Value::STRING => String::from("string"),
             Value::FOREIGN => format!("foreign({})", self.c_ref),
-            _ => String::from("unknown"),
+            Value::ENUM => format!("enum({})", self.c_variants.join(", ")),
+            Value::DECIMAL => 
+                format!("decimal({},{})", self.c_precision, self.c_scale),
+            Value::LIST => format!("list<{}>", type_name(self.c_element)),
+            c_type => type_name(c_type).to_string(),
         }
     }
 }
 
+/* name of a type code, as written in the schema */
+pub fn type_name(c_type: i32) -> &'static str {
+    match c_type {
+        Value::NULL => "null",
+        Value::INTEGER => "integer",
+        Value::FLOAT => "float",
+        Value::STRING => "string",
+        Value::FOREIGN => "foreign",
+        Value::BOOLEAN => "boolean",
+        Value::TIMESTAMP => "timestamp",
+        Value::BYTES => "bytes",
+        Value::ENUM => "enum",
+        Value::DECIMAL => "decimal",
+        Value::LIST => "list",
+        Value::JSON => "json",
+        _ => "unknown",
+    }
+}
+
 impl Table {
-    fn new(name: String, tid: i32, tcols: Vec<Column>) -> Table {
+    pub fn new(name: String, tid: i32, tcols: Vec<Column>) -> Table {
         Table {
             t_name: name,
             t_id: tid,
             t_cols: tcols,
+            t_checks: vec![],
+            t_dropped: vec![],
         }
     }
+    
+    /* id for a new column, after every id the table has used */
+    pub fn next_column_id(& self) -> i32 {
+        let used = self.t_cols.iter().map(|c| c.c_id)
+            .chain(self.t_dropped.iter().cloned());
+        used.max().unwrap_or(0) + 1
+    }
 }
 
-/* Returns each string in a vector from the schema file */
-pub fn tokenize(filename: &String) -> io::Result<Vec<String>> {
-    let contents = fs::read_to_string(filename)?;
-    let mut tokens = vec![];
-    let mut token = String::new();
+#[derive(Clone, Copy, PartialEq)]
+pub enum TokenKind {
+    Ident,
+    Number,
+    Str,
+    Symbol,
+}
 
-    for ch in contents.chars() {
-        if ch.is_alphabetic() {
-            token.push(ch);
-        }
-        else if ch.is_numeric() || ch == '_' {
-            if token.len() == 0 {
-                return Err(io::Error::new(io::ErrorKind::Other, 
-                    "invalid identifier, cannot start with a number or underscore"));
+/* A token of the schema file with the position it starts at */
+#[derive(Clone)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub text: String,
+    pub line: usize,    /* 1-based */
+    pub col: usize,     /* 1-based, in characters */
+}
+
+/* A problem found in the schema file */
+pub struct Diagnostic {
+    pub line: usize,
+    pub col: usize,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn at(token: & Token, message: String) -> Diagnostic {
+        Diagnostic { line: token.line, col: token.col, message: message }
+    }
+}
+
+/* Why a schema file could not be loaded */
+pub enum SchemaError {
+    Io(String, io::Error),
+    /* file name, file contents and every problem found in it */
+    Invalid(String, String, Vec<Diagnostic>),
+}
+
+/* Reads and parses a schema file */
+pub fn load(filename: & String) -> Result<Vec<Table>, SchemaError> {
+    let contents = match fs::read_to_string(filename) {
+        Ok(contents) => contents,
+        Err(e) => return Err(SchemaError::Io(filename.clone(), e)),
+    };
+
+    let (tokens, mut errors) = tokenize(&contents);
+    let end = end_position(&contents);
+    match parse(tokens, end) {
+        Ok(tables) if errors.len() == 0 => Ok(tables),
+        Ok(_) => Err(SchemaError::Invalid(filename.clone(), contents, errors)),
+        Err(parse_errors) => {
+            errors.extend(parse_errors);
+            errors.sort_by_key(|d| (d.line, d.col));
+            Err(SchemaError::Invalid(filename.clone(), contents, errors))
+        },
+    }
+}
+
+/* line and column just past the last character, for end of file errors */
+fn end_position(contents: & str) -> (usize, usize) {
+    let lines: Vec<&str> = contents.split('\n').collect();
+    (lines.len(), lines[lines.len() - 1].chars().count() + 1)
+}
+
+/* 
+ * Splits the schema source into tokens. Comments start with `#` or `//`
+ * and run to the end of the line.
+ */
+pub fn tokenize(contents: & str) -> (Vec<Token>, Vec<Diagnostic>) {
+    let mut tokens = vec![];
+    let mut errors = vec![];
+    
+    for (line_index, line) in contents.lines().enumerate() {
+        let chars: Vec<char> = line.chars().collect();
+        let mut i = 0;
+        
+        while i < chars.len() {
+            let ch = chars[i];
+            let start = i;
+            let mut token = Token {
+                kind: TokenKind::Symbol,
+                text: String::new(),
+                line: line_index + 1,
+                col: start + 1,
+            };
+            
+            if ch.is_whitespace() {
+                i += 1;
+                continue;
             }
-            token.push(ch);    
-        }
-        else {
-            if token.len() > 0 {
-                tokens.push(token);
-                token = String::new();
+            else if ch == '#' || (ch == '/' && chars.get(i + 1) == Some(&'/')) {
+                break;
             }
-            
-            if !ch.is_whitespace() {
-                tokens.push(ch.to_string());
+            else if ch.is_alphabetic() {
+                while i < chars.len() && 
+                    (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                token.kind = TokenKind::Ident;
             }
+            else if ch.is_ascii_digit() {
+                while i < chars.len() && 
+                    (chars[i].is_alphanumeric() || chars[i] == '_' 
+                     || chars[i] == '.') {
+                    i += 1;
+                }
+                token.kind = TokenKind::Number;
+            }
+            else if ch == '_' {
+                while i < chars.len() && 
+                    (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                errors.push(Diagnostic::at(&token, String::from(
+                    "invalid identifier, cannot start with a number or underscore")));
+                /* keep it as a name so parsing carries on normally */
+                token.kind = TokenKind::Ident;
+            }
+            else if ch == '"' {
+                i += 1;
+                while i < chars.len() && chars[i] != '"' {
+                    i += if chars[i] == '\\' { 2 } else { 1 };
+                }
+                if i >= chars.len() {
+                    errors.push(Diagnostic::at(&token, 
+                        String::from("unterminated string")));
+                    break;
+                }
+                i += 1;
+                token.kind = TokenKind::Str;
+            }
+            else if i + 1 < chars.len() && 
+                ["<=", ">=", "!=", "==", "&&", "||"].iter().any(|op| 
+                    op.starts_with(ch) && op.ends_with(chars[i + 1])) {
+                i += 2;
+            }
+            else {
+                i += 1;
+            }
+            
+            token.text = chars[start..i].iter().collect();
+            tokens.push(token);
         }
     }
 
-    Ok(tokens)
+    (tokens, errors)
 }
 
-fn validate_name(name: & String) -> Result<& String, &'static str>
+pub fn validate_name(name: & String) -> Result<& String, &'static str>
 {
     for ch in name.chars() {
         if !ch.is_alphanumeric() && ch != '_' {
@@ -99,135 +278,845 @@ fn validate_name(name: & String) -> Result<& String, &'static str>
 }
 
 /* 
- * Parses one column in the schema, and returns the initialized column 
- * if well formed, else return an error 
+ * Returns the type code and referenced table id for a column type name,
+ * which is either a native type or the name of a table 
  */
-fn parse_column<'a, I>(it: &mut I, column_id: i32, tables: & Vec<Table>)
-    -> Result<Option<Column>, &'static str> 
-    where I: Iterator<Item = &'a String>,
+pub fn column_type_of(type_name: & String, tables: & Vec<Table>)
+    -> Result<(i32, i32), &'static str>
 {
-    /* Check for ending curly bracket */
-    let column_name = match it.next() {
-        Some(column_name) => match column_name.as_str() {
-            "}" => return Ok(None),
-            _ => validate_name(column_name)?,
-        },
-        None => return Err("unexpected end of file"),
-    };
+    Ok(match type_name.as_str() {
+        "integer" => (Value::INTEGER, 0),
+        "float" => (Value::FLOAT, 0),
+        "string" => (Value::STRING, 0),
+        "boolean" => (Value::BOOLEAN, 0),
+        "timestamp" => (Value::TIMESTAMP, 0),
+        "bytes" => (Value::BYTES, 0),
+        "json" => (Value::JSON, 0),
+        _ => {
+            match tables.iter().find(|t| type_name.as_str() == t.t_name) {
+                Some(table) => (Value::FOREIGN, table.t_id),
+                None => return Err("cannot find reference table"),
+            }
+        }
+    })
+}
 
-    /* Check for colon after the column name */
-    match it.next() {
-        Some(tok) => match tok.as_str() {
-            ":" => (), 
-            _ => return Err("expecting ':' after column name"),
+/* Recursive descent parser over the tokens, collecting every error */
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    end: Token,                 /* stands in for the end of the file */
+    errors: Vec<Diagnostic>,
+}
+
+impl Parser {
+    fn peek(& self) -> & Token {
+        self.tokens.get(self.pos).unwrap_or(&self.end)
+    }
+    
+    fn at_end(& self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+    
+    fn next(&mut self) -> Result<Token, Diagnostic> {
+        match self.tokens.get(self.pos) {
+            Some(token) => {
+                self.pos += 1;
+                Ok(token.clone())
+            },
+            None => Err(Diagnostic::at(&self.end, 
+                                       String::from("unexpected end of file"))),
         }
-        None => return Err("unexpected end of file"),
-    };
+    }
     
-    let column_type = match it.next() {
-        Some(column_type) => column_type,
-        None => return Err("unexpected end of file"),
-    };
+    /* 
+     * consumes the symbol `text`, or fails with message pointing just
+     * past the previous token, where the symbol was expected 
+     */
+    fn expect(&mut self, text: &str, message: &str) -> Result<Token, Diagnostic> {
+        let token = self.peek().clone();
+        if token.kind == TokenKind::Symbol && token.text == text {
+            self.pos += 1;
+            return Ok(token);
+        }
+        
+        let mut diagnostic = Diagnostic::at(&token, message.to_string());
+        if self.pos > 0 {
+            let prev = &self.tokens[self.pos - 1];
+            diagnostic.line = prev.line;
+            diagnostic.col = prev.col + prev.text.chars().count();
+        }
+        Err(diagnostic)
+    }
+    
+    fn name(&mut self) -> Result<Token, Diagnostic> {
+        let token = self.next()?;
+        if token.kind != TokenKind::Ident {
+            self.pos -= 1;
+            return Err(Diagnostic::at(&token, 
+                                      String::from("invalid name identifier")));
+        }
+        Ok(token)
+    }
+    
+    /* consumes the next token if it is one of the symbols or keywords */
+    fn accept(&mut self, texts: &[&str]) -> Option<Token> {
+        let token = self.peek().clone();
+        if (token.kind == TokenKind::Symbol || token.kind == TokenKind::Ident)
+            && texts.contains(&token.text.as_str()) {
+            self.pos += 1;
+            return Some(token);
+        }
+        None
+    }
+    
+    /* skips past the next `stop` symbol, or up to `before` if it comes first */
+    fn recover(&mut self, stop: &str, before: &str) {
+        while !self.at_end() {
+            let token = self.peek().clone();
+            if token.kind == TokenKind::Symbol && token.text == before {
+                return;
+            }
+            self.pos += 1;
+            if token.kind == TokenKind::Symbol && token.text == stop {
+                return;
+            }
+        }
+    }
+}
 
-    /* Check for semi colon after each column */               
-    match it.next() {
-        Some(tok) => match tok.as_str() {
-            ";" => (), 
-            _ => return Err("expecting ';' after column type"),
+/* 
+ * Parses one column in the schema, and returns the column along with the
+ * token naming its type if well formed, else return an error. The type is
+ * resolved once all tables are parsed.
+ */
+fn parse_column(p: &mut Parser, column_id: i32)
+    -> Result<(Column, Token, Option<Token>), Diagnostic> 
+{
+    let column_name = p.name()?;
+    
+    /* Check for colon after the column name */
+    p.expect(":", "expecting ':' after column name")?;
+    
+    let column_type = p.next()?;
+    if column_type.kind != TokenKind::Ident {
+        p.pos -= 1;
+        return Err(Diagnostic::at(&column_type, 
+                                  String::from("expecting a column type")));
+    }
+    
+    let mut column = Column::new(column_name.text, column_id, Value::NULL, 0);
+    let mut default = None;
+    
+    /* 
+     * enums, decimals and lists are parameterized, and need no resolving
+     * later 
+     */
+    if column_type.text == "enum" {
+        p.expect("(", "expecting '(' after enum")?;
+        column.c_type = Value::ENUM;
+        column.c_variants = parse_variants(p)?;
+    }
+    else if column_type.text == "decimal" {
+        p.expect("(", "expecting '(' after decimal")?;
+        let (precision, scale) = parse_precision(p)?;
+        column.c_type = Value::DECIMAL;
+        column.c_precision = precision;
+        column.c_scale = scale;
+    }
+    else if column_type.text == "list" {
+        p.expect("<", "expecting '<' after list")?;
+        let element = p.next()?;
+        column.c_type = Value::LIST;
+        column.c_element = match column_type_of(&element.text, &vec![]) {
+            Ok((c_type, _)) if element.kind == TokenKind::Ident 
+                && c_type != Value::JSON => c_type,
+            _ => {
+                p.pos -= 1;
+                return Err(Diagnostic::at(&element, String::from(
+                    "list elements must be integer, float, string, \
+                     boolean, timestamp or bytes")));
+            },
+        };
+        p.expect(">", "expecting '>' after list element type")?;
+    }
+    
+    /* Constraints follow the type in any order */
+    loop {
+        let token = p.peek().clone();
+        if token.kind != TokenKind::Ident {
+            break;
+        }
+        let duplicate = match token.text.as_str() {
+            "not" => {
+                p.pos += 1;
+                let null = p.next()?;
+                if null.kind != TokenKind::Ident || null.text != "null" {
+                    p.pos -= 1;
+                    return Err(Diagnostic::at(&null, 
+                        String::from("expecting 'null' after 'not'")));
+                }
+                mem::replace(&mut column.c_not_null, true)
+            },
+            "unique" => {
+                p.pos += 1;
+                mem::replace(&mut column.c_unique, true)
+            },
+            "default" => {
+                p.pos += 1;
+                let literal = parse_literal(p)?;
+                default.replace(literal).is_some()
+            },
+            _ => break,
+        };
+        if duplicate {
+            p.errors.push(Diagnostic::at(&token, 
+                format!("duplicate '{}' constraint", token.text)));
         }
-        None => return Err("unexpected end of file"),
-    };                        
+    }
+
+    /* 
+     * Check for semi colon after each column. The column itself is fine,
+     * so keep it and carry on with the next one.
+     */
+    if let Err(e) = p.expect(";", "expecting ';' after column type") {
+        p.errors.push(e);
+    }
     
     /* Parse one column and return */
-    let column = match column_type.as_str() {
-        "integer" => 
-            Column::new(column_name.to_string(), column_id, Value::INTEGER, 0),
-        "float" =>
-            Column::new(column_name.to_string(), column_id, Value::FLOAT, 0),
-        "string" =>
-            Column::new(column_name.to_string(), column_id, Value::STRING, 0),
+    Ok((column, column_type, default))
+}
+
+/* Parses the comma separated values of an enum, after the '(' */
+fn parse_variants(p: &mut Parser) -> Result<Vec<String>, Diagnostic> {
+    let mut variants: Vec<String> = vec![];
+    loop {
+        let token = p.next()?;
+        let variant = match token.kind {
+            TokenKind::Ident => token.text.clone(),
+            TokenKind::Str => unquote(&token.text),
+            _ => {
+                p.pos -= 1;
+                return Err(Diagnostic::at(&token, 
+                                          String::from("expecting an enum value")));
+            },
+        };
+        if variants.contains(&variant) {
+            p.errors.push(Diagnostic::at(&token, 
+                format!("duplicate enum value '{}'", variant)));
+        }
+        else {
+            variants.push(variant);
+        }
+        
+        if p.accept(&[","]).is_none() {
+            break;
+        }
+    }
+    p.expect(")", "expecting ',' or ')' after enum value")?;
+    Ok(variants)
+}
+
+/* Parses `p,s)` of a decimal, after the '(' */
+fn parse_precision(p: &mut Parser) -> Result<(u32, u32), Diagnostic> {
+    let mut numbers: Vec<u32> = vec![];
+    for separator in [",", ")"].iter() {
+        let token = p.next()?;
+        match token.text.parse::<u32>() {
+            Ok(n) if token.kind == TokenKind::Number => numbers.push(n),
+            _ => {
+                p.pos -= 1;
+                return Err(Diagnostic::at(&token, 
+                                          String::from("expecting a number")));
+            },
+        }
+        p.expect(separator, &format!("expecting '{}'", separator))?;
+    }
+    
+    let (precision, scale) = (numbers[0], numbers[1]);
+    if precision < 1 || precision > decimal::MAX_PRECISION {
+        return Err(Diagnostic::at(&p.tokens[p.pos - 4], format!(
+            "decimal precision must be from 1 to {}", decimal::MAX_PRECISION)));
+    }
+    if scale > precision {
+        return Err(Diagnostic::at(&p.tokens[p.pos - 2], String::from(
+            "decimal scale cannot exceed its precision")));
+    }
+    Ok((precision, scale))
+}
+
+/* 
+ * Parses the literal of a default: a number, which may be negative, a 
+ * quoted string, or a name such as true, false, null or an enum value
+ */
+fn parse_literal(p: &mut Parser) -> Result<Token, Diagnostic> {
+    let mut token = p.next()?;
+    if token.kind == TokenKind::Symbol && token.text == "-" {
+        let number = p.next()?;
+        if number.kind != TokenKind::Number {
+            p.pos -= 1;
+            return Err(Diagnostic::at(&number, 
+                String::from("expecting a number after '-'")));
+        }
+        token.kind = TokenKind::Number;
+        token.text.push_str(&number.text);
+    }
+    
+    match token.kind {
+        TokenKind::Number | TokenKind::Str | TokenKind::Ident => Ok(token),
         _ => {
-            let index = match tables.iter().position(|t| 
-                column_type.as_str() == t.t_name) {
-                Some(index) => index,   
-                None => return Err("cannot find reference table"),
+            p.pos -= 1;
+            Err(Diagnostic::at(&token, 
+                               String::from("expecting a default value")))
+        },
+    }
+}
+
+/* Converts a default literal to a value of the column type */
+fn literal_value(literal: & Token, column: & Column) 
+    -> Result<Value, &'static str> 
+{
+    let text = literal.text.as_str();
+    if literal.kind == TokenKind::Ident && text == "null" {
+        return Ok(Value::Null);
+    }
+    
+    let value = match (column.c_type, &literal.kind) {
+        (Value::INTEGER, TokenKind::Number) => 
+            text.parse().ok().map(Value::Integer),
+        (Value::FLOAT, TokenKind::Number) => 
+            text.parse().ok().map(Value::Float),
+        (Value::TIMESTAMP, TokenKind::Number) => 
+            text.parse().ok().map(Value::Timestamp),
+        (Value::BOOLEAN, TokenKind::Ident) if text == "true" => 
+            Some(Value::Boolean(true)),
+        (Value::BOOLEAN, TokenKind::Ident) if text == "false" => 
+            Some(Value::Boolean(false)),
+        (Value::DECIMAL, TokenKind::Number) => {
+            decimal::parse(text).and_then(|(val, scale)| 
+                decimal::rescale(val, scale, column.c_scale))
+                .filter(|val| decimal::fits(*val, column.c_precision))
+                .map(|val| Value::Decimal(val, column.c_scale))
+        },
+        (Value::ENUM, TokenKind::Ident) | (Value::ENUM, TokenKind::Str) => {
+            let variant = match literal.kind {
+                TokenKind::Str => unquote(text),
+                _ => text.to_string(),
             };
-            Column::new(column_name.to_string(), column_id, Value::FOREIGN,
-                index as i32 + 1)
-        }
+            column.variant_index(&variant).map(|_| Value::Text(variant))
+        },
+        (Value::STRING, TokenKind::Str) => 
+            Some(Value::Text(unquote(text))),
+        (Value::BYTES, TokenKind::Str) => 
+            Some(Value::Bytes(unquote(text).into_bytes())),
+        (Value::JSON, TokenKind::Str) => {
+            let document = unquote(text);
+            json::parse(&document).ok().map(|_| Value::Json(document))
+        },
+        _ => None,
     };
-    
-    Ok(Some(column))
+    value.ok_or("default does not match the column type")
+}
+
+/* Sets the default of a column once its type is known */
+fn resolve_default(literal: & Token, column: &mut Column) 
+    -> Result<(), Diagnostic> 
+{
+    match literal_value(literal, column) {
+        Ok(Value::Null) if column.c_not_null => 
+            Err(Diagnostic::at(literal, String::from(
+                "not null column cannot default to null"))),
+        Ok(value) => {
+            column.c_default = value;
+            Ok(())
+        },
+        Err(e) => Err(Diagnostic::at(literal, e.to_string())),
+    }
+}
+
+/* contents of a quoted string literal, with escapes resolved */
+fn unquote(text: & str) -> String {
+    let mut out = String::new();
+    let mut chars = text[1..text.len() - 1].chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => match chars.next() {
+                Some('n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                Some(c) => out.push(c),
+                None => (),
+            },
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/* Parts of a table that can only be checked once every table is known */
+struct Pending {
+    types: Vec<(Token, Option<Token>)>,     /* column type and default */
+    checks: Vec<Token>,                     /* `check` keyword of each */
 }
 
-/* Parses a single table with columns, and returns the initialized table */
-fn parse_table<'a, I>(it: &mut I, tables: & Vec<Table>) 
-    -> Result<Option<Table>, &'static str>
-    where I: Iterator<Item = &'a String>,
+/* 
+ * Parses a single table with columns and checks, and returns the 
+ * initialized table with the tokens still to be resolved. Errors within 
+ * a column are recorded and parsing resumes at the next column.
+ */
+fn parse_table(p: &mut Parser, table_id: i32) 
+    -> Result<(Table, Pending), Diagnostic>
 {
     let mut columns: Vec<Column> = vec![];
+    let mut pending = Pending { types: vec![], checks: vec![] };
+    let mut check_starts: Vec<usize> = vec![];
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    let errors = p.errors.len();
     
     /* Check for a valid table name */
-    let table_name = match it.next() {
-        Some(table_name) => validate_name(table_name)?,
-        None => return Ok(None),
-    };
+    let table_name = p.name()?;
 
     /* Check for curly brackets and columns inside table */
-    match it.next() {
-        Some(tok) => match tok.as_str() {
-            "{" => (), 
-            _ => return Err("expecting '{' after table name"),
-        },
-        None => return Err("unexpected end of file"),
-    };
+    p.expect("{", "expecting '{' after table name")?;
     
     loop {
+        let token = p.peek().clone();
+        if token.kind == TokenKind::Symbol && token.text == "}" {
+            p.pos += 1;
+            break;
+        }
+        if p.at_end() {
+            return Err(Diagnostic::at(&token, 
+                                      String::from("unexpected end of file")));
+        }
+        
+        /* 
+         * checks may name columns declared after them, so they are parsed
+         * once the table is done. A column called check is still allowed.
+         */
+        let after = p.tokens.get(p.pos + 1).map(|t| t.text.as_str());
+        if token.kind == TokenKind::Ident && token.text == "check" 
+            && after != Some(":") {
+            check_starts.push(p.pos);
+            p.recover(";", "}");
+            continue;
+        }
+        
         let column_id = columns.len() as i32 + 1;
-        let column = match parse_column(it, column_id, tables)? {
-            Some(column) => column,
-            None => break,
-        };    
-        columns.push(column);
+        match parse_column(p, column_id) {
+            Ok((column, type_name, default)) => {
+                if let Some(line) = seen.get(&column.c_name) {
+                    p.errors.push(Diagnostic::at(&token, format!(
+                        "duplicate column '{}' (first defined on line {})",
+                        column.c_name, line)));
+                    continue;
+                }
+                seen.insert(column.c_name.clone(), token.line);
+                columns.push(column);
+                pending.types.push((type_name, default));
+            },
+            Err(e) => {
+                p.errors.push(e);
+                p.recover(";", "}");
+            },
+        }
     }
     
-    if columns.len() == 0 {
-        Err("table has no column")
+    /* a table whose columns all failed to parse was already reported */
+    if columns.len() == 0 && p.errors.len() == errors {
+        return Err(Diagnostic::at(&table_name, 
+                                  String::from("table has no column")));
     }
-    else {
-        let table_id = tables.len() as i32 + 1;
-        Ok(Some(Table::new(table_name.to_string(), table_id, columns)))
+    
+    let mut table = Table::new(table_name.text, table_id, columns);
+    let end = p.pos;
+    for start in check_starts {
+        p.pos = start;
+        pending.checks.push(p.peek().clone());
+        match parse_check(p, &table.t_cols) {
+            Ok(check) => table.t_checks.push(check),
+            Err(e) => {
+                pending.checks.pop();
+                p.errors.push(e);
+            },
+        }
+    }
+    p.pos = end;
+    
+    Ok((table, pending))
+}
+
+/* 
+ * Parses `check [name] (expression);`. An unnamed check is named after
+ * its expression.
+ */
+fn parse_check(p: &mut Parser, columns: & Vec<Column>) 
+    -> Result<Check, Diagnostic> 
+{
+    p.next()?;
+    let name = match p.peek().kind {
+        TokenKind::Ident => Some(p.next()?.text),
+        _ => None,
+    };
+    
+    p.expect("(", "expecting '(' after check")?;
+    let expr = parse_or(p, columns)?;
+    p.expect(")", "expecting ')' after check expression")?;
+    if let Err(e) = p.expect(";", "expecting ';' after check") {
+        p.errors.push(e);
+    }
+    
+    Ok(Check {
+        name: name.unwrap_or_else(|| expr.format(columns)),
+        expr: expr,
+    })
+}
+
+/* Checks that a check, starting at `keyword`, is a boolean expression */
+fn check_type(check: & Check, columns: & Vec<Column>, keyword: & Token)
+    -> Result<(), Diagnostic>
+{
+    match check.expr.type_of(columns) {
+        Ok(Value::BOOLEAN) | Ok(Value::NULL) => Ok(()),
+        Ok(_) => Err(Diagnostic::at(keyword, String::from(
+            "check must be a boolean expression"))),
+        Err(e) => Err(Diagnostic::at(keyword, e)),
+    }
+}
+
+/* 
+ * Check expressions, from the lowest precedence:
+ *     or, ||
+ *     and, &&
+ *     not, !
+ *     == = != < > <= >=
+ *     + -
+ *     * / %
+ *     unary -
+ */
+fn parse_or(p: &mut Parser, columns: & Vec<Column>) -> Result<Expr, Diagnostic> {
+    let mut expr = parse_and(p, columns)?;
+    while p.accept(&["or", "||"]).is_some() {
+        let right = parse_and(p, columns)?;
+        expr = Expr::Binary(Op::Or, Box::new(expr), Box::new(right));
+    }
+    Ok(expr)
+}
+
+fn parse_and(p: &mut Parser, columns: & Vec<Column>) -> Result<Expr, Diagnostic> {
+    let mut expr = parse_not(p, columns)?;
+    while p.accept(&["and", "&&"]).is_some() {
+        let right = parse_not(p, columns)?;
+        expr = Expr::Binary(Op::And, Box::new(expr), Box::new(right));
+    }
+    Ok(expr)
+}
+
+fn parse_not(p: &mut Parser, columns: & Vec<Column>) -> Result<Expr, Diagnostic> {
+    if p.accept(&["not", "!"]).is_some() {
+        let operand = parse_not(p, columns)?;
+        return Ok(Expr::Unary(Op::Not, Box::new(operand)));
+    }
+    parse_comparison(p, columns)
+}
+
+fn parse_comparison(p: &mut Parser, columns: & Vec<Column>) 
+    -> Result<Expr, Diagnostic> 
+{
+    let expr = parse_sum(p, columns)?;
+    let op = match p.accept(&["==", "=", "!=", "<", ">", "<=", ">="]) {
+        Some(token) => match token.text.as_str() {
+            "==" | "=" => Op::Eq,
+            "!=" => Op::Ne,
+            "<" => Op::Lt,
+            ">" => Op::Gt,
+            "<=" => Op::Le,
+            _ => Op::Ge,
+        },
+        None => return Ok(expr),
+    };
+    let right = parse_sum(p, columns)?;
+    Ok(Expr::Binary(op, Box::new(expr), Box::new(right)))
+}
+
+fn parse_sum(p: &mut Parser, columns: & Vec<Column>) -> Result<Expr, Diagnostic> {
+    let mut expr = parse_term(p, columns)?;
+    while let Some(token) = p.accept(&["+", "-"]) {
+        let op = if token.text == "+" { Op::Add } else { Op::Sub };
+        let right = parse_term(p, columns)?;
+        expr = Expr::Binary(op, Box::new(expr), Box::new(right));
+    }
+    Ok(expr)
+}
+
+fn parse_term(p: &mut Parser, columns: & Vec<Column>) -> Result<Expr, Diagnostic> {
+    let mut expr = parse_unary(p, columns)?;
+    while let Some(token) = p.accept(&["*", "/", "%"]) {
+        let op = match token.text.as_str() {
+            "*" => Op::Mul,
+            "/" => Op::Div,
+            _ => Op::Rem,
+        };
+        let right = parse_unary(p, columns)?;
+        expr = Expr::Binary(op, Box::new(expr), Box::new(right));
+    }
+    Ok(expr)
+}
+
+fn parse_unary(p: &mut Parser, columns: & Vec<Column>) -> Result<Expr, Diagnostic> {
+    if p.accept(&["-"]).is_some() {
+        let operand = parse_unary(p, columns)?;
+        return Ok(Expr::Unary(Op::Neg, Box::new(operand)));
     }
+    parse_primary(p, columns)
+}
+
+/* a literal, a column of the table, or a parenthesized expression */
+fn parse_primary(p: &mut Parser, columns: & Vec<Column>) 
+    -> Result<Expr, Diagnostic> 
+{
+    let token = p.next()?;
+    let value = match (&token.kind, token.text.as_str()) {
+        (TokenKind::Symbol, "(") => {
+            let expr = parse_or(p, columns)?;
+            p.expect(")", "expecting ')'")?;
+            return Ok(expr);
+        },
+        (TokenKind::Number, text) => {
+            match text.parse::<i64>() {
+                Ok(v) => Value::Integer(v),
+                Err(_) => match text.parse::<f64>() {
+                    Ok(v) => Value::Float(v),
+                    Err(_) => return Err(Diagnostic::at(&token, 
+                        String::from("invalid number"))),
+                },
+            }
+        },
+        (TokenKind::Str, text) => Value::Text(unquote(text)),
+        (TokenKind::Ident, "true") => Value::Boolean(true),
+        (TokenKind::Ident, "false") => Value::Boolean(false),
+        (TokenKind::Ident, "null") => Value::Null,
+        (TokenKind::Ident, name) => {
+            return match columns.iter().position(|c| c.c_name == name) {
+                Some(index) => Ok(Expr::Column(index)),
+                None => Err(Diagnostic::at(&token, 
+                    format!("unknown column '{}'", name))),
+            };
+        },
+        _ => {
+            p.pos -= 1;
+            return Err(Diagnostic::at(&token, 
+                                      String::from("expecting an expression")));
+        },
+    };
+    Ok(Expr::Literal(value))
 }
 
 /* 
  * Iteratively parses each table from the vector of tokens and returns 
- * the vector of tables 
+ * the vector of tables, or every error found. `end` is the position
+ * just past the end of the file.
  */
-pub fn parse(tokens: Vec<String>) -> Result<Vec<Table>, &'static str> {
+pub fn parse(tokens: Vec<Token>, end: (usize, usize)) 
+    -> Result<Vec<Table>, Vec<Diagnostic>> 
+{
     let mut tables: Vec<Table> = vec![]; 
+    let mut pending: Vec<Pending> = vec![];
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    let mut p = Parser {
+        tokens: tokens,
+        pos: 0,
+        end: Token { 
+            kind: TokenKind::Symbol, 
+            text: String::new(), 
+            line: end.0, 
+            col: end.1,
+        },
+        errors: vec![],
+    };
     
-    let mut it = tokens.iter();
-    loop {
-        let table = match parse_table(&mut it, &tables)? {
-            Some(table) => table,
-            None => break,
+    while !p.at_end() {
+        let start = p.peek().clone();
+        let table_id = tables.len() as i32 + 1;
+        match parse_table(&mut p, table_id) {
+            Ok((table, table_pending)) => {
+                if let Some(line) = seen.get(&table.t_name) {
+                    p.errors.push(Diagnostic::at(&start, format!(
+                        "duplicate table '{}' (first defined on line {})",
+                        table.t_name, line)));
+                    continue;
+                }
+                seen.insert(table.t_name.clone(), start.line);
+                tables.push(table);    
+                pending.push(table_pending);
+            },
+            Err(e) => {
+                p.errors.push(e);
+                p.recover("}", "");
+            },
+        }
+    }
+    
+    if tables.len() == 0 && p.errors.len() == 0 {
+        p.errors.push(Diagnostic { line: 1, col: 1, 
+                                   message: String::from("schema file is empty") });
+    }
+    
+    /* 
+     * Second pass: resolve column types now that every table name is 
+     * known, so a table can refer to itself or to a later table. Defaults
+     * and checks are type checked once the column types are known.
+     */
+    for (t, table_pending) in pending.iter().enumerate() {
+        for (c, (type_name, default)) in table_pending.types.iter().enumerate() {
+            let resolved = match tables[t].t_cols[c].c_type {
+                Value::NULL => column_type_of(&type_name.text, &tables),
+                c_type => Ok((c_type, 0)),
+            };
+            match resolved {
+                Ok((c_type, c_ref)) => {
+                    tables[t].t_cols[c].c_type = c_type;
+                    tables[t].t_cols[c].c_ref = c_ref;
+                },
+                Err(e) => {
+                    p.errors.push(Diagnostic::at(type_name, 
+                        format!("{} '{}'", e, type_name.text)));
+                    continue;
+                },
+            }
+            
+            if let Some(literal) = default {
+                if let Err(e) = resolve_default(literal, 
+                                                &mut tables[t].t_cols[c]) {
+                    p.errors.push(e);
+                }
+            }
+        }
+        
+        let table = &tables[t];
+        for (check, keyword) in table.t_checks.iter().zip(&table_pending.checks) {
+            if let Err(e) = check_type(check, &table.t_cols, keyword) {
+                p.errors.push(e);
+            }
+        }
+    }
+    
+    if p.errors.len() > 0 {
+        return Err(p.errors);
+    }
+    Ok(tables)
+}
+
+/* 
+ * Parses a column that a migration adds to `table`, declared as in a 
+ * schema file after the column name: its type and constraints, then any
+ * checks added with it, e.g. `integer not null default 0; check (age >= 0)`.
+ * The checks may use the column and those the table already has. Returns
+ * the column with id `column_id`, or the first error found.
+ */
+pub fn parse_added_column(table: & Table, tables: & Vec<Table>, 
+    name: & String, declaration: & str, column_id: i32) 
+    -> Result<(Column, Vec<Check>), String>
+{
+    let text = format!("{}: {};", name, 
+                       declaration.trim().trim_end_matches(';'));
+    let (tokens, errors) = tokenize(&text);
+    if let Some(e) = errors.into_iter().next() {
+        return Err(e.message);
+    }
+    let end = end_position(&text);
+    let mut p = Parser {
+        tokens: tokens,
+        pos: 0,
+        end: Token { 
+            kind: TokenKind::Symbol, 
+            text: String::new(), 
+            line: end.0, 
+            col: end.1,
+        },
+        errors: vec![],
+    };
+    
+    let (mut column, type_name, default) = parse_column(&mut p, column_id)
+        .map_err(|e| e.message)?;
+    if column.c_type == Value::NULL {
+        /* the table may not be in `tables` yet, and may refer to itself */
+        let (c_type, c_ref) = match type_name.text == table.t_name {
+            true => (Value::FOREIGN, table.t_id),
+            false => column_type_of(&type_name.text, tables)
+                .map_err(|e| format!("{} '{}'", e, type_name.text))?,
         };
-        tables.push(table);    
+        column.c_type = c_type;
+        column.c_ref = c_ref;
+    }
+    if let Some(literal) = default {
+        resolve_default(&literal, &mut column).map_err(|e| e.message)?;
     }
     
-    if tables.len() == 0 {
-        Err("schema file is empty")
+    let mut columns = table.t_cols.clone();
+    columns.push(column.clone());
+    let mut checks = vec![];
+    while !p.at_end() {
+        let keyword = p.peek().clone();
+        if keyword.kind != TokenKind::Ident || keyword.text != "check" {
+            return Err(format!("expecting a check instead of '{}'", 
+                               keyword.text));
+        }
+        let check = parse_check(&mut p, &columns).map_err(|e| e.message)?;
+        check_type(&check, &columns, &keyword).map_err(|e| e.message)?;
+        checks.push(check);
     }
-    else {                        
-        Ok(tables)
+    
+    match p.errors.into_iter().next() {
+        Some(e) => Err(e.message),
+        None => Ok((column, checks)),
+    }
+}
+
+/* 
+ * file:line:col: error: message
+ *     the offending line
+ *         ^
+ */
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemaError::Io(filename, e) => 
+                write!(f, "could not read {}: {}", filename, e),
+            SchemaError::Invalid(filename, contents, errors) => {
+                let lines: Vec<&str> = contents.lines().collect();
+                for (i, error) in errors.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "\n")?;
+                    }
+                    write!(f, "{}:{}:{}: error: {}", filename, error.line, 
+                           error.col, error.message)?;
+                    if let Some(line) = lines.get(error.line - 1) {
+                        let pad: String = line.chars().take(error.col - 1)
+                            .map(|c| if c == '\t' { '\t' } else { ' ' })
+                            .collect();
+                        write!(f, "\n    {}\n    {}^", line, pad)?;
+                    }
+                }
+                write!(f, "\n{} error(s) in {}", errors.len(), filename)
+            },
+        }
     }
 }
 
 /* For debugging */
 impl fmt::Display for Column {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}. {} : {}", self.c_id, self.c_name, self.type_as_str())
+        write!(f, "{}. {} : {}", self.c_id, self.c_name, self.type_as_str())?;
+        if self.c_not_null {
+            write!(f, " not null")?;
+        }
+        if self.c_default != Value::Null {
+            write!(f, " default {}", self.c_default)?;
+        }
+        if self.c_unique {
+            write!(f, " unique")?;
+        }
+        Ok(())
     }
 }
 
@@ -237,6 +1126,9 @@ impl fmt::Debug for Table {
         for column in &self.t_cols {
             write!(f, "    {}\n", column)?;
         }
+        for check in &self.t_checks {
+            write!(f, "    check {}\n", check.name)?;
+        }
         Ok(())
     }
 }
@@ -9,138 +9,390 @@
 
 use std::net::TcpListener;
 use std::net::TcpStream;
+use std::net::Shutdown;
 use std::io::Write;
 use std::io;
 use packet::Command;
+use packet::Request;
 use packet::Response;
 use packet::Network;
+use packet::{ClientInfo, Stats};
 use schema::Table;
+use subscription::Inbox;
+use config::Config;
 use database;
 use database::Database;
+use metrics;
+use metrics::METRICS;
+use raft;
+use raft::Raft;
+use replica;
+use std::collections::HashMap;
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::{Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-fn single_threaded(listener: TcpListener, table_schema: Vec<Table>, verbose: bool)
-{
-    /* 
-     * you probably need to use table_schema somewhere here or in
-     * Database::new 
-     */
-    let db = Arc::new(Mutex::new(Database::new(table_schema)));
-    let num_conn = Arc::new(Mutex::new(0 as i64));
+/* set by the signal handler once SIGINT or SIGTERM is received */
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        
-        if verbose {
-            println!("Connected to {}", stream.peer_addr().unwrap());
-        }
+const SIGINT: i32 = 2;
+const SIGTERM: i32 = 15;
 
-        let db_clone = db.clone();
-        let num_conn_clone = num_conn.clone();
+/* how often the accept loop checks for a pending shutdown */
+const ACCEPT_POLL: Duration = Duration::from_millis(50);
 
-        match handle_connection(stream, db_clone, num_conn_clone) {
-            Ok(()) => {
-                if verbose {
-                    println!("Disconnected.");
-                }
-            },
-            Err(e) => eprintln!("Connection error: {:?}", e),
-        };
+/* how often a subscribed connection checks its change queue */
+const CHANGE_POLL: Duration = Duration::from_millis(50);
+
+extern "C" {
+    fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+}
+
+/* only async-signal-safe work is allowed here, so just raise the flag */
+extern "C" fn on_signal(_signum: i32) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+fn install_signal_handlers() {
+    unsafe {
+        signal(SIGINT, on_signal);
+        signal(SIGTERM, on_signal);
     }
 }
 
-fn multi_threaded(listener: TcpListener, table_schema: Vec<Table>, verbose: bool)
-{
-    // TODO: implement me
-    let db = Arc::new(Mutex::new(Database::new(table_schema)));
-    let mut threads = vec![];
+fn shutting_down() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+/* A connected client, as listed by the CONNECTIONS admin command */
+struct Client {
+    stream: TcpStream,      /* clone used to close the connection */
+    peer: String,
+    since: SystemTime,
+    requests: u64,
+}
+
+/* State shared by the listener and every connection thread */
+struct Server {
+    config: Config,
+    db: Arc<Mutex<Database>>,
+    num_conn: Mutex<i64>,
+    connections: Mutex<HashMap<u64, Client>>,
+    started: Instant,
+    requests: AtomicU64,
+    raft: Option<Arc<Raft>>,    /* set on cluster nodes */
+}
 
-    //Number of connections to database server
-    let num_conn = Arc::new(Mutex::new(0 as i64));
+impl Server {
+    fn new(table_schema: Vec<Table>, config: Config) -> Server {
+        let db = Database::new(table_schema, config.change_log_size);
+        Server {
+            config: config,
+            db: Arc::new(Mutex::new(db)),
+            num_conn: Mutex::new(0),
+            connections: Mutex::new(HashMap::new()),
+            started: Instant::now(),
+            requests: AtomicU64::new(0),
+            raft: None,
+        }
+    }
+}
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
+/* 
+ * Accepts the next client, or returns None once the server is shutting
+ * down. The listener is non-blocking so the shutdown flag is polled.
+ */
+fn accept(listener: &TcpListener) -> Option<io::Result<TcpStream>> {
+    loop {
+        if shutting_down() {
+            return None;
+        }
         
-        if verbose {
-            println!("Connected to {}", stream.peer_addr().unwrap());
+        match listener.accept() {
+            Ok((stream, _)) => {
+                return Some(stream.set_nonblocking(false).map(|_| stream));
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_POLL);
+            },
+            Err(e) => return Some(Err(e)),
         }
+    }
+}
+
+/* 
+ * Stops reading from every connected client. Requests already being
+ * handled still get their response; the client threads then see the end
+ * of the stream and close the connection.
+ */
+fn close_connections(server: &Server) -> usize {
+    let connections = server.connections.lock().unwrap();
+    for client in connections.values() {
+        let _ = client.stream.shutdown(Shutdown::Read);
+    }
+    connections.len()
+}
+
+fn multi_threaded(listener: TcpListener, server: Arc<Server>)
+{
+    let mut threads = vec![];
+    let mut next_id: u64 = 0;
+    metrics::serve(&server.config, server.db.clone(), shutting_down);
+
+    while let Some(stream) = accept(&listener) {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("could not accept connection"; error = e.to_string());
+                continue;
+            },
+        };
         
-        let db_clone = db.clone();
-        let num_conn_clone = num_conn.clone();
+        let server_clone = server.clone();
+        next_id += 1;
+        let conn_id = next_id;
 
         threads.push(thread::spawn(move || {
-            match handle_connection(stream, db_clone, num_conn_clone) {
-                Ok(()) => {
-                    if verbose {
-                        println!("Disconnected.");
-                    }
-                },
-                Err(e) => eprintln!("Connection error: {:?}", e),
-            };
+            handle_connection(stream, conn_id, &server_clone);
         }));
     }
 
+    /* no new clients are accepted past this point */
+    drop(listener);
+    let closed = close_connections(&server);
+    if closed > 0 {
+        info!("shutting down, closing connections"; connections = closed);
+    }
+
     for child in threads {
         child.join().unwrap();
     }
 }
 
 /* Sets up the TCP connection between the database client and server */
-pub fn run_server(table_schema: Vec<Table>, ip_address: String, verbose: bool)
+pub fn run_server(table_schema: Vec<Table>, config: Config)
 {
-    let listener = match TcpListener::bind(ip_address) {
+    let listener = match TcpListener::bind(config.address()) {
         Ok(listener) => listener,
         Err(e) => {
-            eprintln!("Could not start server: {}", e);
+            error!("could not start server"; address = config.address(), 
+                   error = e.to_string());
             return;
         },
     };
     
-    println!("Listening: {:?}", listener);
+    if let Err(e) = listener.set_nonblocking(true) {
+        error!("could not start server"; address = config.address(), 
+               error = e.to_string());
+        return;
+    }
     
-    /*
-     * TODO: replace with multi_threaded
-     */
-    multi_threaded(listener, table_schema, verbose);
+    install_signal_handlers();
+    info!("listening"; address = config.address());
+    
+    let mut server = Server::new(table_schema, config);
+    if server.config.replica_of.len() > 0 {
+        replica::follow(server.config.replica_of.clone(), 
+                        server.config.admin_token.clone(), server.db.clone(),
+                        shutting_down);
+    }
+    if server.config.cluster.len() > 0 {
+        match raft::start(&server.config, server.db.clone(), shutting_down) {
+            Ok(raft) => server.raft = Some(raft),
+            Err(e) => {
+                error!("could not join cluster"; error = e.to_string());
+                return;
+            },
+        }
+    }
+    multi_threaded(listener, Arc::new(server));
+    
+    info!("server shut down cleanly");
 }
 
 impl Network for TcpStream {}
 
+/* 
+ * One of the max_clients connection slots. The slot is given back when
+ * this is dropped, however the connection ends.
+ */
+struct Slot<'a> {
+    num_conn: &'a Mutex<i64>,
+}
+
+impl<'a> Slot<'a> {
+    fn acquire(num_conn: &'a Mutex<i64>, max_clients: i64) -> Option<Slot<'a>> {
+        let mut total_num_conn = num_conn.lock().unwrap();
+        if *total_num_conn >= max_clients {
+            return None;
+        }
+        *total_num_conn += 1;
+        drop(total_num_conn);
+        Some(Slot { num_conn: num_conn })
+    }
+}
+
+impl<'a> Drop for Slot<'a> {
+    fn drop(&mut self) {
+        *self.num_conn.lock().unwrap() -= 1;
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
 /* Receive the request packet from ORM and send a response back */
-fn handle_connection(mut stream: TcpStream, db: Arc<Mutex<Database>>, num_conn: Arc<Mutex<i64>>) 
-    -> io::Result<()> 
+fn handle_connection(stream: TcpStream, conn_id: u64, server: &Server)
 {
+    let peer = match stream.peer_addr() {
+        Ok(addr) => addr.to_string(),
+        Err(_) => String::from("unknown"),
+    };
+    
+    info!("connected"; conn = conn_id, peer = &peer);
+    match serve_connection(stream, conn_id, &peer, server) {
+        Ok(()) => info!("disconnected"; conn = conn_id, peer = &peer),
+        Err(e) => warn!("connection error"; conn = conn_id, peer = &peer, 
+                        error = e.to_string()),
+    }
+}
+
+fn serve_connection(mut stream: TcpStream, conn_id: u64, peer: &str,
+    server: &Server) -> io::Result<()> 
+{
+    let config = &server.config;
+    stream.set_write_timeout(Config::timeout(config.write_timeout))?;
+    
     /* 
-     * Tells the client that the connction to server is successful.
-     * TODO: respond with SERVER_BUSY when attempting to accept more than
-     *       4 simultaneous clients.
+     * Tells the client that the connction to server is successful, or
+     * responds with SERVER_BUSY when max_clients are already connected.
      */
+    let _slot = match Slot::acquire(&server.num_conn, config.max_clients) {
+        Some(slot) => slot,
+        None => {
+            warn!("server busy, rejecting client"; conn = conn_id, peer = peer);
+            METRICS.rejected();
+            stream.respond(&Response::Error(Response::SERVER_BUSY))?;
+            return Ok(());
+        },
+    };
     
-    //Check number of connections currently
-    let mut total_num_conn = num_conn.lock().unwrap();
+    let client = Client {
+        stream: stream.try_clone()?,
+        peer: peer.to_string(),
+        since: SystemTime::now(),
+        requests: 0,
+    };
+    server.connections.lock().unwrap().insert(conn_id, client);
+    METRICS.connected();
+    let result = match serve_requests(&mut stream, conn_id, peer, server) {
+        /* KILL cuts the connection wherever it was, which is no failure */
+        Err(_) if killed(server, conn_id) => {
+            info!("disconnected by admin"; conn = conn_id, peer = peer);
+            Ok(())
+        },
+        result => result,
+    };
+    database::disconnected(&mut server.db.lock().unwrap(), conn_id);
+    METRICS.disconnected();
+    server.connections.lock().unwrap().remove(&conn_id);
+    result
+}
 
-    if *total_num_conn >= 4 {
-        stream.respond(&Response::Error(Response::SERVER_BUSY))?;
-        return Ok(());
-    }
-    else {
-        *total_num_conn += 1;
+/* 
+ * Waits up to idle_timeout for the client to start sending its next
+ * request. Returns false if the client stayed idle for too long.
+ * Subscribed clients are sent their changes meanwhile, and are not idle
+ * while they have subscriptions.
+ */
+fn wait_for_request(stream: &mut TcpStream, config: &Config, 
+    inbox: Option<&mut Inbox>) -> io::Result<bool> 
+{
+    let result = match inbox {
+        None => {
+            stream.set_read_timeout(Config::timeout(config.idle_timeout))?;
+            match stream.peek(&mut [0 as u8; 1]) {
+                Ok(_) => Ok(true),
+                Err(ref e) if is_timeout(e) => Ok(false),
+                Err(e) => Err(e),
+            }
+        },
+        Some(inbox) => wait_for_changes(stream, config, inbox),
+    };
+    
+    /* the rest of the request must then arrive within read_timeout */
+    stream.set_read_timeout(Config::timeout(config.read_timeout))?;
+    result
+}
+
+fn wait_for_changes(stream: &mut TcpStream, config: &Config, 
+    inbox: &mut Inbox) -> io::Result<bool> 
+{
+    let idle_since = Instant::now();
+    stream.set_read_timeout(Some(CHANGE_POLL))?;
+    loop {
+        for (subscription, change) in inbox.drain() {
+            stream.respond(&Response::Change(subscription, change))?;
+        }
+        stream.flush()?;
+        
+        if let Some(timeout) = Config::timeout(config.idle_timeout) {
+            if inbox.active.len() == 0 && idle_since.elapsed() >= timeout {
+                return Ok(false);
+            }
+        }
+        match stream.peek(&mut [0 as u8; 1]) {
+            Ok(_) => return Ok(true),
+            Err(ref e) if is_timeout(e) => continue,
+            Err(e) => return Err(e),
+        }
     }
+}
+
+/* true once an admin has disconnected this client with KILL */
+fn killed(server: &Server, conn_id: u64) -> bool {
+    !server.connections.lock().unwrap().contains_key(&conn_id)
+}
+
+/* Answers requests until the client exits or the server shuts down */
+fn serve_requests(stream: &mut TcpStream, conn_id: u64, peer: &str,
+    server: &Server) -> io::Result<()>
+{
+    let config = &server.config;
+    let mut admin = false;
+    let mut inbox: Option<Inbox> = None;    /* created by the first SUBSCRIBE */
     
-    drop(total_num_conn);
+    /* the registry may have been closed before this client was added */
+    if shutting_down() {
+        return Ok(());
+    }
     
     stream.respond(&Response::Connected)?;
 
     loop {
-        let request = match stream.receive() {
+        if !wait_for_request(stream, config, inbox.as_mut())? {
+            info!("idle timeout, disconnecting"; conn = conn_id, peer = peer,
+                  timeout = config.idle_timeout);
+            let _ = stream.respond(&Response::Error(Response::TIMEOUT));
+            break;
+        }
+        
+        let request = match stream.receive(config.max_packet_size) {
             Ok(request) => request,
+            /* the read side was closed by a shutdown, not by the client */
+            Err(_) if shutting_down() => break,
+            Err(ref e) if is_timeout(e) => {
+                warn!("read timeout, disconnecting"; conn = conn_id, 
+                      peer = peer, timeout = config.read_timeout);
+                let _ = stream.respond(&Response::Error(Response::TIMEOUT));
+                break;
+            },
             Err(e) => {
                 /* respond error */
                 stream.respond(&Response::Error(Response::BAD_REQUEST))?;
-                let mut total_num_conn = num_conn.lock().unwrap();
-                *total_num_conn -= 1;
                 return Err(e);
             },
         };
@@ -150,17 +402,214 @@ fn handle_connection(mut stream: TcpStream, db: Arc<Mutex<Database>>, num_conn:
             break;
         }
         
-        let mut shared_db = db.lock().unwrap();
+        server.requests.fetch_add(1, Ordering::Relaxed);
+        if let Some(client) = server.connections.lock().unwrap()
+            .get_mut(&conn_id) {
+            client.requests += 1;
+        }
+        
+        let start = Instant::now();
+        let command = request.command.name();
+        let table_id = request.table_id;
+        
+        if request.command.is_admin() {
+            let response = handle_admin(server, conn_id, &mut admin, 
+                                        request);
+            info!("admin request"; conn = conn_id, peer = peer, 
+                  command = command, result = response.code());
+            METRICS.request(command, response.code(), start.elapsed());
+            stream.respond(&response)?;
+            continue;
+        }
+        
+        /* 
+         * a snapshot makes this server a leader with followers, which 
+         * cannot change its schema, so only admins may take one
+         */
+        if let Command::Snapshot = request.command {
+            if !admin {
+                warn!("snapshot refused to a client that is not an admin";
+                      conn = conn_id, peer = peer);
+                METRICS.request(command, Response::FORBIDDEN, start.elapsed());
+                stream.respond(&Response::Error(Response::FORBIDDEN))?;
+                continue;
+            }
+        }
+        
+        /* on a cluster, reads wait for the leader to confirm it still leads */
+        let refused = match server.raft {
+            Some(ref raft) if request.command.is_read() => 
+                raft.read_index().err(),
+            _ => None,
+        };
+        if let Some(response) = refused {
+            debug!("read refused"; conn = conn_id, peer = peer, 
+                   command = command, result = response.code());
+            METRICS.request(command, response.code(), start.elapsed());
+            stream.respond(&response)?;
+            continue;
+        }
+        
+        /* and writes go through the leader's consensus log */
+        let mut shared_db: MutexGuard<Database>;
+        let response = match server.raft {
+            Some(ref raft) if request.command.is_write() => {
+                raft.submit(request)
+            },
+            _ => {
+                shared_db = server.db.lock().unwrap();
+                METRICS.lock_wait.observe(start.elapsed());
 
-        /* Send back a response */
-        let response = database::handle_request(request, & mut *shared_db);
+                /* Send back a response */
+                match request.command.is_subscription() {
+                    true => handle_subscription(&mut shared_db, conn_id, 
+                                                &mut inbox, config, request),
+                    false => database::handle_request(request, 
+                                                      & mut *shared_db),
+                }
+            },
+        };
+        let latency = start.elapsed();
         
-        stream.respond(&response)?;
-        stream.flush()?;
+        debug!("request"; conn = conn_id, peer = peer, command = command,
+               table = table_id, latency_us = latency, 
+               result = response.code());
+        METRICS.request(command, response.code(), latency);
+        
+        if let Err(e) = stream.respond(&response).and_then(|_| stream.flush()) {
+            if is_timeout(&e) {
+                warn!("write timeout, disconnecting"; conn = conn_id, 
+                      peer = peer, timeout = config.write_timeout);
+            }
+            return Err(e);
+        }
     }
-
-    let mut total_num_conn = num_conn.lock().unwrap();
-    *total_num_conn -= 1;
+    
     Ok(())
 }
 
+/* 
+ * Answers SUBSCRIBE and UNSUBSCRIBE. The connection's change queue is
+ * created by its first subscription.
+ */
+fn handle_subscription(db: &mut Database, conn_id: u64, 
+    inbox: &mut Option<Inbox>, config: &Config, request: Request) 
+    -> Response<'static>
+{
+    let inbox = inbox.get_or_insert_with(|| Inbox::new(config.event_queue));
+    match request.command {
+        Command::Subscribe(column_id, operator, value) => {
+            let response = database::subscribe(db, request.table_id, conn_id,
+                                               column_id, operator, value, 
+                                               &inbox.queue);
+            if let Response::Subscribed(id) = response {
+                inbox.active.push(id);
+            }
+            response
+        },
+        Command::Unsubscribe(id) => {
+            inbox.active.retain(|active| *active != id);
+            database::unsubscribe(db, conn_id, id)
+        },
+        _ => Response::Error(Response::UNIMPLEMENTED),
+    }
+}
+
+/* 
+ * Compares a token given to AUTH with the admin token. Every byte of the
+ * admin token is compared whatever the given one holds, so the time taken
+ * does not tell how much of a guess was right.
+ */
+fn same_token(given: &str, token: &str) -> bool {
+    let (given, token) = (given.as_bytes(), token.as_bytes());
+    let mut diff = given.len() ^ token.len();
+    for (i, byte) in token.iter().enumerate() {
+        diff |= (byte ^ given.get(i).cloned().unwrap_or(0)) as usize;
+    }
+    diff == 0
+}
+
+/* 
+ * Answers the admin command set. Every command other than AUTH needs the
+ * connection to have authenticated with the configured admin token.
+ */
+fn handle_admin(server: &Server, conn_id: u64, admin: &mut bool, 
+    request: Request) -> Response<'static>
+{
+    let command = request.command;
+    let token = &server.config.admin_token;
+    if let Command::Auth(given) = command {
+        *admin = token.len() > 0 && same_token(&given, token);
+        return match *admin {
+            true => Response::Done,
+            false => Response::Error(Response::FORBIDDEN),
+        };
+    }
+    
+    if !*admin {
+        return Response::Error(Response::FORBIDDEN);
+    }
+    
+    match command {
+        Command::Stats => {
+            let db = server.db.lock().unwrap();
+            Response::Stats(Stats {
+                uptime: server.started.elapsed().as_secs() as i64,
+                requests: server.requests.load(Ordering::Relaxed) as i64,
+                connections: *server.num_conn.lock().unwrap() as i32,
+                memory: db.memory_estimate() as i64,
+                rows: db.row_counts(),
+            })
+        },
+        Command::Connections => {
+            let connections = server.connections.lock().unwrap();
+            let mut clients: Vec<ClientInfo> = connections.iter()
+                .map(|(id, client)| ClientInfo {
+                    id: *id as i64,
+                    peer: client.peer.clone(),
+                    since: client.since.duration_since(UNIX_EPOCH)
+                        .map(|d| d.as_secs() as i64).unwrap_or(0),
+                    requests: client.requests as i64,
+                }).collect();
+            clients.sort_by_key(|client| client.id);
+            Response::Connections(clients)
+        },
+        Command::Kill(id) => {
+            /* the client thread notices it was removed and stops */
+            match server.connections.lock().unwrap().remove(&(id as u64)) {
+                Some(client) => {
+                    let _ = client.stream.shutdown(Shutdown::Both);
+                    info!("killing connection"; conn = conn_id, target = id);
+                    Response::Done
+                },
+                None => Response::Error(Response::NOT_FOUND),
+            }
+        },
+        Command::Migrate(migration) => {
+            info!("migrating schema"; conn = conn_id, table = request.table_id,
+                  migration = format!("{:?}", migration));
+            /* 
+             * schema changes are not replicated, and followers load their
+             * schema from a file, so they could not apply the rows of a 
+             * migrated table
+             */
+            if server.raft.is_some() {
+                return Response::Error(Response::UNIMPLEMENTED);
+            }
+            let mut db = server.db.lock().unwrap();
+            if let Some(response) = database::not_leader(&db) {
+                return response;
+            }
+            if db.followed {
+                warn!("refusing to migrate the schema of a leader with \
+                       followers"; conn = conn_id);
+                return Response::Error(Response::UNIMPLEMENTED);
+            }
+            match database::migrate(&mut db, request.table_id, migration) {
+                Ok(()) => Response::Done,
+                Err(code) => Response::Error(code),
+            }
+        },
+        _ => Response::Error(Response::UNIMPLEMENTED),
+    }
+}
@@ -0,0 +1,200 @@
+/*
+ * subscription.rs
+ *
+ * Change data capture: connections subscribe to the changes of a table,
+ * and the database pushes them into a bounded queue per connection
+ *
+ * University of Toronto
+ * 2019
+ */
+
+use filter;
+use filter::Filter;
+use packet::{Change, Value};
+use schema::Table;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+
+/*
+ * The producing end of a connection's change queue. A subscriber that
+ * lets its queue fill up has missed changes, so all of its subscriptions
+ * are cancelled, and their ids are left in `cancelled` to be reported
+ * after the changes already queued.
+ */
+#[derive(Clone)]
+pub struct Queue {
+    sender: SyncSender<(i64, Change)>,  /* subscription id, change */
+    cancelled: Arc<Mutex<Vec<i64>>>,
+}
+
+/* The consuming end of a change queue, owned by the connection */
+pub struct Inbox {
+    pub queue: Queue,
+    pub active: Vec<i64>,       /* ids of the connection's subscriptions */
+    receiver: Receiver<(i64, Change)>,
+}
+
+impl Inbox {
+    pub fn new(capacity: usize) -> Inbox {
+        let (sender, receiver) = sync_channel(capacity);
+        Inbox {
+            queue: Queue {
+                sender: sender,
+                cancelled: Arc::new(Mutex::new(vec![])),
+            },
+            active: vec![],
+            receiver: receiver,
+        }
+    }
+
+    /* takes the queued changes, then a notice for each cancellation */
+    pub fn drain(&mut self) -> Vec<(i64, Change)> {
+        /*
+         * cancellations are read first: changes of those subscriptions
+         * were all queued before they were cancelled
+         */
+        let cancelled = mem::replace(&mut *self.queue.cancelled.lock().unwrap(),
+                                     vec![]);
+        let mut changes: Vec<(i64, Change)> = self.receiver.try_iter().collect();
+        for id in cancelled {
+            self.active.retain(|active| *active != id);
+            changes.push((id, Change {
+                sequence: 0,
+                kind: Change::CANCELLED,
+                table_id: 0,
+                object_id: 0,
+                version: 0,
+                values: vec![],
+            }));
+        }
+        changes
+    }
+}
+
+/* A connection's interest in the changes to a table */
+struct Subscription {
+    id: i64,
+    conn_id: u64,
+    table_id: i32,
+    /* the scan the filter was built from, to rebuild it after migrations */
+    column_id: i32,
+    operator: i32,
+    operand: Value,
+    filter: Filter,
+    queue: Queue,
+}
+
+pub struct Subscriptions {
+    list: Vec<Subscription>,
+    next_id: i64,
+}
+
+impl Subscriptions {
+    pub fn new() -> Subscriptions {
+        Subscriptions {
+            list: vec![],
+            next_id: 1,
+        }
+    }
+
+    /*
+     * Subscribes a connection to the rows of `table` matching a scan
+     * predicate, and returns the subscription id
+     */
+    pub fn subscribe(&mut self, table: & Table, conn_id: u64, column_id: i32,
+        operator: i32, operand: Value, queue: & Queue) -> Result<i64, i32>
+    {
+        let filter = filter::compile(table, column_id, operator,
+                                     operand.clone())?;
+        let id = self.next_id;
+        self.next_id += 1;
+        self.list.push(Subscription {
+            id: id,
+            conn_id: conn_id,
+            table_id: table.t_id,
+            column_id: column_id,
+            operator: operator,
+            operand: operand,
+            filter: filter,
+            queue: queue.clone(),
+        });
+        Ok(id)
+    }
+
+    /* false if the connection has no such subscription */
+    pub fn unsubscribe(&mut self, conn_id: u64, id: i64) -> bool {
+        let before = self.list.len();
+        self.list.retain(|s| s.id != id || s.conn_id != conn_id);
+        self.list.len() < before
+    }
+
+    /* drops the subscriptions of a closed connection */
+    pub fn disconnected(&mut self, conn_id: u64) {
+        self.list.retain(|s| s.conn_id != conn_id);
+    }
+
+    /*
+     * Rebuilds the filters after a migration. Subscriptions whose table
+     * or column is gone are cancelled.
+     */
+    pub fn schema_changed(&mut self, tables: & Vec<Table>) {
+        let mut cancelled = vec![];
+        for s in self.list.iter_mut() {
+            let filter = match tables.iter().find(|t| t.t_id == s.table_id) {
+                Some(table) => filter::compile(table, s.column_id, s.operator,
+                                               s.operand.clone()),
+                None => Err(0),
+            };
+            match filter {
+                Ok(filter) => s.filter = filter,
+                Err(_) => cancelled.push(s.id),
+            }
+        }
+        self.cancel(|s| cancelled.contains(&s.id));
+    }
+
+    /*
+     * Queues a change for every subscription whose filter matches the row
+     * before or after it. `old` is None for inserts and `new` for drops.
+     */
+    pub fn publish(&mut self, change: & Change, old: Option<& Vec<Value>>, 
+        new: Option<& Vec<Value>>)
+    {
+        let (table_id, object_id) = (change.table_id, change.object_id);
+        let mut overflowed: Vec<u64> = vec![];
+        let mut closed: Vec<u64> = vec![];
+        for s in self.list.iter().filter(|s| s.table_id == table_id) {
+            let matched = old.map_or(false, |v| s.filter.matches(object_id, v))
+                || new.map_or(false, |v| s.filter.matches(object_id, v));
+            if !matched || overflowed.contains(&s.conn_id) {
+                continue;
+            }
+            match s.queue.sender.try_send((s.id, change.clone())) {
+                Ok(()) => (),
+                Err(TrySendError::Full(_)) => overflowed.push(s.conn_id),
+                Err(TrySendError::Disconnected(_)) => closed.push(s.conn_id),
+            }
+        }
+
+        for conn_id in overflowed.iter() {
+            warn!("change queue full, cancelling subscriptions";
+                  conn = *conn_id);
+        }
+        self.cancel(|s| overflowed.contains(&s.conn_id));
+        self.list.retain(|s| !closed.contains(&s.conn_id));
+    }
+
+    /* cancels every subscription, once the rows were replaced */
+    pub fn cancel_all(&mut self) {
+        self.cancel(|_| true);
+    }
+
+    /* removes subscriptions and tells their connections */
+    fn cancel<F: Fn(& Subscription) -> bool>(&mut self, cancelled: F) {
+        for s in self.list.iter().filter(|s| cancelled(s)) {
+            s.queue.cancelled.lock().unwrap().push(s.id);
+        }
+        self.list.retain(|s| !cancelled(s));
+    }
+}
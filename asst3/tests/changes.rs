@@ -0,0 +1,59 @@
+/*
+ * changes.rs
+ *
+ * Paging through the change log with CHANGES, across a restart. The log
+ * is kept in memory, so a cursor from before the restart is TRUNCATED,
+ * even once the restarted server has made more changes than the old one.
+ *
+ * University of Toronto
+ * 2019
+ */
+
+use client;
+use client::{Client, Failure, Outcome, Server, TRUNCATED, USER};
+
+/* rows inserted before the restart; more are inserted after it */
+const BEFORE: usize = 3;
+const AFTER: usize = 2 * BEFORE;
+
+pub fn run() -> Outcome {
+    let dir = client::scratch("changes")?;
+    let port = client::free_port()?;
+    let mut server = Server::start(&dir, "server", port, &[])?;
+
+    let mut consumer = Client::connect(port)?;
+    let ids = insert(port, "before", BEFORE)?;
+    let (cursor, latest, changed) = consumer.changes(0)?
+        .map_err(|refusal| format!("changes: {}", refusal))?;
+    ensure!(changed == ids, "changes to rows {:?}, not {:?}", changed, ids);
+    ensure!(cursor == latest, "cursor {} is not the latest {}", cursor, latest);
+
+    server.kill()?;
+    let restarted = Server::start(&dir, "server", port, &[])?;
+    let ids = insert(restarted.port, "after", AFTER)?;
+
+    let mut consumer = Client::connect(restarted.port)?;
+    match consumer.changes(cursor)? {
+        Err(ref refusal) if refusal.code == TRUNCATED => (),
+        answer => ensure!(false, "changes since a cursor from before the \
+                          restart: {:?}", answer),
+    }
+    let (_, _, changed) = consumer.changes(0)?
+        .map_err(|refusal| format!("changes after restart: {}", refusal))?;
+    ensure!(changed == ids, "changes after the restart to rows {:?}, not {:?}",
+            changed, ids);
+    Ok(())
+}
+
+/* inserts `count` users, returning their ids */
+fn insert(port: u16, name: &str, count: usize) -> Result<Vec<i64>, Failure> {
+    let mut client = Client::connect(port)?;
+    let mut ids = vec![];
+    for i in 0..count {
+        match client.insert(USER, &client::user(name, i as i64))? {
+            Ok((id, _)) => ids.push(id),
+            Err(refusal) => ensure!(false, "insert: {}", refusal),
+        }
+    }
+    Ok(ids)
+}
@@ -0,0 +1,94 @@
+/*
+ * checks.rs
+ *
+ * CHECK constraints on insert and update. A row that passes every check
+ * is written, and one that fails a check is refused with CHECK_FAILED and
+ * the check's name, leaving the table as it was. A check that evaluates
+ * to null, because a column it reads is null, passes.
+ *
+ * University of Toronto
+ * 2019
+ */
+
+use client;
+use client::{Client, Outcome, Server, Value, CHECK_FAILED};
+
+const SCHEMA: &str = "\
+Item {
+    name: string;
+    price: integer;
+    discount: integer;
+    check positive (price > 0);
+    check (discount < price);
+}
+";
+
+const ITEM: i32 = 1;
+
+/* name of the unnamed check, after its expression */
+const UNNAMED: &str = "discount < price";
+
+/* a price and discount, and the check they fail, if any */
+const ROWS: [(Option<i64>, Option<i64>, Option<&str>); 7] = [
+    (Some(10), Some(2), None),
+    (Some(0), Some(0), Some("positive")),
+    (Some(-5), Some(-10), Some("positive")),
+    (Some(10), Some(10), Some(UNNAMED)),
+    (Some(10), None, None),            /* discount < null is null */
+    (None, Some(5), None),             /* so are null > 0 and 5 < null */
+    (None, None, None),
+];
+
+pub fn run() -> Outcome {
+    let dir = client::scratch("checks")?;
+    let schema = client::schema(&dir, SCHEMA)?;
+    let server = Server::start(&dir, "server", client::free_port()?,
+                               &["--schema", &schema])?;
+    let mut client = Client::connect(server.port)?;
+
+    let mut inserted = vec![];
+    for (i, &(price, discount, failed)) in ROWS.iter().enumerate() {
+        let values = item(&format!("insert {}", i), price, discount);
+        let answer = client.insert(ITEM, &values)?;
+        match (answer, failed) {
+            (Ok((id, _)), None) => inserted.push(id),
+            (Err(ref refusal), Some(check))
+                if refusal.code == CHECK_FAILED && refusal.detail == check =>
+                (),
+            (answer, _) => ensure!(false, "insert {:?} {:?}: {:?}", price,
+                                   discount, answer),
+        }
+    }
+    let mut found = client.scan_all(ITEM)?
+        .map_err(|refusal| format!("scan: {}", refusal))?;
+    found.sort();
+    ensure!(found == inserted, "table holds rows {:?}, not {:?}", found,
+            inserted);
+
+    /* updates of the first row, which passes its checks */
+    let id = inserted[0];
+    let mut current = item("insert 0", Some(10), Some(2));
+    for (i, &(price, discount, failed)) in ROWS.iter().enumerate() {
+        let values = item(&format!("update {}", i), price, discount);
+        let answer = client.update(ITEM, id, &values)?;
+        match (answer, failed) {
+            (Ok(_), None) => current = values,
+            (Err(ref refusal), Some(check))
+                if refusal.code == CHECK_FAILED && refusal.detail == check =>
+                (),
+            (answer, _) => ensure!(false, "update {:?} {:?}: {:?}", price,
+                                   discount, answer),
+        }
+        match client.get(ITEM, id)? {
+            Ok((_, ref values)) if *values == current => (),
+            answer => ensure!(false, "after update {:?} {:?} the row is {:?}",
+                              price, discount, answer),
+        }
+    }
+    Ok(())
+}
+
+fn item(name: &str, price: Option<i64>, discount: Option<i64>) -> Vec<Value> {
+    let number = |n: Option<i64>| n.map_or(Value::Null, Value::Integer);
+    vec![Value::Text(name.to_string()), number(price), number(discount)]
+}
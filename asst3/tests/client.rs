@@ -0,0 +1,682 @@
+/*
+ * client.rs
+ *
+ * A small EasyDB client for the tests, and the server processes they run
+ * it against
+ *
+ * University of Toronto
+ * 2019
+ */
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::process::{Child, Command, ExitStatus};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/* fails the running test unless `condition` holds */
+macro_rules! ensure {
+    ($condition:expr, $($message:tt)*) => {
+        if !$condition {
+            return Err($crate::client::Failure::from(format!($($message)*)));
+        }
+    };
+}
+
+/* the server under test and its schema, from the directory of the Makefile */
+const SERVER: &str = "./server";
+const SCHEMA: &str = "default.txt";
+
+/* time a server has to start accepting clients */
+const START_TIMEOUT: Duration = Duration::from_secs(5);
+
+/* time a client waits for any one reply */
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/* how often a condition that should eventually hold is checked */
+const POLL: Duration = Duration::from_millis(50);
+
+pub const SIGTERM: i32 = 15;
+pub const SIGCONT: i32 = 18;
+pub const SIGSTOP: i32 = 19;
+
+extern "C" {
+    fn kill(pid: i32, signum: i32) -> i32;
+}
+
+/* request codes */
+const INSERT: i32 = 1;
+const UPDATE: i32 = 2;
+const GET: i32 = 4;
+const SCAN: i32 = 5;
+const EXIT: i32 = 6;
+const AUTH: i32 = 8;
+const MIGRATE: i32 = 12;
+const CHANGES: i32 = 16;
+const SNAPSHOT: i32 = 17;
+
+/* result codes */
+pub const OK: i32 = 1;
+pub const BAD_TABLE: i32 = 3;
+pub const BAD_QUERY: i32 = 4;
+pub const BAD_VALUE: i32 = 6;
+pub const UNIMPLEMENTED: i32 = 11;
+pub const FORBIDDEN: i32 = 13;
+pub const CHECK_FAILED: i32 = 17;
+pub const TRUNCATED: i32 = 19;
+pub const NOT_LEADER: i32 = 20;
+
+/* value types */
+const NULL: i32 = 0;
+const INTEGER: i32 = 1;
+const FLOAT: i32 = 2;
+const STRING: i32 = 3;
+const DECIMAL: i32 = 9;
+const LIST: i32 = 10;
+const JSON: i32 = 11;
+
+/* schema changes */
+const ADD_TABLE: i32 = 1;
+const DROP_TABLE: i32 = 2;
+const RENAME_COLUMN: i32 = 5;
+
+/* a scan of every row: the reserved id column with the AL operator */
+const ID_COLUMN: i32 = 0;
+const OP_AL: i32 = 1;
+
+/* scan operators */
+pub const OP_EQ: i32 = 2;
+pub const OP_NE: i32 = 3;
+pub const OP_LT: i32 = 4;
+pub const OP_GT: i32 = 5;
+pub const OP_CONTAINS: i32 = 8;
+pub const OP_LIKE: i32 = 11;
+pub const OP_REGEX: i32 = 13;
+
+/* the User table of default.txt */
+pub const USER: i32 = 1;
+
+/* Why a test failed */
+#[derive(Debug)]
+pub struct Failure(String);
+
+pub type Outcome = Result<(), Failure>;
+
+impl From<String> for Failure {
+    fn from(message: String) -> Failure {
+        Failure(message)
+    }
+}
+
+impl From<io::Error> for Failure {
+    fn from(e: io::Error) -> Failure {
+        Failure(e.to_string())
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/* The column values the tests use, a subset of the server's */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Float(f64),
+    Text(String),
+    Decimal(i64, i32),      /* unscaled value and scale: 12.50 is (1250, 2) */
+    List(Vec<Value>),       /* scan operands only */
+    Json(String),
+}
+
+/* a row of the User table: first name, last name, height and age */
+pub fn user(name: &str, age: i64) -> Vec<Value> {
+    vec![Value::Text(name.to_string()), Value::Text(String::from("tester")),
+         Value::Float(1.75), Value::Integer(age)]
+}
+
+/* An error code the server answered a request with */
+#[derive(Debug)]
+pub struct Refusal {
+    pub code: i32,
+    pub detail: String,     /* leader of NOT_LEADER, check of CHECK_FAILED */
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.detail.len() {
+            0 => write!(f, "error code {}", self.code),
+            _ => write!(f, "error code {} ({})", self.code, self.detail),
+        }
+    }
+}
+
+/* what a request was answered with: its result, or an error code */
+pub type Answer<T> = io::Result<Result<T, Refusal>>;
+
+/* A connection to a server, sending one request at a time */
+pub struct Client {
+    stream: TcpStream,
+}
+
+impl Client {
+    /* connects, failing unless the server greets the client with OK */
+    pub fn connect(port: u16) -> io::Result<Client> {
+        let stream = TcpStream::connect(("localhost", port))?;
+        stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+        let mut client = Client { stream: stream };
+        match client.reply()? {
+            Ok(_) => Ok(client),
+            Err(refusal) => Err(io::Error::new(io::ErrorKind::ConnectionRefused,
+                                               refusal.to_string())),
+        }
+    }
+
+    pub fn insert(&mut self, table_id: i32, values: &[Value])
+        -> Answer<(i64, i64)>
+    {
+        self.send_insert(table_id, values)?;
+        self.read_insert()
+    }
+
+    /* sends an insert without waiting for it to be answered */
+    pub fn send_insert(&mut self, table_id: i32, values: &[Value])
+        -> io::Result<()>
+    {
+        let mut packet = Packet::new(INSERT, table_id);
+        packet.i32(values.len() as i32);
+        for value in values {
+            packet.value(value);
+        }
+        self.send(packet)
+    }
+
+    /* the id and version of the row a sent insert added */
+    pub fn read_insert(&mut self) -> Answer<(i64, i64)> {
+        let mut reply = match self.reply()? {
+            Ok(reply) => reply,
+            Err(refusal) => return Ok(Err(refusal)),
+        };
+        let id = reply.i64()?;
+        Ok(Ok((id, reply.i64()?)))
+    }
+
+    /* replaces the values of a row at any version, returning the new one */
+    pub fn update(&mut self, table_id: i32, id: i64, values: &[Value])
+        -> Answer<i64>
+    {
+        let mut packet = Packet::new(UPDATE, table_id);
+        packet.i64(id);
+        packet.i64(0);
+        packet.i32(values.len() as i32);
+        for value in values {
+            packet.value(value);
+        }
+        self.send(packet)?;
+        let mut reply = match self.reply()? {
+            Ok(reply) => reply,
+            Err(refusal) => return Ok(Err(refusal)),
+        };
+        Ok(Ok(reply.i64()?))
+    }
+
+    /* the version and values of a row */
+    pub fn get(&mut self, table_id: i32, id: i64) -> Answer<(i64, Vec<Value>)> {
+        let mut packet = Packet::new(GET, table_id);
+        packet.i64(id);
+        self.send(packet)?;
+        let mut reply = match self.reply()? {
+            Ok(reply) => reply,
+            Err(refusal) => return Ok(Err(refusal)),
+        };
+        let version = reply.i64()?;
+        Ok(Ok((version, reply.values()?)))
+    }
+
+    /* the ids of every row of a table */
+    pub fn scan_all(&mut self, table_id: i32) -> Answer<Vec<i64>> {
+        self.scan(table_id, ID_COLUMN, OP_AL, &Value::Null)
+    }
+
+    /* the ids of the rows whose column matches `value` by `operator` */
+    pub fn scan(&mut self, table_id: i32, column_id: i32, operator: i32,
+        value: &Value) -> Answer<Vec<i64>>
+    {
+        let mut packet = Packet::new(SCAN, table_id);
+        packet.i32(column_id);
+        packet.i32(operator);
+        packet.value(value);
+        self.send(packet)?;
+        let mut reply = match self.reply()? {
+            Ok(reply) => reply,
+            Err(refusal) => return Ok(Err(refusal)),
+        };
+        let mut ids = vec![];
+        for _ in 0..reply.i32()? {
+            ids.push(reply.i64()?);
+        }
+        Ok(Ok(ids))
+    }
+
+    /* allows the admin commands on this connection */
+    pub fn auth(&mut self, token: &str) -> Answer<()> {
+        let mut packet = Packet::new(AUTH, 0);
+        packet.value(&Value::Text(token.to_string()));
+        self.send(packet)?;
+        Ok(self.reply()?.map(|_| ()))
+    }
+
+    /* adds a table of (name, declaration) columns */
+    pub fn add_table(&mut self, name: &str, columns: &[(&str, &str)])
+        -> Answer<()>
+    {
+        let mut packet = Packet::new(MIGRATE, 0);
+        packet.i32(ADD_TABLE);
+        packet.value(&Value::Text(name.to_string()));
+        packet.i32(columns.len() as i32);
+        for &(column, declaration) in columns {
+            packet.value(&Value::Text(column.to_string()));
+            packet.value(&Value::Text(declaration.to_string()));
+        }
+        self.send(packet)?;
+        Ok(self.reply()?.map(|_| ()))
+    }
+
+    pub fn drop_table(&mut self, table_id: i32) -> Answer<()> {
+        let mut packet = Packet::new(MIGRATE, table_id);
+        packet.i32(DROP_TABLE);
+        self.send(packet)?;
+        Ok(self.reply()?.map(|_| ()))
+    }
+
+    pub fn rename_column(&mut self, table_id: i32, column_id: i32, name: &str)
+        -> Answer<()>
+    {
+        let mut packet = Packet::new(MIGRATE, table_id);
+        packet.i32(RENAME_COLUMN);
+        packet.i32(column_id);
+        packet.value(&Value::Text(name.to_string()));
+        self.send(packet)?;
+        Ok(self.reply()?.map(|_| ()))
+    }
+
+    /*
+     * A page of the changes to every table after sequence `since`: the
+     * cursor to continue from, the latest sequence, and the rows changed
+     */
+    pub fn changes(&mut self, since: i64) -> Answer<(i64, i64, Vec<i64>)> {
+        let mut packet = Packet::new(CHANGES, 0);
+        packet.i64(since);
+        packet.i32(0);
+        self.send(packet)?;
+        let mut reply = match self.reply()? {
+            Ok(reply) => reply,
+            Err(refusal) => return Ok(Err(refusal)),
+        };
+        let cursor = reply.i64()?;
+        let latest = reply.i64()?;
+        let mut ids = vec![];
+        for _ in 0..reply.i32()? {
+            reply.i64()?;
+            reply.i32()?;
+            reply.i32()?;
+            ids.push(reply.i64()?);
+            reply.i64()?;
+            reply.values()?;
+        }
+        Ok(Ok((cursor, latest, ids)))
+    }
+
+    /* the ids of every row of every table, as (table, id) */
+    pub fn snapshot(&mut self) -> Answer<Vec<(i32, i64)>> {
+        self.send(Packet::new(SNAPSHOT, 0))?;
+        let mut reply = match self.reply()? {
+            Ok(reply) => reply,
+            Err(refusal) => return Ok(Err(refusal)),
+        };
+        reply.i64()?;
+        let mut rows = vec![];
+        for _ in 0..reply.i32()? {
+            let table_id = reply.i32()?;
+            let id = reply.i64()?;
+            reply.i64()?;
+            reply.values()?;
+            rows.push((table_id, id));
+        }
+        Ok(Ok(rows))
+    }
+
+    pub fn exit(mut self) -> io::Result<()> {
+        self.send(Packet::new(EXIT, 0))
+    }
+
+    /* true once the server has closed the connection */
+    pub fn closed(&mut self) -> io::Result<bool> {
+        match self.stream.read(&mut [0 as u8; 1]) {
+            Ok(0) => Ok(true),
+            Ok(_) => Ok(false),
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => Ok(true),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn send(&mut self, packet: Packet) -> io::Result<()> {
+        self.stream.write_all(&packet.bytes)
+    }
+
+    /*
+     * Reads the result code of the next reply, and the rest of an error.
+     * Returns the reply to read the fields of a result from.
+     */
+    fn reply(&mut self) -> Answer<Reply<'_>> {
+        let mut reply = Reply { stream: &mut self.stream, received: 0 };
+        let code = reply.i32()?;
+        if code == OK {
+            return Ok(Ok(reply));
+        }
+        let detail = match code {
+            NOT_LEADER | CHECK_FAILED => reply.text()?,
+            _ => String::new(),
+        };
+        Ok(Err(Refusal { code: code, detail: detail }))
+    }
+}
+
+/* A request being written */
+struct Packet {
+    bytes: Vec<u8>,
+}
+
+impl Packet {
+    fn new(command: i32, table_id: i32) -> Packet {
+        let mut packet = Packet { bytes: vec![] };
+        packet.i32(command);
+        packet.i32(table_id);
+        packet
+    }
+
+    fn i32(&mut self, value: i32) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn i64(&mut self, value: i64) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+
+    /* the type and size fields, then the value padded to 4 bytes */
+    fn value(&mut self, value: &Value) {
+        match value {
+            Value::Null => {
+                self.i32(NULL);
+                self.i32(0);
+            },
+            Value::Integer(v) => {
+                self.i32(INTEGER);
+                self.i32(8);
+                self.i64(*v);
+            },
+            Value::Float(v) => {
+                self.i32(FLOAT);
+                self.i32(8);
+                self.bytes.extend_from_slice(&v.to_bits().to_be_bytes());
+            },
+            Value::Text(v) => self.text(STRING, v),
+            Value::Json(v) => self.text(JSON, v),
+            Value::Decimal(v, scale) => {
+                self.i32(DECIMAL);
+                self.i32(12);
+                self.i64(*v);
+                self.i32(*scale);
+            },
+            Value::List(values) => {
+                let mut list = Packet { bytes: vec![] };
+                list.i32(values.len() as i32);
+                for value in values {
+                    list.value(value);
+                }
+                self.i32(LIST);
+                self.i32(list.bytes.len() as i32);
+                self.bytes.extend_from_slice(&list.bytes);
+            },
+        }
+    }
+
+    /* a string or json document, padded to 4 bytes */
+    fn text(&mut self, value_type: i32, text: &str) {
+        let size = padded(text.len());
+        self.i32(value_type);
+        self.i32(size as i32);
+        self.bytes.extend_from_slice(text.as_bytes());
+        self.bytes.resize(self.bytes.len() + size - text.len(), 0);
+    }
+}
+
+fn padded(size: usize) -> usize {
+    (size + 3) / 4 * 4
+}
+
+/*
+ * A reply being read. A connection that closes before a reply is the
+ * server going away, but one that closes partway through is a bug, and
+ * is reported as InvalidData.
+ */
+struct Reply<'a> {
+    stream: &'a mut TcpStream,
+    received: usize,
+}
+
+impl<'a> Reply<'a> {
+    fn bytes(&mut self, size: usize) -> io::Result<Vec<u8>> {
+        let mut bytes = vec![0 as u8; size];
+        let mut filled = 0;
+        while filled < size {
+            let e = match self.stream.read(&mut bytes[filled..]) {
+                Ok(0) => io::Error::new(io::ErrorKind::UnexpectedEof,
+                                        "connection closed"),
+                Ok(n) => {
+                    filled += n;
+                    self.received += n;
+                    continue;
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => e,
+            };
+            if self.received > 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                           format!("reply cut short: {}", e)));
+            }
+            return Err(e);
+        }
+        Ok(bytes)
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        let mut field = [0 as u8; 4];
+        field.copy_from_slice(&self.bytes(4)?);
+        Ok(i32::from_be_bytes(field))
+    }
+
+    fn i64(&mut self) -> io::Result<i64> {
+        let mut field = [0 as u8; 8];
+        field.copy_from_slice(&self.bytes(8)?);
+        Ok(i64::from_be_bytes(field))
+    }
+
+    /* a size field, then the text padded to 4 bytes */
+    fn text(&mut self) -> io::Result<String> {
+        let size = self.i32()? as usize;
+        let bytes = self.bytes(padded(size))?;
+        let text = String::from_utf8_lossy(&bytes);
+        Ok(text.trim_end_matches('\0').to_string())
+    }
+
+    fn value(&mut self) -> io::Result<Value> {
+        let value_type = self.i32()?;
+        Ok(match value_type {
+            NULL => {
+                self.i32()?;
+                Value::Null
+            },
+            INTEGER => {
+                self.i32()?;
+                Value::Integer(self.i64()?)
+            },
+            FLOAT => {
+                self.i32()?;
+                Value::Float(f64::from_bits(self.i64()? as u64))
+            },
+            STRING => Value::Text(self.text()?),
+            JSON => Value::Json(self.text()?),
+            DECIMAL => {
+                self.i32()?;
+                let v = self.i64()?;
+                Value::Decimal(v, self.i32()?)
+            },
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                            format!("unexpected value type {}", value_type))),
+        })
+    }
+
+    fn values(&mut self) -> io::Result<Vec<Value>> {
+        let mut values = vec![];
+        for _ in 0..self.i32()? {
+            values.push(self.value()?);
+        }
+        Ok(values)
+    }
+}
+
+/* A server process, killed when dropped */
+pub struct Server {
+    child: Child,
+    pub port: u16,
+    log: PathBuf,
+}
+
+impl Server {
+    /*
+     * Starts a server for the clients of `port`, with its data in `dir`
+     * and its output in `dir`/`name`.log, which restarts append to
+     */
+    pub fn start(dir: &Path, name: &str, port: u16, args: &[&str])
+        -> io::Result<Server>
+    {
+        let log = dir.join(format!("{}.log", name));
+        let output = OpenOptions::new().create(true).append(true).open(&log)?;
+        let child = Command::new(SERVER)
+            .arg("--port").arg(port.to_string())
+            .arg("--schema").arg(SCHEMA)
+            .arg("--data-dir").arg(dir)
+            .args(args)
+            .stdout(output.try_clone()?)
+            .stderr(output)
+            .spawn()?;
+        let mut server = Server { child: child, port: port, log: log };
+
+        let start = Instant::now();
+        while TcpStream::connect(("localhost", port)).is_err() {
+            if let Some(status) = server.child.try_wait()? {
+                return Err(io::Error::new(io::ErrorKind::Other,
+                           format!("{} exited with {}", name, status)));
+            }
+            if start.elapsed() > START_TIMEOUT {
+                return Err(io::Error::new(io::ErrorKind::TimedOut,
+                           format!("{} did not start", name)));
+            }
+            thread::sleep(POLL);
+        }
+        Ok(server)
+    }
+
+    pub fn signal(& self, signum: i32) -> io::Result<()> {
+        match unsafe { kill(self.child.id() as i32, signum) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    /* SIGKILL, so the server gets no chance to clean up */
+    pub fn kill(&mut self) -> io::Result<()> {
+        self.child.kill()?;
+        self.child.wait()?;
+        Ok(())
+    }
+
+    pub fn wait(&mut self, timeout: Duration) -> io::Result<ExitStatus> {
+        let start = Instant::now();
+        loop {
+            if let Some(status) = self.child.try_wait()? {
+                return Ok(status);
+            }
+            if start.elapsed() > timeout {
+                return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                          "server did not exit"));
+            }
+            thread::sleep(POLL);
+        }
+    }
+
+    /* everything the server has printed or logged, across restarts */
+    pub fn log(& self) -> String {
+        fs::read_to_string(&self.log).unwrap_or(String::new())
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/* retries `check` until it passes, or fails as it last did after `timeout` */
+pub fn eventually<F: FnMut() -> Outcome>(timeout: Duration, mut check: F)
+    -> Outcome
+{
+    let start = Instant::now();
+    loop {
+        match check() {
+            Err(ref _e) if start.elapsed() < timeout => thread::sleep(POLL),
+            result => return result,
+        }
+    }
+}
+
+/* a localhost port nothing listens on */
+pub fn free_port() -> io::Result<u16> {
+    Ok(TcpListener::bind(("localhost", 0))?.local_addr()?.port())
+}
+
+fn scratch_root() -> PathBuf {
+    env::temp_dir().join(format!("easydb-tester-{}", process::id()))
+}
+
+/* an empty directory for the data and logs of one test */
+pub fn scratch(name: &str) -> io::Result<PathBuf> {
+    let dir = scratch_root().join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/* writes a schema file into `dir`, for the --schema of a server */
+pub fn schema(dir: &Path, text: &str) -> io::Result<String> {
+    let file = dir.join("schema.txt");
+    fs::write(&file, text)?;
+    Ok(file.to_string_lossy().into_owned())
+}
+
+/* removes the directories of tests that passed */
+pub fn clean_up() {
+    let _ = fs::remove_dir_all(scratch_root());
+}
@@ -0,0 +1,184 @@
+/*
+ * cluster.rs
+ *
+ * Three nodes started with --cluster. The leader is killed with SIGKILL
+ * while a client inserts rows, and restarted. Every insert the cluster
+ * acknowledged is then read back from the new leader. The restarted node
+ * must never answer that an acknowledged row is missing, even before it
+ * has caught up: it has the row, or sends the reader to the leader.
+ *
+ * University of Toronto
+ * 2019
+ */
+
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use client;
+use client::{Client, Failure, Outcome, Refusal, Server, Value, NOT_LEADER,
+             USER};
+
+/* the id and values of every row the cluster acknowledged inserting */
+type Rows = Arc<Mutex<Vec<(i64, Vec<Value>)>>>;
+
+const NODES: usize = 3;
+
+/* inserts acknowledged before the leader is killed, and after it restarts */
+const BEFORE_KILL: usize = 50;
+const AFTER_RESTART: usize = 50;
+
+/* time the cluster has to elect a leader and answer a request */
+const FAILOVER: Duration = Duration::from_secs(10);
+
+const POLL: Duration = Duration::from_millis(50);
+
+pub fn run() -> Outcome {
+    let dir = client::scratch("cluster")?;
+    let mut ports = vec![];
+    let mut peers = vec![];
+    for _ in 0..NODES {
+        ports.push(client::free_port()?);
+        peers.push(format!("localhost:{}", client::free_port()?));
+    }
+    let cluster = peers.join(",");
+    let mut nodes = vec![];
+    for node in 0..NODES {
+        nodes.push(start(&dir, node, ports[node], &cluster)?);
+    }
+
+    /* a client inserting rows, following the leader as it changes */
+    let rows: Rows = Arc::new(Mutex::new(vec![]));
+    let done = Arc::new(AtomicBool::new(false));
+    let workload = {
+        let (ports, rows, done) = (ports.clone(), rows.clone(), done.clone());
+        thread::spawn(move || work(&ports, &rows, &done))
+    };
+    wait_for(&rows, BEFORE_KILL);
+
+    let killed = leader(&ports)?;
+    nodes[killed].kill()?;
+    nodes[killed] = start(&dir, killed, ports[killed], &cluster)?;
+    let before = rows.lock().unwrap().clone();
+    let mut restarted = Client::connect(ports[killed])?;
+    for &(id, ref values) in before.iter().rev() {
+        match restarted.get(USER, id)? {
+            Ok((_, ref found)) if found == values => (),
+            Err(ref refusal) if refusal.code == NOT_LEADER => (),
+            answer => ensure!(false, "acknowledged row {} read from the \
+                              restarted node as {:?}", id, answer),
+        }
+    }
+
+    wait_for(&rows, before.len() + AFTER_RESTART);
+    done.store(true, Ordering::SeqCst);
+    workload.join().unwrap()?;
+    let rows = rows.lock().unwrap();
+    ensure!(rows.len() >= before.len() + AFTER_RESTART,
+            "only {} of {} inserts after the restart were acknowledged",
+            rows.len() - before.len(), AFTER_RESTART);
+
+    let mut leader = Client::connect(ports[leader(&ports)?])?;
+    for &(id, ref values) in rows.iter() {
+        match leader.get(USER, id)? {
+            Ok((_, ref found)) if found == values => (),
+            answer => ensure!(false, "acknowledged row {} read from the \
+                              leader as {:?}", id, answer),
+        }
+    }
+    Ok(())
+}
+
+/* waits a while for `count` inserts to be acknowledged */
+fn wait_for(rows: &Rows, count: usize) {
+    let start = Instant::now();
+    while rows.lock().unwrap().len() < count && start.elapsed() < FAILOVER {
+        thread::sleep(POLL);
+    }
+}
+
+/* starts the node with index `node`, whose id is one more */
+fn start(dir: &Path, node: usize, port: u16, cluster: &str)
+    -> Result<Server, Failure>
+{
+    let id = (node + 1).to_string();
+    Ok(Server::start(dir, &format!("node{}", id), port,
+                     &["--cluster", cluster, "--node-id", &id])?)
+}
+
+/*
+ * Inserts rows until `done`, adding those the cluster acknowledged to
+ * `rows`. Inserts refused or cut off by the failover are retried as new
+ * rows.
+ */
+fn work(ports: &Vec<u16>, rows: &Rows, done: &AtomicBool) -> Outcome {
+    let mut sent = 0;
+    let mut target = 0;
+    let mut client: Option<Client> = None;
+    let mut unanswered = Instant::now();
+    while !done.load(Ordering::SeqCst) {
+        ensure!(unanswered.elapsed() < FAILOVER,
+                "no insert was acknowledged for {:?}", FAILOVER);
+        if client.is_none() {
+            client = Client::connect(ports[target]).ok();
+        }
+        sent += 1;
+        let values = client::user(&format!("row {}", sent), sent);
+        let answer = match client {
+            Some(ref mut client) => client.insert(USER, &values),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected,
+                                       "no connection")),
+        };
+        match answer {
+            Ok(Ok((id, _))) => {
+                rows.lock().unwrap().push((id, values));
+                unanswered = Instant::now();
+            },
+            Ok(Err(refusal)) => {
+                target = redirect(ports, target, &refusal);
+                client = None;
+            },
+            Err(_) => {
+                target = (target + 1) % ports.len();
+                client = None;
+                thread::sleep(POLL);
+            },
+        }
+    }
+    Ok(())
+}
+
+/*
+ * The node to try after a refusal: the leader it names, or else the next
+ * node, once a leader may have been elected
+ */
+fn redirect(ports: &Vec<u16>, target: usize, refusal: &Refusal) -> usize {
+    if refusal.code == NOT_LEADER {
+        let port = refusal.detail.rsplit(':').next()
+            .and_then(|port| port.parse::<u16>().ok());
+        if let Some(node) = ports.iter().position(|p| Some(*p) == port) {
+            return node;
+        }
+    }
+    thread::sleep(POLL);
+    (target + 1) % ports.len()
+}
+
+/* the node that answers reads, once the cluster has a leader */
+fn leader(ports: &Vec<u16>) -> Result<usize, Failure> {
+    let mut found = 0;
+    client::eventually(FAILOVER, || {
+        for (node, port) in ports.iter().enumerate() {
+            let answer = Client::connect(*port)
+                .and_then(|mut client| client.scan_all(USER));
+            if let Ok(Ok(_)) = answer {
+                found = node;
+                return Ok(());
+            }
+        }
+        Err(Failure::from(String::from("no node answers reads")))
+    })?;
+    Ok(found)
+}
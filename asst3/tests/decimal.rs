@@ -0,0 +1,69 @@
+/*
+ * decimal.rs
+ *
+ * Inserts into a decimal(5,2) column. Values are brought to the column's
+ * scale, negative ones included, and those that would lose digits or
+ * need more than its precision are refused.
+ *
+ * University of Toronto
+ * 2019
+ */
+
+use client;
+use client::{Client, Outcome, Server, Value, BAD_VALUE};
+
+const SCHEMA: &str = "Price {\n    amount: decimal(5,2);\n}\n";
+
+const PRICE: i32 = 1;
+
+/* values that fit, as inserted and as stored */
+const FITTING: [((i64, i32), (i64, i32)); 6] = [
+    ((125, 1), (1250, 2)),          /* 12.5 */
+    ((-125, 1), (-1250, 2)),        /* -12.5 */
+    ((1230, 3), (123, 2)),          /* 1.230, with a trailing zero */
+    ((-1230, 3), (-123, 2)),
+    ((99999, 2), (99999, 2)),       /* 999.99, the largest */
+    ((-999, 0), (-99900, 2)),       /* -999, the smallest whole */
+];
+
+/* values the column cannot hold */
+const REFUSED: [(i64, i32); 7] = [
+    (1234, 3),                      /* 1.234 loses a digit */
+    (-1234, 3),
+    (100000, 2),                    /* 1000.00 needs 6 digits */
+    (-100000, 2),
+    (1000, 0),                      /* 1000 needs 6 digits at scale 2 */
+    (-1000, 0),
+    (i64::max_value(), 0),          /* overflows when rescaled */
+];
+
+pub fn run() -> Outcome {
+    let dir = client::scratch("decimal")?;
+    let schema = client::schema(&dir, SCHEMA)?;
+    let server = Server::start(&dir, "server", client::free_port()?,
+                               &["--schema", &schema])?;
+    let mut client = Client::connect(server.port)?;
+
+    for &((value, scale), (stored, stored_scale)) in FITTING.iter() {
+        let id = match client.insert(PRICE, &[Value::Decimal(value, scale)])? {
+            Ok((id, _)) => id,
+            Err(refusal) => return Err(format!("insert ({}, {}): {}", value,
+                                               scale, refusal).into()),
+        };
+        match client.get(PRICE, id)? {
+            Ok((_, ref values))
+                if *values == [Value::Decimal(stored, stored_scale)] => (),
+            answer => ensure!(false, "({}, {}) stored as {:?}", value, scale,
+                              answer),
+        }
+    }
+
+    for &(value, scale) in REFUSED.iter() {
+        match client.insert(PRICE, &[Value::Decimal(value, scale)])? {
+            Err(ref refusal) if refusal.code == BAD_VALUE => (),
+            answer => ensure!(false, "insert ({}, {}): {:?}", value, scale,
+                              answer),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,40 @@
+/*
+ * idle.rs
+ *
+ * A server with room for one client, held by a client that connects and
+ * then sends nothing. New clients are refused until the idle timeout
+ * disconnects the silent one, and are then accepted in its slot.
+ *
+ * University of Toronto
+ * 2019
+ */
+
+use std::time::Duration;
+use client;
+use client::{Client, Failure, Outcome, Server};
+
+/* seconds a client may stay silent */
+const IDLE_TIMEOUT: u64 = 1;
+
+/* time the server has to notice and reclaim the slot */
+const RECLAIM: Duration = Duration::from_secs(5);
+
+pub fn run() -> Outcome {
+    let dir = client::scratch("idle")?;
+    let timeout = IDLE_TIMEOUT.to_string();
+    let server = Server::start(&dir, "server", client::free_port()?,
+                               &["--max-clients", "1",
+                                 "--idle-timeout", &timeout])?;
+
+    let _silent = Client::connect(server.port)?;
+    ensure!(Client::connect(server.port).is_err(),
+            "second client accepted while the silent one holds the slot");
+
+    client::eventually(RECLAIM, || match Client::connect(server.port) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(Failure::from(format!("slot not reclaimed: {}", e))),
+    })?;
+    ensure!(server.log().contains("idle timeout"),
+            "server did not report the idle timeout");
+    Ok(())
+}
@@ -0,0 +1,137 @@
+/*
+ * json.rs
+ *
+ * A json column. Malformed documents are refused with BAD_VALUE, and
+ * scans follow dotted paths through nested objects and arrays, matching
+ * only documents that hold a value of the operand's type at the path.
+ *
+ * University of Toronto
+ * 2019
+ */
+
+use client;
+use client::{Client, Outcome, Server, Value, BAD_QUERY, BAD_VALUE, OP_CONTAINS,
+             OP_EQ, OP_GT, OP_LIKE, OP_LT, OP_NE};
+
+const SCHEMA: &str = "Doc {\n    body: json;\n}\n";
+
+const DOC: i32 = 1;
+const BODY: i32 = 1;
+
+/* most arrays and objects a document may nest */
+const DEEPEST: usize = 64;
+
+const MALFORMED: [&str; 12] = [
+    "",
+    "{\"a\": }",
+    "{\"a\": 1,}",
+    "[1, 2",
+    "{a: 1}",
+    "{\"a\" 1}",
+    "\"unterminated",
+    "{} {}",
+    "tru",
+    "01",
+    "[1.]",
+    "\"\\x\"",
+];
+
+const DOCUMENTS: [&str; 6] = [
+    r#"{"profile": {"address": {"city": "Toronto", "zip": "M5S"}},
+        "tags": ["a", "b"], "scores": [1, 2.5, 3]}"#,
+    r#"{"profile": {"address": {"city": "Ottawa"}}, "tags": ["b"],
+        "scores": []}"#,
+    r#"{"profile": {"address": "none"}, "tags": "a"}"#,
+    r#"[{"name": "first"}, {"name": "second"}]"#,
+    r#"{"profile": null}"#,
+    r#"{"profile": {"address": {"city": "Toronto"}},
+        "profile": {"address": {"city": "Montreal"}}}"#,
+];
+
+pub fn run() -> Outcome {
+    let dir = client::scratch("json")?;
+    let schema = client::schema(&dir, SCHEMA)?;
+    let server = Server::start(&dir, "server", client::free_port()?,
+                               &["--schema", &schema])?;
+    let mut client = Client::connect(server.port)?;
+
+    let malformed = MALFORMED.iter().map(|text| text.to_string())
+        .chain(Some(nested(DEEPEST + 1)));
+    for text in malformed {
+        match client.insert(DOC, &[Value::Json(text.clone())])? {
+            Err(ref refusal) if refusal.code == BAD_VALUE => (),
+            answer => ensure!(false, "insert of {:?}: {:?}", text, answer),
+        }
+    }
+
+    client.insert(DOC, &[Value::Json(nested(DEEPEST))])?
+        .map_err(|refusal| format!("insert of the deepest document: {}",
+                                   refusal))?;
+
+    let mut ids = vec![];
+    for text in DOCUMENTS.iter() {
+        let values = [Value::Json(text.to_string())];
+        match client.insert(DOC, &values)? {
+            Ok((id, _)) => ids.push(id),
+            Err(refusal) => ensure!(false, "insert of {}: {}", text, refusal),
+        }
+        match client.get(DOC, *ids.last().unwrap())? {
+            Ok((_, ref found)) if *found == values => (),
+            answer => ensure!(false, "{} read back as {:?}", text, answer),
+        }
+    }
+
+    /* scans, and the DOCUMENTS they must find */
+    let city = "profile.address.city";
+    let scans = [
+        (OP_EQ, city, text("Toronto"), vec![0]),
+        (OP_EQ, city, text("Montreal"), vec![5]),
+        (OP_NE, city, text("Toronto"), vec![1, 5]),
+        (OP_LT, city, text("P"), vec![1, 5]),
+        (OP_EQ, "profile.address", text("none"), vec![2]),
+        (OP_EQ, "profile", Value::Null, vec![4]),
+        (OP_CONTAINS, "tags", text("a"), vec![0]),
+        (OP_CONTAINS, "tags", text("b"), vec![0, 1]),
+        (OP_EQ, "scores.0", Value::Integer(1), vec![0]),
+        (OP_GT, "scores.1", Value::Float(2.0), vec![0]),
+        (OP_EQ, "scores.1", text("2.5"), vec![]),
+        (OP_EQ, "0.name", text("first"), vec![3]),
+        (OP_EQ, "1.name", text("second"), vec![3]),
+        (OP_EQ, "2.name", text("second"), vec![]),
+    ];
+    for &(operator, path, ref value, ref documents) in scans.iter() {
+        let operand = Value::List(vec![text(path), value.clone()]);
+        let mut expected: Vec<i64> = documents.iter().map(|d| ids[*d])
+            .collect();
+        expected.sort();
+        match client.scan(DOC, BODY, operator, &operand)? {
+            Ok(mut found) => {
+                found.sort();
+                ensure!(found == expected, "scan {} {} {:?} found {:?}, not \
+                        {:?}", operator, path, value, found, expected);
+            },
+            Err(refusal) => ensure!(false, "scan {} {} {:?}: {}", operator,
+                                    path, value, refusal),
+        }
+    }
+
+    let refused = [(OP_EQ, text("Toronto")),
+                   (OP_LIKE, Value::List(vec![text(city), text("T%")]))];
+    for &(operator, ref operand) in refused.iter() {
+        match client.scan(DOC, BODY, operator, operand)? {
+            Err(ref refusal) if refusal.code == BAD_QUERY => (),
+            answer => ensure!(false, "scan {} {:?}: {:?}", operator, operand,
+                              answer),
+        }
+    }
+    Ok(())
+}
+
+/* `depth` arrays, one inside the other */
+fn nested(depth: usize) -> String {
+    format!("{}{}", "[".repeat(depth), "]".repeat(depth))
+}
+
+fn text(text: &str) -> Value {
+    Value::Text(text.to_string())
+}
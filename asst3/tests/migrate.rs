@@ -0,0 +1,45 @@
+/*
+ * migrate.rs
+ *
+ * Schema changes through MIGRATE. A table added after one was dropped
+ * gets an id of its own, so clients still holding the dropped table's id
+ * are told the table is gone rather than writing to the new one.
+ *
+ * University of Toronto
+ * 2019
+ */
+
+use client;
+use client::{Client, Outcome, Server, Value, BAD_TABLE};
+
+/* default.txt has two tables, so the first table added is 3 */
+const ADDED: i32 = 3;
+
+const TOKEN: &str = "migrate";
+
+pub fn run() -> Outcome {
+    let dir = client::scratch("migrate")?;
+    let server = Server::start(&dir, "server", client::free_port()?,
+                               &["--admin-token", TOKEN])?;
+    let mut admin = Client::connect(server.port)?;
+    admin.auth(TOKEN)?.map_err(|refusal| format!("auth: {}", refusal))?;
+
+    let columns = [("name", "string")];
+    admin.add_table("Dropped", &columns)?
+        .map_err(|refusal| format!("add table: {}", refusal))?;
+    admin.drop_table(ADDED)?
+        .map_err(|refusal| format!("drop table: {}", refusal))?;
+    admin.add_table("Added", &columns)?
+        .map_err(|refusal| format!("add table again: {}", refusal))?;
+
+    let row = [Value::Text(String::from("row"))];
+    match admin.insert(ADDED, &row)? {
+        Err(ref refusal) if refusal.code == BAD_TABLE => (),
+        answer => ensure!(false, "insert into the dropped table: {:?}",
+                          answer),
+    }
+    if let Err(refusal) = admin.insert(ADDED + 1, &row)? {
+        ensure!(false, "insert into the added table: {}", refusal);
+    }
+    Ok(())
+}
@@ -0,0 +1,131 @@
+/*
+ * patterns.rs
+ *
+ * Scans of a string column with LIKE and REGEX: escapes, wildcards and
+ * anchors, classes with escapes inside them, and patterns that would
+ * take exponential time in a backtracking matcher. Invalid and overlong
+ * patterns are refused as BAD_QUERY.
+ *
+ * University of Toronto
+ * 2019
+ */
+
+use std::time::{Duration, Instant};
+use client;
+use client::{Client, Outcome, Server, Value, BAD_QUERY, OP_LIKE, OP_REGEX,
+             USER};
+
+/* the firstName column of the User table */
+const FIRST_NAME: i32 = 1;
+
+/* longest pattern a scan accepts */
+const MAX_PATTERN: usize = 256;
+
+/* far longer than any scan of these rows should take */
+const SLOW: Duration = Duration::from_secs(1);
+
+/* scans, and the NAMES they must find */
+const SCANS: [(i32, &str, &[&str]); 22] = [
+    (OP_LIKE, "a%", &["a", "abc", "axb", "a%b", "a_c", "a$", "a$b", "a\\",
+                      "acb"]),
+    (OP_LIKE, "%", &["", "a", "abc", "ba", "axb", "a%b", "a_c", "a$", "a$b",
+                     "a\\", "xaybzc", "acb", "12", "x y", "x_y", "x-y"]),
+    (OP_LIKE, "a\\%b", &["a%b"]),
+    (OP_LIKE, "a\\_c", &["a_c"]),
+    (OP_LIKE, "a_c", &["abc", "a_c"]),
+    (OP_LIKE, "a\\", &["a\\"]),
+    (OP_LIKE, "%a%b%c%", &["abc", "xaybzc"]),
+    (OP_LIKE, "%b", &["axb", "a%b", "a$b", "acb"]),
+    (OP_REGEX, "^a.c$", &["abc", "a_c"]),
+    (OP_REGEX, "b+c", &["abc"]),
+    (OP_REGEX, "a\\$", &["a$", "a$b"]),
+    (OP_REGEX, "a\\$$", &["a$"]),
+    (OP_REGEX, "a\\\\$", &["a\\"]),
+    (OP_REGEX, "^[\\d]+$", &["12"]),
+    (OP_REGEX, "^x[\\s]y$", &["x y"]),
+    (OP_REGEX, "^x[\\w]y$", &["x_y"]),
+    (OP_REGEX, "^x[-\\w]y$", &["x_y", "x-y"]),
+    (OP_REGEX, "^x[\\w-]y$", &["x_y", "x-y"]),
+    (OP_REGEX, "^[^\\d]$", &["a"]),
+    (OP_REGEX, "^$", &[""]),
+    (OP_REGEX, "z?c$", &["abc", "xaybzc", "a_c"]),
+    (OP_REGEX, "^a?b?a$", &["a", "ba"]),
+];
+
+const NAMES: [&str; 16] = ["", "a", "abc", "ba", "axb", "a%b", "a_c", "a$",
+                           "a$b", "a\\", "xaybzc", "acb", "12", "x y", "x_y",
+                           "x-y"];
+
+pub fn run() -> Outcome {
+    let dir = client::scratch("patterns")?;
+    let server = Server::start(&dir, "server", client::free_port()?, &[])?;
+    let mut client = Client::connect(server.port)?;
+
+    let mut ids = vec![];
+    for (age, name) in NAMES.iter().enumerate() {
+        match client.insert(USER, &client::user(name, age as i64))? {
+            Ok((id, _)) => ids.push(id),
+            Err(refusal) => ensure!(false, "insert {:?}: {}", name, refusal),
+        }
+    }
+
+    for &(operator, pattern, names) in SCANS.iter() {
+        let mut expected: Vec<i64> = names.iter()
+            .map(|name| ids[NAMES.iter().position(|n| n == name).unwrap()])
+            .collect();
+        expected.sort();
+        let text = Value::Text(pattern.to_string());
+        match client.scan(USER, FIRST_NAME, operator, &text)? {
+            Ok(mut found) => {
+                found.sort();
+                ensure!(found == expected, "{} {:?} found {:?}, not {:?}",
+                        name(operator), pattern, found, expected);
+            },
+            Err(refusal) => ensure!(false, "{} {:?}: {}", name(operator),
+                                    pattern, refusal),
+        }
+    }
+
+    /* a run of a's, which backtracking would try every split of */
+    let long = "a".repeat(30);
+    client.insert(USER, &client::user(&long, 0))?
+        .map_err(|refusal| format!("insert: {}", refusal))?;
+    let slow = [(OP_LIKE, format!("{}b", "%a".repeat(30)), 0),
+                (OP_REGEX, format!("^{}{}$", "a?".repeat(30), long), 1),
+                (OP_REGEX, format!("^{}b$", "a*".repeat(30)), 0)];
+    for &(operator, ref pattern, expected) in slow.iter() {
+        let start = Instant::now();
+        let text = Value::Text(pattern.clone());
+        let found = client.scan(USER, FIRST_NAME, operator, &text)?
+            .map_err(|refusal| format!("{} {}: {}", name(operator), pattern,
+                                       refusal))?;
+        ensure!(start.elapsed() < SLOW, "{} {} took {:?}", name(operator),
+                pattern, start.elapsed());
+        ensure!(found.len() == expected, "{} {} found {} rows",
+                name(operator), pattern, found.len());
+    }
+
+    let refused = [(OP_REGEX, String::from("^[\\D]$")),
+                   (OP_REGEX, String::from("(a|b)")),
+                   (OP_REGEX, String::from("*a")),
+                   (OP_REGEX, String::from("[a")),
+                   (OP_REGEX, String::from("a\\")),
+                   (OP_LIKE, "%".repeat(MAX_PATTERN + 1)),
+                   (OP_REGEX, "a".repeat(MAX_PATTERN + 1))];
+    for &(operator, ref pattern) in refused.iter() {
+        let text = Value::Text(pattern.clone());
+        match client.scan(USER, FIRST_NAME, operator, &text)? {
+            Err(ref refusal) if refusal.code == BAD_QUERY => (),
+            answer => ensure!(false, "{} {:?}: {:?}", name(operator), pattern,
+                              answer),
+        }
+    }
+    Ok(())
+}
+
+fn name(operator: i32) -> &'static str {
+    match operator {
+        OP_LIKE => "LIKE",
+        _ => "REGEX",
+    }
+}
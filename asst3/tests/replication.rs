@@ -0,0 +1,118 @@
+/*
+ * replication.rs
+ *
+ * A leader and a follower started with --replica-of. The follower serves
+ * the leader's rows, sends writers to the leader, and catches up from a
+ * snapshot once the leader has discarded the changes it missed. Only
+ * admins may take snapshots, so the follower shares the leader's admin
+ * token. The leader refuses schema changes, which would not reach the
+ * follower.
+ *
+ * University of Toronto
+ * 2019
+ */
+
+use std::time::Duration;
+use client;
+use client::{Client, Outcome, Server, FORBIDDEN, NOT_LEADER, SIGCONT,
+             SIGSTOP, UNIMPLEMENTED, USER};
+
+/* changes the leader keeps for CHANGES requests */
+const CHANGE_LOG_SIZE: usize = 8;
+
+/* time the follower has to apply what the leader has */
+const CATCH_UP: Duration = Duration::from_secs(10);
+
+const TOKEN: &str = "replication";
+
+pub fn run() -> Outcome {
+    let leader_dir = client::scratch("replication/leader")?;
+    let follower_dir = client::scratch("replication/follower")?;
+    let log_size = CHANGE_LOG_SIZE.to_string();
+    let leader = Server::start(&leader_dir, "leader", client::free_port()?,
+                               &["--change-log-size", &log_size,
+                                 "--admin-token", TOKEN])?;
+    let address = format!("localhost:{}", leader.port);
+    let mut writer = Client::connect(leader.port)?;
+
+    /* rows from before the follower starts arrive in its first snapshot */
+    let mut ids = insert(&mut writer, "before", 5)?;
+    match writer.snapshot()? {
+        Err(ref refusal) if refusal.code == FORBIDDEN => (),
+        answer => ensure!(false, "snapshot without auth: {:?}", answer),
+    }
+    let follower = Server::start(&follower_dir, "follower",
+                                 client::free_port()?,
+                                 &["--replica-of", &address,
+                                   "--admin-token", TOKEN])?;
+    ids.extend(insert(&mut writer, "after", 5)?);
+    same_rows(leader.port, follower.port, &ids)?;
+
+    let mut reader = Client::connect(follower.port)?;
+    match reader.insert(USER, &client::user("on follower", 0))? {
+        Err(ref refusal) if refusal.code == NOT_LEADER =>
+            ensure!(refusal.detail == address,
+                    "follower sent the writer to {}", refusal.detail),
+        answer => ensure!(false, "insert on the follower: {:?}", answer),
+    }
+
+    writer.auth(TOKEN)?.map_err(|refusal| format!("auth: {}", refusal))?;
+    match writer.rename_column(USER, 1, "givenName")? {
+        Err(ref refusal) if refusal.code == UNIMPLEMENTED => (),
+        answer => ensure!(false, "schema change on the leader: {:?}", answer),
+    }
+
+    /*
+     * a stopped follower falls more than the change log behind, and is
+     * TRUNCATED when it asks for the changes it missed
+     */
+    follower.signal(SIGSTOP)?;
+    let missed = insert(&mut writer, "missed", 3 * CHANGE_LOG_SIZE);
+    follower.signal(SIGCONT)?;
+    ids.extend(missed?);
+    same_rows(leader.port, follower.port, &ids)?;
+    ensure!(follower.log().contains("catching up again"),
+            "follower did not catch up from a new snapshot");
+    Ok(())
+}
+
+/* inserts `count` users on the leader, returning their ids */
+fn insert(client: &mut Client, name: &str, count: usize)
+    -> Result<Vec<i64>, client::Failure>
+{
+    let mut ids = vec![];
+    for i in 0..count {
+        match client.insert(USER, &client::user(name, i as i64))? {
+            Ok((id, _)) => ids.push(id),
+            Err(refusal) => ensure!(false, "insert on the leader: {}", refusal),
+        }
+    }
+    Ok(ids)
+}
+
+/* waits for the follower to have exactly the leader's rows, as `ids` */
+fn same_rows(leader: u16, follower: u16, ids: &Vec<i64>) -> Outcome {
+    let mut leader = Client::connect(leader)?;
+    let mut follower = Client::connect(follower)?;
+    client::eventually(CATCH_UP, || {
+        let mut found = match follower.scan_all(USER)? {
+            Ok(found) => found,
+            Err(refusal) => return Err(format!("scan: {}", refusal).into()),
+        };
+        found.sort();
+        ensure!(found == *ids, "follower has rows {:?}, not {:?}", found, ids);
+        Ok(())
+    })?;
+
+    for id in ids {
+        let expected = leader.get(USER, *id)?;
+        let copied = follower.get(USER, *id)?;
+        match (expected, copied) {
+            (Ok(expected), Ok(copied)) =>
+                ensure!(expected == copied, "row {} is {:?} on the leader \
+                        but {:?} on the follower", id, expected, copied),
+            answers => ensure!(false, "get {}: {:?}", id, answers),
+        }
+    }
+    Ok(())
+}
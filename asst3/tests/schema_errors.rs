@@ -0,0 +1,90 @@
+/*
+ * schema_errors.rs
+ *
+ * A schema file with a mistake in most of its tables. The server refuses
+ * to start, and reports every mistake in one go, in file order: at its
+ * line and column, with the line quoted and a caret under the column,
+ * tabs included. Parsing carries on after each mistake, so one does not
+ * hide the next or report errors of its own.
+ *
+ * University of Toronto
+ * 2019
+ */
+
+use std::fs;
+use client;
+use client::{Outcome, Server};
+
+const SCHEMA: &str = "\
+# several mistakes, each reported where it is
+User {
+    name: string;
+    age: integr;
+    height float;
+    email: string default 5;
+}
+
+User {
+    id: integer;
+}
+
+Account {
+\towner: Usr;
+    balance: decimal(20,2);
+    $bad: integer;
+    _hidden: integer;
+}
+
+Broken {
+    count: integer;
+";
+
+/* line, column and message of every error, and the lines quoting it */
+const ERRORS: [(usize, usize, &str, &str); 9] = [
+    (4, 10, "cannot find reference table 'integr'", "
+        age: integr;
+             ^"),
+    (5, 11, "expecting ':' after column name", "
+        height float;
+              ^"),
+    (6, 27, "default does not match the column type", "
+        email: string default 5;
+                              ^"),
+    (9, 1, "duplicate table 'User' (first defined on line 2)", "
+    User {
+    ^"),
+    (14, 9, "cannot find reference table 'Usr'", "
+    \towner: Usr;
+    \t       ^"),
+    (15, 22, "decimal precision must be from 1 to 18", "
+        balance: decimal(20,2);
+                         ^"),
+    (16, 5, "invalid name identifier", "
+        $bad: integer;
+        ^"),
+    (17, 5,
+     "invalid identifier, cannot start with a number or underscore", "
+        _hidden: integer;
+        ^"),
+    (22, 1, "unexpected end of file", ""),
+];
+
+pub fn run() -> Outcome {
+    let dir = client::scratch("schema_errors")?;
+    let schema = client::schema(&dir, SCHEMA)?;
+    let started = Server::start(&dir, "server", client::free_port()?,
+                                &["--schema", &schema]);
+    ensure!(started.is_err(), "server started with an invalid schema");
+
+    let mut expected = String::new();
+    for &(line, col, message, quoted) in ERRORS.iter() {
+        expected += &format!("{}:{}:{}: error: {}{}\n", schema, line, col,
+                             message, quoted);
+    }
+    expected += &format!("{} error(s) in {}\n", ERRORS.len(), schema);
+
+    let log = fs::read_to_string(dir.join("server.log"))?;
+    ensure!(log.contains(&expected), "expected the errors\n{}\nbut the \
+            server reported\n{}", expected, log);
+    Ok(())
+}
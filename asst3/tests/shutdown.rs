@@ -0,0 +1,95 @@
+/*
+ * shutdown.rs
+ *
+ * SIGTERM in the middle of a workload. Requests already sent are answered,
+ * idle clients are closed, new clients are refused, and the server exits
+ * cleanly.
+ *
+ * University of Toronto
+ * 2019
+ */
+
+use std::io;
+use std::thread;
+use std::time::Duration;
+use client;
+use client::{Client, Outcome, Server, SIGTERM, USER};
+
+/* clients inserting rows as fast as they are answered */
+const WORKERS: usize = 4;
+
+/* clients with a request sent when the signal arrives */
+const IN_FLIGHT: usize = 4;
+
+/* longer than the server takes to notice the signal and stop accepting */
+const STOP_ACCEPTING: Duration = Duration::from_millis(300);
+
+pub fn run() -> Outcome {
+    let dir = client::scratch("shutdown")?;
+    let mut server = Server::start(&dir, "server", client::free_port()?,
+                                   &["--max-clients", "16"])?;
+    let port = server.port;
+
+    let mut idle = Client::connect(port)?;
+    let mut in_flight = vec![];
+    for _ in 0..IN_FLIGHT {
+        in_flight.push(Client::connect(port)?);
+    }
+    let workers: Vec<_> = (0..WORKERS)
+        .map(|worker| thread::spawn(move || work(port, worker)))
+        .collect();
+    thread::sleep(Duration::from_millis(200));
+
+    for (i, client) in in_flight.iter_mut().enumerate() {
+        client.send_insert(USER, &client::user(&format!("in flight {}", i), 0))?;
+    }
+    server.signal(SIGTERM)?;
+
+    for client in in_flight.iter_mut() {
+        match client.read_insert() {
+            Ok(Ok(_)) => (),
+            Ok(Err(refusal)) => ensure!(false, "in-flight insert: {}", refusal),
+            Err(e) => ensure!(false, "in-flight insert not answered: {}", e),
+        }
+    }
+
+    for (worker, thread) in workers.into_iter().enumerate() {
+        match thread.join().unwrap() {
+            Ok(answered) => ensure!(answered > 0,
+                                    "worker {} was never answered", worker),
+            Err(e) => ensure!(false, "worker {}: {}", worker, e),
+        }
+    }
+
+    ensure!(idle.closed()?, "idle client was not disconnected");
+
+    thread::sleep(STOP_ACCEPTING);
+    ensure!(Client::connect(port).is_err(),
+            "new client accepted after SIGTERM");
+
+    let status = server.wait(Duration::from_secs(10))?;
+    ensure!(status.success(), "server exited with {}", status);
+    ensure!(server.log().to_lowercase().contains("server shut down cleanly"),
+            "server did not report a clean shutdown");
+    Ok(())
+}
+
+/*
+ * Inserts until the server closes the connection, and returns how many
+ * inserts were answered. A reply cut short or an error code is a failure.
+ */
+fn work(port: u16, worker: usize) -> io::Result<usize> {
+    let mut client = Client::connect(port)?;
+    let mut answered = 0;
+    loop {
+        let row = client::user(&format!("worker {}", worker), answered as i64);
+        match client.insert(USER, &row) {
+            Ok(Ok(_)) => answered += 1,
+            Ok(Err(refusal)) => return Err(io::Error::new(
+                io::ErrorKind::Other, refusal.to_string())),
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData =>
+                return Err(io::Error::new(e.kind(), e.to_string())),
+            Err(_) => return Ok(answered),
+        }
+    }
+}
@@ -0,0 +1,77 @@
+/*
+ * tester.rs
+ *
+ * Runs the EasyDB server integration tests. Each test starts its own
+ * server processes from ./server on free localhost ports, e.g.
+ * ./tester shutdown replication
+ *
+ * University of Toronto
+ * 2019
+ */
+
+#[macro_use]
+mod client;
+mod changes;
+mod checks;
+mod cluster;
+mod decimal;
+mod idle;
+mod json;
+mod migrate;
+mod patterns;
+mod replication;
+mod schema_errors;
+mod shutdown;
+
+use std::env;
+use std::process;
+use std::time::Instant;
+use client::Outcome;
+
+/* every test, by the name it is selected with */
+const TESTS: [(&str, fn() -> Outcome); 11] = [
+    ("shutdown", shutdown::run),
+    ("idle", idle::run),
+    ("migrate", migrate::run),
+    ("schema_errors", schema_errors::run),
+    ("checks", checks::run),
+    ("decimal", decimal::run),
+    ("json", json::run),
+    ("patterns", patterns::run),
+    ("changes", changes::run),
+    ("replication", replication::run),
+    ("cluster", cluster::run),
+];
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Some(name) = args.iter()
+        .find(|name| !TESTS.iter().any(|test| test.0 == name.as_str())) {
+        eprintln!("unknown test {}", name);
+        let names: Vec<&str> = TESTS.iter().map(|test| test.0).collect();
+        eprintln!("usage: tester [{}]...", names.join(" | "));
+        process::exit(2);
+    }
+
+    let mut failed = 0;
+    for (name, test) in TESTS.iter() {
+        if args.len() > 0 && !args.iter().any(|arg| arg == name) {
+            continue;
+        }
+
+        let start = Instant::now();
+        match test() {
+            Ok(()) => println!("PASS {} ({:.1}s)", name,
+                              start.elapsed().as_secs_f64()),
+            Err(e) => {
+                println!("FAIL {}: {}", name, e);
+                failed += 1;
+            },
+        }
+    }
+
+    if failed > 0 {
+        process::exit(1);
+    }
+    client::clean_up();
+}